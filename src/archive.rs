@@ -0,0 +1,217 @@
+//! Writes a backup into a single tar archive instead of replicating the tree.
+
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use progress::Bar;
+use tar::{Builder, Header, HeaderMode};
+use zstd::stream::write::Encoder;
+
+use globalvars::Format;
+use throttle::{Throttle, Throttled};
+
+/// The zstd level used for tar.zst archives
+const ZSTD_LEVEL: i32 = 3;
+
+/// Returns the path of the archive that is written for `dest`, ie.
+/// `backup/home` becomes `backup/home.tar.zst`
+pub fn archive_path(dest: &Path, format: Format) -> PathBuf {
+    let mut name = dest.file_name().unwrap_or_default().to_os_string();
+    name.push(format.extension());
+    dest.with_file_name(name)
+}
+
+/// Writes every file in the queue into a tar archive. Entry names are the
+/// destination paths relative to `root`, so the archive unpacks into the same
+/// tree a plain backup would create. Symlinks are stored as links and long
+/// names, permissions and mtimes are kept in the headers.
+pub fn write_tar<W: Write>(
+    queue: Vec<(PathBuf, PathBuf)>,
+    root: &Path,
+    out: W,
+    format: Format,
+    progress: bool,
+    quite: bool,
+    throttle: Option<Arc<Throttle>>,
+) -> Vec<String> {
+    let mut errors = vec![];
+    let out = Throttled::new(BufWriter::new(out), throttle.as_deref());
+
+    let result = match format {
+        Format::TarZst => Encoder::new(out, ZSTD_LEVEL).and_then(|encoder| {
+            let encoder = append_all(queue, root, encoder, progress, quite, &mut errors)?;
+            encoder.finish()?.flush()
+        }),
+        _ => append_all(queue, root, out, progress, quite, &mut errors)
+            .and_then(|mut out| out.flush()),
+    };
+
+    if let Err(error) = result {
+        if quite {
+            say!("{}", &error);
+        }
+        errors.push(format!("Error: Failed to write the archive \n {}", error));
+    }
+
+    errors
+}
+
+/// Appends the queue to a new archive and returns the writer once the archive
+/// is finished. Errors reading a single file are collected, errors writing
+/// the archive are returned.
+fn append_all<W: Write>(
+    queue: Vec<(PathBuf, PathBuf)>,
+    root: &Path,
+    out: W,
+    progress: bool,
+    quite: bool,
+    errors: &mut Vec<String>,
+) -> io::Result<W> {
+    let mut builder = Builder::new(out);
+    builder.mode(HeaderMode::Complete);
+    builder.follow_symlinks(false);
+
+    let total = queue.len();
+    let mut bar = Bar::new();
+    if progress {
+        bar.set_job_title("Backup");
+    }
+
+    for (completed, (src, dest)) in queue.into_iter().enumerate() {
+        let name = dest.strip_prefix(root).unwrap_or(&dest);
+
+        // only a failure to read the source is the file's fault, everything
+        // else means the archive itself is broken
+        let entry = match open(&src) {
+            Ok(entry) => entry,
+            Err(error) => {
+                if quite {
+                    say!("{}", &error);
+                }
+                errors.push(format!(
+                    "Error: Failed to archive {:?} -> {:?} \n {}",
+                    src, name, error
+                ));
+                continue;
+            }
+        };
+        match entry {
+            Entry::File(mut file) => builder.append_file(name, &mut file)?,
+            Entry::Dir(meta) => {
+                let mut header = Header::new_gnu();
+                header.set_metadata_in_mode(&meta, HeaderMode::Complete);
+                builder.append_data(&mut header, name, io::empty())?
+            }
+            Entry::Link(meta, target) => {
+                let mut header = Header::new_gnu();
+                header.set_metadata_in_mode(&meta, HeaderMode::Complete);
+                builder.append_link(&mut header, name, target)?
+            }
+        }
+
+        if progress {
+            bar.reach_percent((((completed + 1) as f32 / total as f32) * 100.0) as i32);
+        }
+    }
+
+    builder.into_inner()
+}
+
+/// A source that was opened for the archive
+enum Entry {
+    File(fs::File),
+    Dir(fs::Metadata),
+    /// A symlink and where it points to
+    Link(fs::Metadata, PathBuf),
+}
+
+/// Opens a source, so everything that can go wrong with it goes wrong before
+/// its header is written
+fn open(src: &Path) -> io::Result<Entry> {
+    let meta = fs::symlink_metadata(src)?;
+    match meta.file_type() {
+        kind if kind.is_symlink() => fs::read_link(src).map(|target| Entry::Link(meta, target)),
+        kind if kind.is_dir() => Ok(Entry::Dir(meta)),
+        _ => fs::File::open(src).map(Entry::File),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+    use std::os::unix::fs::symlink;
+
+    use tar::{Archive, EntryType};
+    use tempfile::TempDir;
+
+    /// The name, kind and contents of an entry
+    type Archived = (String, EntryType, Vec<u8>);
+
+    /// Archives `dir`, `dir/file`, `link` and a file that is gone, and
+    /// returns the names, kinds and contents in the archive with the errors
+    fn archived(format: Format) -> (Vec<Archived>, Vec<String>) {
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        fs::create_dir(src.path().join("dir")).unwrap();
+        fs::write(src.path().join("dir/file"), b"file").unwrap();
+        symlink("dir/file", src.path().join("link")).unwrap();
+
+        let root = dest.path().join("backup");
+        let queue = ["dir", "dir/file", "gone", "link"]
+            .iter()
+            .map(|name| (src.path().join(name), root.join(name)))
+            .collect();
+        let mut out = vec![];
+        let errors = write_tar(queue, &root, &mut out, format, false, false, None);
+
+        let tar = match format {
+            Format::TarZst => zstd::stream::decode_all(&out[..]).unwrap(),
+            _ => out,
+        };
+        let mut archive = Archive::new(&tar[..]);
+        let entries = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                // a symlink reads as where it points to
+                let mut data = vec![];
+                match entry.link_name().unwrap() {
+                    Some(target) => data.extend_from_slice(target.to_string_lossy().as_bytes()),
+                    None => entry.read_to_end(&mut data).map(|_| ()).unwrap(),
+                }
+                let name = entry.path().unwrap().to_string_lossy().into_owned();
+                (name, entry.header().entry_type(), data)
+            }).collect();
+        (entries, errors)
+    }
+
+    #[test]
+    fn archive_paths() {
+        assert_eq!(archive_path(Path::new("backup/home"), Format::Tar), Path::new("backup/home.tar"));
+        assert_eq!(archive_path(Path::new("backup/home"), Format::TarZst), Path::new("backup/home.tar.zst"));
+    }
+
+    #[test]
+    fn tar_layout() {
+        for format in [Format::Tar, Format::TarZst] {
+            let (entries, errors) = archived(format);
+            let layout: Vec<_> = entries.iter().map(|(name, kind, data)| (name.as_str(), *kind, data.as_slice())).collect();
+            assert_eq!(
+                layout,
+                [
+                    ("dir", EntryType::Directory, &b""[..]),
+                    ("dir/file", EntryType::Regular, b"file"),
+                    ("link", EntryType::Symlink, b"dir/file"),
+                ]
+            );
+
+            // the file that could not be read is skipped, not the archive
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0].contains("Failed to archive"));
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! The `check` subcommand. Verifies a repository, or a plain backup against
+//! its manifest, and optionally repairs it.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use regex::Regex;
+
+use encrypt;
+use manifest::{self, Manifest, MANIFEST_FILE};
+use names;
+use repo::lock::Lock;
+use repo::{self, Repository};
+use storage::{Local, Storage};
+
+/// Builds the clap subcommand
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("check")
+        .about("Verifies a repository or a plain backup and reports missing, damaged and orphaned data.")
+        .arg(
+            Arg::with_name("source")
+                .short("s")
+                .long("source")
+                .value_name("BACKUP_PATH")
+                .help("The repository or plain backup to check, ie. backup_dir/home")
+                .takes_value(true)
+                .required(true),
+        ).arg(
+            Arg::with_name("repair")
+                .long("repair")
+                .help("Repairs what can be repaired")
+                .long_help(
+                    "Repairs what can be repaired. A repository gets its index\
+                     rebuilt from the packs, and snapshots that reference\
+                     missing chunks are dropped. A plain backup has the\
+                     entries of missing files dropped from its manifest, and\
+                     with --read-data damaged files are removed, so the next\
+                     backup copies them again.",
+                ),
+        ).arg(
+            Arg::with_name("read_data")
+                .long("read-data")
+                .help("Also hashes every file of a plain backup")
+                .long_help(
+                    "Also reads every file of a plain backup and compares its\
+                     sha256 with the one in the manifest. Repositories always\
+                     have every chunk hashed.",
+                ),
+        ).arg(
+            Arg::with_name("quite")
+                .short("q")
+                .long("quite")
+                .help("Stop backr from printing to stdout."),
+        )
+}
+
+/// Runs the check subcommand and returns the errors that are left
+pub fn run(cli: &ArgMatches) -> Vec<String> {
+    let source = PathBuf::from(cli.value_of("source").unwrap());
+    let repair = cli.is_present("repair");
+    let read_data = cli.is_present("read_data");
+    let quite = !cli.is_present("quite");
+
+    if quite {
+        say!("** Checking {:?}", source);
+    }
+
+    let errors = match Repository::exists(&source) {
+        true => Repository::open(&source)
+            .and_then(|repo| {
+                // a repair writes to the repository
+                let _lock = match repair {
+                    true => Some(Lock::acquire(repo.root())?),
+                    false => None,
+                };
+                repo::check::check(&repo, repair, quite)
+            })
+            .unwrap_or_else(|error| {
+                vec![format!("Error: Failed to check the repository {:?} \n {}", source, error)]
+            }),
+        false if source.join(MANIFEST_FILE).is_file() => check_plain(&source, repair, read_data, quite),
+        false => vec![format!(
+            "Error: {:?} is neither a repository nor a backup with a manifest",
+            source
+        )],
+    };
+
+    if quite {
+        for error in &errors {
+            say!("{}", error);
+        }
+        match errors.is_empty() {
+            true => say!("** No errors found"),
+            false => say!("** Total errors {}", errors.len()),
+        }
+    }
+
+    errors
+}
+
+/// Checks that every file in the manifest of a plain backup is still there,
+/// and with `read_data` that it still has the content it was stored with
+fn check_plain(root: &Path, repair: bool, read_data: bool, quite: bool) -> Vec<String> {
+    let manifest = match Manifest::load(root) {
+        Ok(manifest) => manifest,
+        Err(error) => return vec![format!("Error: Failed to read the manifest \n {}", error)],
+    };
+
+    let mut errors = vec![];
+    // damaged files are handled like missing ones
+    let mut missing = vec![];
+    let mut unhashed = 0;
+    let mut stored = HashSet::new();
+    let entries = manifest.entries();
+    for entry in &entries {
+        let path = root.join(&entry.stored);
+        match fs::metadata(&path) {
+            // files stored as is have the size of their source
+            Ok(meta) if entry.stored == entry.path && meta.len() != entry.size => missing.push((
+                entry.path.clone(),
+                format!(
+                    "Error: {:?} is {} bytes, but was backed up with {} bytes",
+                    path,
+                    meta.len(),
+                    entry.size
+                ),
+            )),
+            Ok(_) if read_data => match (&entry.sha256, manifest::hash_file(&path)) {
+                (Some(expected), Ok(ref actual)) if expected != actual => missing.push((
+                    entry.path.clone(),
+                    format!("Error: {:?} does not match the hash it was backed up with", path),
+                )),
+                (Some(_), Err(error)) => errors.push(format!("Error: Failed to read {:?} \n {}", path, error)),
+                (None, _) => unhashed += 1,
+                _ => (),
+            },
+            Ok(_) => (),
+            Err(error) => missing.push((
+                entry.path.clone(),
+                format!("Error: {:?} is missing \n {}", path, error),
+            )),
+        }
+        stored.insert(path);
+    }
+
+    // files in the destination that the manifest does not know about
+    let internal = [
+        root.join("backr_log.txt"),
+        root.join(encrypt::SALT_FILE),
+        root.join(MANIFEST_FILE),
+        root.join(names::NAMES_FILE),
+    ];
+    let (files, _) = ::walk(
+        vec![],
+        vec![],
+        &root.to_path_buf(),
+        &root.to_path_buf(),
+        &Regex::new(".*").unwrap(),
+        false,
+        None,
+        None,
+        None,
+        None,
+        &mut Local,
+    );
+    let orphaned = files
+        .iter()
+        .filter(|(path, _)| !stored.contains(path) && !internal.contains(path) && !path.is_dir())
+        .count();
+
+    if quite {
+        say!("** Checked {} files in the manifest", entries.len());
+        if unhashed > 0 {
+            say!("** {} files were backed up before hashes were recorded", unhashed);
+        }
+        if orphaned > 0 {
+            say!("** {} files in the backup are not in the manifest", orphaned);
+        }
+    }
+
+    if !repair || missing.is_empty() {
+        errors.extend(missing.into_iter().map(|(_, error)| error));
+        return errors;
+    }
+
+    // a damaged file is removed, so the next backup does not skip it
+    for (path, _) in &missing {
+        if let Some(entry) = entries.iter().find(|entry| entry.path == *path) {
+            if let Err(error) = Local.remove(&root.join(&entry.stored)) {
+                errors.push(format!("Error: Failed to remove {:?} \n {}", entry.stored, error));
+            }
+        }
+        manifest.remove(path);
+    }
+    match manifest.save() {
+        Ok(_) => {
+            if quite {
+                say!("** Dropped {} missing or damaged files from the manifest", missing.len());
+            }
+        }
+        Err(error) => {
+            errors.extend(missing.into_iter().map(|(_, error)| error));
+            errors.push(format!("Error: Failed to write the manifest \n {}", error));
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn repair_drops_files_of_another_size() {
+        let root = TempDir::new().unwrap();
+        let manifest = Manifest::new(root.path());
+        for name in ["kept", "grown", "damaged"] {
+            let path = root.path().join(name);
+            fs::write(&path, name).unwrap();
+            manifest.record(&path, &path, &path, vec![]);
+        }
+        manifest.save().unwrap();
+        fs::write(root.path().join("grown"), "grown more").unwrap();
+        fs::write(root.path().join("damaged"), "DAMAGED").unwrap();
+
+        assert_eq!(check_plain(root.path(), false, false, false).len(), 1);
+        assert_eq!(check_plain(root.path(), false, true, false).len(), 2);
+        assert_eq!(check_plain(root.path(), true, true, false).len(), 0);
+        assert!(!root.path().join("grown").exists());
+        assert!(!root.path().join("damaged").exists());
+        let entries = Manifest::load(root.path()).unwrap().entries();
+        assert_eq!(entries.iter().map(|entry| entry.path.as_str()).collect::<Vec<_>>(), ["kept"]);
+        assert!(check_plain(root.path(), false, true, false).is_empty());
+    }
+}
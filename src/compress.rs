@@ -0,0 +1,131 @@
+//! Optional per-file zstd compression.
+//!
+//! Compressed files are stored with the `.backr.zst` suffix, so they can be
+//! recognized on restore and can still be opened with the zstd cli.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use zstd::stream::{copy_decode, copy_encode};
+
+
+/// Appended to the name of every compressed file
+pub const SUFFIX: &str = ".backr.zst";
+
+/// Files with these extensions are already compressed and are copied as is
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "br", "bz2", "deb", "docx", "epub", "flac", "gif", "gz", "heic",
+    "jar", "jpeg", "jpg", "lz", "lz4", "lzma", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "odp",
+    "ods", "odt", "ogg", "opus", "png", "pptx", "rar", "rpm", "tgz", "txz", "webm", "webp",
+    "xlsx", "xz", "zip", "zst",
+];
+
+/// Sample size used for the entropy check
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Samples with more bits of entropy per byte than this are assumed to be
+/// compressed or encrypted already
+const MAX_ENTROPY: f64 = 7.5;
+
+/// The compression settings passed with --compress
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compression {
+    /// The zstd level, 1 - 22
+    pub level: i32,
+}
+
+impl Compression {
+    /// Parses `zstd` or `zstd:LEVEL`
+    pub fn parse(value: &str) -> Result<Compression, String> {
+        let mut split = value.splitn(2, ':');
+
+        match split.next() {
+            Some("zstd") => (),
+            _ => return Err(format!("{:?} is not supported, try zstd or zstd:LEVEL", value)),
+        }
+
+        let level = match split.next() {
+            Some(level) => match level.parse::<i32>() {
+                Ok(level) if (1..=22).contains(&level) => level,
+                _ => return Err(format!("{:?} is not a zstd level between 1 and 22", level)),
+            },
+            None => 3,
+        };
+
+        Ok(Compression { level })
+    }
+}
+
+/// Returns the path a compressed copy of `dest` is stored under
+pub fn compressed_path(dest: &Path) -> PathBuf {
+    let mut name = dest.as_os_str().to_os_string();
+    name.push(SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Returns the original path of a compressed file, or None if `path` is not
+/// compressed
+pub fn original_path(path: &Path) -> Option<PathBuf> {
+    path.to_str()
+        .and_then(|path| path.strip_suffix(SUFFIX))
+        .map(PathBuf::from)
+}
+
+/// Decides if compressing `src` is worth it. Files with a known compressed
+/// extension are skipped without being opened, everything else has its first
+/// block checked for entropy.
+pub fn worth_compressing(src: &Path) -> bool {
+    if let Some(ext) = src.extension().and_then(|ext| ext.to_str()) {
+        let ext = ext.to_lowercase();
+        if COMPRESSED_EXTENSIONS.contains(&ext.as_str()) {
+            return false;
+        }
+    }
+
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE);
+    match File::open(src).and_then(|file| file.take(SAMPLE_SIZE as u64).read_to_end(&mut sample)) {
+        Ok(_) => entropy(&sample) <= MAX_ENTROPY,
+        // let the copy report the error
+        Err(_) => false,
+    }
+}
+
+/// Returns the Shannon entropy of `data` in bits per byte
+fn entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+
+    let mut counts = [0usize; 256];
+    for byte in data {
+        counts[*byte as usize] += 1;
+    }
+
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Compresses `src` into `dest`
+pub fn compress_file(src: &Path, dest: &mut dyn Write, level: i32) -> io::Result<()> {
+    copy_encode(BufReader::new(File::open(src)?), dest, level)
+}
+
+/// Decompresses `src` into `dest`, keeping the permissions of `src`
+pub fn decompress_file(src: &Path, dest: &Path) -> io::Result<u64> {
+    let reader = File::open(src)?;
+    let permissions = reader.metadata()?.permissions();
+    let mut writer = File::create(dest)?;
+
+    copy_decode(BufReader::new(reader), &mut writer)?;
+    fs::set_permissions(dest, permissions)?;
+
+    fs::metadata(dest).map(|meta| meta.len())
+}
@@ -0,0 +1,310 @@
+//! The copy engine used by the backup threads.
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use age::x25519::Recipient;
+use age::Identity;
+
+use compress::{self, Compression};
+use encrypt::{self, Key, Keys};
+use manifest::Manifest;
+use pubkey;
+use sparse;
+use storage::{Meta, Storage};
+use throttle::{Throttle, Throttled};
+
+/// Size of the buffer used when a file has to be copied by hand
+const BLOCK_SIZE: usize = 128 * 1024;
+
+/// Settings that decide how each file is written to the destination. It is
+/// cloned into every backup thread.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Limits shared by every thread
+    pub throttle: Option<Arc<Throttle>>,
+    /// Compress files that are worth compressing
+    pub compress: Option<Compression>,
+    /// Encrypt every file with this key
+    pub encrypt: Option<Key>,
+    /// Encrypt every file to these public keys
+    pub recipients: Option<Arc<Vec<Recipient>>>,
+    /// Records every file that is written
+    pub manifest: Option<Arc<Manifest>>,
+    /// Files at least this large only have their changes written, if the
+    /// storage can
+    pub delta: Option<u64>,
+    /// Turn long runs of zeros into holes, besides keeping the holes files
+    /// already have
+    pub sparse: bool,
+}
+
+/// Everything needed to undo the encryption of a backup. The passphrase is
+/// only asked for once a file encrypted with one shows up.
+pub struct Secrets<F: FnMut() -> io::Result<String>> {
+    pub keys: Keys<F>,
+    pub identities: Vec<Box<dyn Identity>>,
+}
+
+/// Returns every path a backup of `dest` may be stored under, plain first
+pub fn stored_paths(dest: &Path) -> [PathBuf; 5] {
+    [
+        dest.to_path_buf(),
+        compress::compressed_path(dest),
+        encrypt::encrypted_path(dest),
+        pubkey::encrypted_path(dest, false),
+        pubkey::encrypted_path(dest, true),
+    ]
+}
+
+/// Backs up a single file. Depending on the options the file is encrypted to
+/// public keys and stored under `dest` + `.backr.age`, encrypted with a
+/// passphrase and stored under `dest` + `.backr.enc`, compressed and stored
+/// under `dest` + `.backr.zst`, or copied to `dest`. Encrypted files are
+/// compressed before they are encrypted. The variants that are not written
+/// are removed afterwards, so a restore never sees more than one.
+///
+/// The file is written under a `.backr.part` name and renamed once it is
+/// complete, so an interrupted backup never leaves a truncated file behind,
+/// unless the storage only shows complete files anyway. A large copy that is
+/// already there is patched in place instead, see delta.rs. It keeps the
+/// permissions and mtime of `src`.
+pub fn backup_file(storage: &mut dyn Storage, src: &Path, dest: &Path, opts: &Options) -> io::Result<()> {
+    let metadata = fs::metadata(src)?;
+    let meta = Meta::of(&metadata);
+    let throttle = opts.throttle.as_deref();
+    let level = match opts.compress {
+        Some(compression) if compress::worth_compressing(src) => Some(compression.level),
+        _ => None,
+    };
+
+    let target = match (&opts.recipients, &opts.encrypt, level) {
+        (Some(_), _, level) => pubkey::encrypted_path(dest, level.is_some()),
+        (None, Some(_), _) => encrypt::encrypted_path(dest),
+        (None, None, Some(_)) => compress::compressed_path(dest),
+        (None, None, None) => dest.to_path_buf(),
+    };
+
+    // compressed and encrypted files change all over, so only plain copies
+    // are patched
+    let patched = match (&opts.recipients, &opts.encrypt, level, opts.delta) {
+        (None, None, None, Some(threshold)) if metadata.len() >= threshold => {
+            storage.patch_file(src, &target, meta, throttle)?
+        }
+        _ => false,
+    };
+
+    if !patched {
+        let part = match storage.atomic() {
+            true => target.clone(),
+            false => part_path(&target),
+        };
+        match (&opts.recipients, &opts.encrypt, level) {
+            (Some(recipients), _, level) => storage.write(&part, Some(meta), &mut |writer| {
+                pubkey::encrypt_file(src, &mut Throttled::new(writer, throttle), recipients, level)
+            }),
+            (None, Some(key), level) => storage.write(&part, Some(meta), &mut |writer| {
+                encrypt::encrypt_file(src, &mut Throttled::new(writer, throttle), key, level)
+            }),
+            (None, None, Some(level)) => storage.write(&part, Some(meta), &mut |writer| {
+                compress::compress_file(src, &mut Throttled::new(writer, throttle), level)
+            }),
+            (None, None, None) => storage.copy_file(src, &part, Some(meta), throttle, opts.sparse),
+        }?;
+        if part != target {
+            storage.rename(&part, &target)?;
+        }
+    }
+
+    // the other variants only go once the new one is complete, so a failed
+    // write keeps the last good copy
+    for stale in stored_paths(dest).iter().filter(|path| **path != target) {
+        storage.remove(stale)?;
+    }
+
+    record(opts, src, dest, &target);
+    Ok(())
+}
+
+/// Creates the directory `dest` for the directory `src`, with the
+/// permissions of `src` but writable by its owner until `finish_dirs` runs
+pub fn backup_dir(storage: &mut dyn Storage, src: &Path, dest: &Path) -> io::Result<()> {
+    let meta = Meta::of(&fs::metadata(src)?);
+    storage.mkdir_all(dest)?;
+    storage.set_meta(dest, Meta { mode: meta.mode | 0o700, ..meta })
+}
+
+/// Gives the directories the permissions and mtime of their sources, once
+/// everything in them is written, as writing a file changes the mtime of
+/// its directory. The deepest ones go first, so a parent that can not be
+/// entered anymore does not lock its children out. Returns the errors it ran
+/// into.
+pub fn finish_dirs(storage: &mut dyn Storage, mut dirs: Vec<(PathBuf, PathBuf)>) -> Vec<String> {
+    dirs.sort_by_key(|(_, dest)| std::cmp::Reverse(dest.components().count()));
+
+    let mut errors = vec![];
+    for (src, dest) in dirs {
+        let result = fs::metadata(&src).and_then(|meta| storage.set_meta(&dest, Meta::of(&meta)));
+        if let Err(error) = result {
+            errors.push(format!("Error: Failed to set the permissions of {:?} \n {}", dest, error));
+        }
+    }
+    errors
+}
+
+/// Adds a file that was backed up to `target` to the manifest, if there is
+/// one
+pub fn record(opts: &Options, src: &Path, dest: &Path, target: &Path) {
+    if let Some(ref manifest) = opts.manifest {
+        let recipients = match opts.recipients {
+            Some(ref recipients) => recipients.iter().map(|r| r.to_string()).collect(),
+            None => vec![],
+        };
+        manifest.record(src, dest, target, recipients);
+    }
+}
+
+/// Returns the name a file is written under until it is complete
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_os_string();
+    part.push(".backr.part");
+    PathBuf::from(part)
+}
+
+/// Restores a single file from a backup, decrypting and decompressing it if
+/// needed. `dest` is the path inside the backup, the suffix is stripped from
+/// it here.
+pub fn restore_file<F: FnMut() -> io::Result<String>>(
+    src: &Path,
+    dest: &Path,
+    secrets: &mut Secrets<F>,
+) -> io::Result<u64> {
+    if let Some((original, compressed)) = pubkey::original_path(dest) {
+        return pubkey::decrypt_file(src, &original, &secrets.identities, compressed);
+    }
+
+    if let Some(original) = encrypt::original_path(dest) {
+        return encrypt::decrypt_file(src, &original, &mut secrets.keys);
+    }
+
+    match compress::original_path(dest) {
+        Some(original) => compress::decompress_file(src, &original),
+        None => copy(src, dest, None, false),
+    }
+}
+
+/// Copies `src` to `dest`, keeping its holes, see sparse.rs. With `sparse`
+/// long runs of zeros become holes too. A file without holes and without a
+/// throttle is copied with `fs::copy`, otherwise the file is copied one block
+/// at a time so every read and write can be accounted for.
+pub fn copy(src: &Path, dest: &Path, throttle: Option<&Throttle>, sparse: bool) -> io::Result<u64> {
+    let mut reader = fs::File::open(src)?;
+    let ranges = match (sparse::data_ranges(&reader)?, sparse, throttle) {
+        (None, false, None) => return fs::copy(src, dest),
+        (Some(ranges), ..) => ranges,
+        (None, ..) => vec![(0, reader.metadata()?.len())],
+    };
+
+    let mut writer = fs::File::create(dest)?;
+    let mut buf = vec![0; BLOCK_SIZE];
+
+    // the holes between the ranges are skipped
+    for (start, end) in ranges {
+        reader.seek(SeekFrom::Start(start))?;
+        writer.seek(SeekFrom::Start(start))?;
+        let mut offset = start;
+        while offset < end {
+            let want = BLOCK_SIZE.min((end - offset) as usize);
+            if let Some(throttle) = throttle {
+                throttle.take_op();
+            }
+            let len = reader.read(&mut buf[..want])?;
+            if len == 0 {
+                break;
+            }
+
+            if let Some(throttle) = throttle {
+                throttle.take_bytes(len as u64);
+                throttle.take_op();
+            }
+            match sparse {
+                true => sparse::write_skipping_zeros(&mut writer, &buf[..len], offset)?,
+                false => writer.write_all(&buf[..len])?,
+            }
+            offset += len as u64;
+        }
+    }
+
+    // a hole at the end is only there once the length is set
+    let len = reader.metadata()?.len();
+    writer.set_len(len)?;
+
+    // match fs::copy and carry the permissions over
+    writer.set_permissions(reader.metadata()?.permissions())?;
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    use filetime::{self, FileTime};
+    use tempfile::TempDir;
+
+    use storage::{self, Local};
+
+    #[test]
+    fn copies_keep_their_holes() {
+        use sparse::tests::{holey, on_disk, HOLEY_RANGES};
+
+        let dir = TempDir::new().unwrap();
+        let (src, dest) = (dir.path().join("src"), dir.path().join("dest"));
+        let data = holey(&src);
+        assert_eq!(copy(&src, &dest, None, false).unwrap(), data.len() as u64);
+        assert_eq!(fs::read(&dest).unwrap(), data);
+        assert_eq!(sparse::data_ranges(&fs::File::open(&dest).unwrap()).unwrap().unwrap(), HOLEY_RANGES);
+        assert!(on_disk(&dest) <= on_disk(&src));
+
+        // --sparse turns an aligned run of zeros into a hole
+        let run = sparse::ZERO_RUN;
+        let data = [&vec![1; run][..], &vec![0; run], &vec![2; run]].concat();
+        fs::write(&src, &data).unwrap();
+        copy(&src, &dest, None, false).unwrap();
+        assert_eq!(sparse::data_ranges(&fs::File::open(&dest).unwrap()).unwrap(), None);
+        copy(&src, &dest, None, true).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), data);
+        let ranges = sparse::data_ranges(&fs::File::open(&dest).unwrap()).unwrap().unwrap();
+        assert_eq!(ranges, [(0, run as u64), (2 * run as u64, 3 * run as u64)]);
+    }
+
+    #[test]
+    fn finished_dirs_get_their_metadata() {
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let dirs = ["dir", "dir/empty", "dir/locked"];
+        for (dir, mode) in dirs.iter().zip([0o750, 0o700, 0o500]) {
+            fs::create_dir_all(src.path().join(dir)).unwrap();
+            fs::create_dir_all(dest.path().join(dir)).unwrap();
+            fs::set_permissions(src.path().join(dir), fs::Permissions::from_mode(mode)).unwrap();
+        }
+        for (dir, mtime) in dirs.iter().zip([1_500_000_000, 1_400_000_000, 1_300_000_000]) {
+            filetime::set_file_mtime(src.path().join(dir), FileTime::from_unix_time(mtime, 0)).unwrap();
+        }
+        fs::write(dest.path().join("dir/locked/file"), b"written last").unwrap();
+
+        let queue = dirs.iter().map(|dir| (src.path().join(dir), dest.path().join(dir))).collect();
+        assert!(finish_dirs(&mut Local, queue).is_empty());
+        for dir in dirs {
+            let (want, got) = (fs::metadata(src.path().join(dir)).unwrap(), fs::metadata(dest.path().join(dir)).unwrap());
+            assert_eq!(storage::mode(&got), storage::mode(&want), "{}", dir);
+            assert_eq!(storage::mtime(&got), storage::mtime(&want), "{}", dir);
+        }
+
+        let missing = vec![(src.path().join("missing"), dest.path().join("missing"))];
+        assert_eq!(finish_dirs(&mut Local, missing).len(), 1);
+    }
+}
@@ -0,0 +1,407 @@
+//! Delta transfer, so a large file that changed in a few places is not
+//! copied whole again.
+//!
+//! Local destinations compare the file with the stored copy block by block
+//! and rewrite only the blocks that differ. backr serve sends the signature
+//! of its copy instead, a weak rolling checksum and an md5 of every block,
+//! and the client answers with the blocks that can be reused and the bytes
+//! in between, like rsync. Files smaller than the threshold are copied whole,
+//! which is faster than comparing them.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use md5::{Digest, Md5};
+
+use encrypt::read_full;
+use throttle::{Throttle, Throttled};
+
+/// Files at least this large are sent as a delta by default
+pub const DEFAULT_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Size of the blocks local files are compared in
+const COMPARE_SIZE: usize = 1024 * 1024;
+
+/// Bounds of the block size of a signature
+const MIN_BLOCK: u64 = 2 * 1024;
+const MAX_BLOCK: u64 = 128 * 1024;
+
+/// Literal bytes are handed on in pieces of at most this size
+const MAX_LITERAL: usize = 128 * 1024;
+
+/// A copy spans at most this many blocks, at most 1 GiB
+const MAX_RUN: u32 = 8192;
+
+/// A block of the copy the other end already has
+#[derive(Debug, Clone, Copy)]
+pub struct Block {
+    pub weak: u32,
+    pub strong: [u8; 16],
+}
+
+/// Describes a file by the checksums of its blocks. The last block may be
+/// shorter than the others.
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub block_size: u32,
+    pub len: u64,
+    pub blocks: Vec<Block>,
+}
+
+/// One step of rebuilding a file
+#[derive(Debug, PartialEq)]
+pub enum Op<'a> {
+    /// `count` blocks of the old copy, starting with block `index`
+    Copy { index: u64, count: u32 },
+    /// Bytes the old copy does not have
+    Data(&'a [u8]),
+}
+
+/// Returns the block size for a file of `len` bytes, about its square root
+/// like rsync, so big files do not get huge signatures
+pub fn block_size(len: u64) -> u32 {
+    let root = (len as f64).sqrt() as u64;
+    (root.div_ceil(1024) * 1024).clamp(MIN_BLOCK, MAX_BLOCK) as u32
+}
+
+impl Signature {
+    /// Reads `len` bytes from `reader` and checksums every block
+    pub fn of<R: Read>(reader: &mut R, len: u64, block_size: u32) -> io::Result<Signature> {
+        let mut blocks = vec![];
+        let mut buf = vec![0; block_size as usize];
+        let mut left = len;
+        while left > 0 {
+            let size = left.min(block_size as u64) as usize;
+            reader.read_exact(&mut buf[..size])?;
+            blocks.push(Block {
+                weak: Rolling::new(&buf[..size]).digest(),
+                strong: Md5::digest(&buf[..size]).into(),
+            });
+            left -= size as u64;
+        }
+        Ok(Signature { block_size, len, blocks })
+    }
+
+    /// The length of block `index`
+    pub fn block_len(&self, index: usize) -> usize {
+        let start = index as u64 * self.block_size as u64;
+        (self.len - start).min(self.block_size as u64) as usize
+    }
+}
+
+/// The rsync checksum of a window, which can be moved by one byte without
+/// reading the whole window again
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Rolling {
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((window.len() - i) as u32 * byte as u32);
+        }
+        Rolling {
+            a,
+            b,
+            len: window.len() as u32,
+        }
+    }
+
+    /// Drops `out` from the front of the window and appends `add`
+    fn roll(&mut self, out: u8, add: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(add as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    /// Drops `out` from the front of the window, at the end of the file
+    fn shrink(&mut self, out: u8) {
+        self.a = self.a.wrapping_sub(out as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32));
+        self.len -= 1;
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Reads the new version of a file from `reader` and hands `emit` the steps
+/// that rebuild it from the copy described by `signature`. Neighbouring
+/// blocks are joined into one Copy.
+pub fn delta<R: Read>(reader: &mut R, signature: &Signature, emit: &mut dyn FnMut(Op) -> io::Result<()>) -> io::Result<()> {
+    let size = signature.block_size as usize;
+    let mut table: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, block) in signature.blocks.iter().enumerate() {
+        table.entry(block.weak).or_default().push(index);
+    }
+
+    // buf[literal..pos] has no match yet, buf[pos..pos + size] is the window
+    let mut buf = vec![];
+    let (mut pos, mut literal) = (0, 0);
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+    let mut run: Option<(u64, u32)> = None;
+
+    loop {
+        // one byte past the window is needed to roll it
+        if !eof && buf.len() < pos + size + 1 {
+            buf.drain(..literal);
+            pos -= literal;
+            literal = 0;
+            let start = buf.len();
+            buf.resize(start + size.max(MAX_LITERAL), 0);
+            let len = read_full(reader, &mut buf[start..])?;
+            buf.truncate(start + len);
+            eof = len == 0;
+            continue;
+        }
+
+        let end = buf.len().min(pos + size);
+        if pos == end {
+            break;
+        }
+        let window = &buf[pos..end];
+        let sum = rolling.unwrap_or_else(|| Rolling::new(window));
+        // the block that continues the run is preferred among equal ones
+        let next = run.map(|(start, count)| (start + count as u64) as usize);
+        let found = table.get(&sum.digest()).and_then(|indexes| {
+            let strong: [u8; 16] = Md5::digest(window).into();
+            let matches = |index: &usize| {
+                signature.block_len(*index) == window.len() && signature.blocks[*index].strong == strong
+            };
+            match next {
+                Some(next) if indexes.contains(&next) && matches(&next) => Some(next),
+                _ => indexes.iter().copied().find(matches),
+            }
+        });
+
+        if let Some(index) = found {
+            if literal < pos {
+                if let Some((index, count)) = run.take() {
+                    emit(Op::Copy { index, count })?;
+                }
+                emit(Op::Data(&buf[literal..pos]))?;
+            }
+            run = match run {
+                Some((start, count)) if start + count as u64 == index as u64 && count < MAX_RUN => Some((start, count + 1)),
+                Some((start, count)) => {
+                    emit(Op::Copy { index: start, count })?;
+                    Some((index as u64, 1))
+                }
+                None => Some((index as u64, 1)),
+            };
+            pos = end;
+            literal = pos;
+            rolling = None;
+            continue;
+        }
+
+        let mut sum = sum;
+        match end < buf.len() {
+            true => sum.roll(buf[pos], buf[end]),
+            false => sum.shrink(buf[pos]),
+        }
+        rolling = Some(sum);
+        pos += 1;
+
+        if pos - literal >= MAX_LITERAL {
+            if let Some((index, count)) = run.take() {
+                emit(Op::Copy { index, count })?;
+            }
+            emit(Op::Data(&buf[literal..pos]))?;
+            literal = pos;
+        }
+    }
+
+    if let Some((index, count)) = run.take() {
+        emit(Op::Copy { index, count })?;
+    }
+    if literal < buf.len() {
+        emit(Op::Data(&buf[literal..]))?;
+    }
+    Ok(())
+}
+
+/// Rewrites the blocks of `dest` that differ from `src` and cuts it to the
+/// length of `src`. The file is changed in place and every write bumps its
+/// mtime, so the caller patches a copy that is not under its final name.
+pub fn patch(src: &Path, dest: &File, throttle: Option<&Throttle>) -> io::Result<()> {
+    let mut reader = Throttled::new(File::open(src)?, throttle);
+    let mut file = dest;
+    let (mut new, mut old) = (vec![0; COMPARE_SIZE], vec![0; COMPARE_SIZE]);
+    let mut offset = 0;
+
+    loop {
+        let len = read_full(&mut reader, &mut new)?;
+        if len == 0 {
+            break;
+        }
+        let old_len = read_full(&mut file, &mut old[..len])?;
+        if old_len != len || old[..len] != new[..len] {
+            file.seek(SeekFrom::Start(offset))?;
+            if let Some(throttle) = throttle {
+                throttle.take_op();
+                throttle.take_bytes(len as u64);
+            }
+            file.write_all(&new[..len])?;
+        }
+        offset += len as u64;
+    }
+
+    file.set_len(offset)?;
+    file.sync_all()
+}
+
+/// Opens the stored copy of a file for `patch`, or returns None if there is
+/// nothing to patch
+pub fn open_existing(dest: &Path) -> Option<File> {
+    match OpenOptions::new().read(true).write(true).open(dest) {
+        Ok(file) if file.metadata().map(|meta| meta.is_file()).unwrap_or(false) => Some(file),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    /// Bytes that do not repeat, so every block of them differs
+    pub fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            }).collect()
+    }
+
+    /// Rebuilds `new` from `old` through a delta, and returns the steps
+    /// as (copied blocks, literal bytes)
+    fn round_trip(old: &[u8], new: &[u8], block_size: u32) -> (u64, usize) {
+        let signature = Signature::of(&mut &old[..], old.len() as u64, block_size).unwrap();
+        let (mut rebuilt, mut copied, mut literal) = (vec![], 0, 0);
+        delta(&mut &new[..], &signature, &mut |op| {
+            match op {
+                Op::Copy { index, count } => {
+                    assert!(count > 0 && count <= MAX_RUN);
+                    let start = (index * block_size as u64) as usize;
+                    let end = (start + count as usize * block_size as usize).min(old.len());
+                    rebuilt.extend_from_slice(&old[start..end]);
+                    copied += count as u64;
+                }
+                Op::Data(data) => {
+                    assert!(!data.is_empty() && data.len() <= MAX_LITERAL);
+                    rebuilt.extend_from_slice(data);
+                    literal += data.len();
+                }
+            }
+            Ok(())
+        }).unwrap();
+        assert!(rebuilt == new, "the delta does not rebuild the file");
+        (copied, literal)
+    }
+
+    #[test]
+    fn block_sizes() {
+        assert_eq!(block_size(0), MIN_BLOCK as u32);
+        assert_eq!(block_size(100 << 20), 10 * 1024);
+        assert_eq!(block_size(10_241 * 10_241), 11 * 1024);
+        assert_eq!(block_size(1 << 50), MAX_BLOCK as u32);
+    }
+
+    #[test]
+    fn signature_blocks() {
+        let data = noise(5000, 1);
+        let signature = Signature::of(&mut &data[..], 5000, 2048).unwrap();
+        assert_eq!(signature.blocks.len(), 3);
+        assert_eq!(signature.block_len(2), 904);
+        assert_eq!(signature.blocks[2].strong, <[u8; 16]>::from(Md5::digest(&data[4096..])));
+        assert!(Signature::of(&mut &data[..], 6000, 2048).is_err());
+    }
+
+    #[test]
+    fn rolling_matches_a_fresh_sum() {
+        let data = noise(100, 2);
+        let mut sum = Rolling::new(&data[..32]);
+        for start in 1..=68 {
+            sum.roll(data[start - 1], data[start + 31]);
+            assert_eq!(sum.digest(), Rolling::new(&data[start..start + 32]).digest());
+        }
+        sum.shrink(data[68]);
+        assert_eq!(sum.digest(), Rolling::new(&data[69..]).digest());
+    }
+
+    #[test]
+    fn delta_round_trips() {
+        let old = noise(100_000, 3);
+
+        // the same file is one copy
+        assert_eq!(round_trip(&old, &old, 2048), (49, 0));
+
+        let mut changed = old.clone();
+        changed[50_000] ^= 1;
+        assert_eq!(round_trip(&old, &changed, 2048), (48, 2048));
+
+        let inserted = [&old[..10], &b"inserted"[..], &old[10..]].concat();
+        assert_eq!(round_trip(&old, &inserted, 2048).1, 2048 + 8);
+
+        let shifted = [&b"x"[..], &old[..]].concat();
+        assert_eq!(round_trip(&old, &shifted, 2048), (49, 1));
+
+        // the short last block only matches at the end
+        let appended = [&old[..], &noise(5000, 4)[..]].concat();
+        assert_eq!(round_trip(&old, &appended, 2048), (48, 100_000 - 48 * 2048 + 5000));
+        assert_eq!(round_trip(&old, &old[..99_000], 2048).1, 99_000 - 48 * 2048);
+        assert_eq!(round_trip(&old, &old[2048..], 2048), (48, 0));
+
+        let moved = [&old[60_000..], &old[..60_000]].concat();
+        assert!(round_trip(&old, &moved, 2048).1 < 2 * 2048);
+
+        assert_eq!(round_trip(&old, b"", 2048), (0, 0));
+        assert_eq!(round_trip(b"", &old, 2048), (0, 100_000));
+        let other = noise(300_000, 5);
+        assert_eq!(round_trip(&old, &other, 2048).0, 0);
+    }
+
+    #[test]
+    fn long_runs_are_split() {
+        let old = vec![7; (MAX_RUN as usize + 10) * 2048];
+        let signature = Signature::of(&mut &old[..], old.len() as u64, 2048).unwrap();
+        let mut ops = vec![];
+        delta(&mut &old[..], &signature, &mut |op| {
+            if let Op::Copy { index, count } = op {
+                ops.push((index, count));
+            }
+            Ok(())
+        }).unwrap();
+        assert_eq!(ops, [(0, MAX_RUN), (MAX_RUN as u64, 10)]);
+    }
+
+    #[test]
+    fn patch_rewrites_what_differs() {
+        let dir = TempDir::new().unwrap();
+        let (src, dest) = (dir.path().join("src"), dir.path().join("dest"));
+        let new = noise(3 * COMPARE_SIZE + 17, 6);
+        for old in [noise(5 * COMPARE_SIZE, 7), new[..COMPARE_SIZE].to_vec(), vec![]] {
+            fs::write(&src, &new).unwrap();
+            fs::write(&dest, &old).unwrap();
+            patch(&src, &open_existing(&dest).unwrap(), None).unwrap();
+            assert!(fs::read(&dest).unwrap() == new);
+        }
+        assert!(open_existing(dir.path()).is_none());
+        assert!(open_existing(&dir.path().join("missing")).is_none());
+    }
+}
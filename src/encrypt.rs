@@ -0,0 +1,517 @@
+//! Optional authenticated encryption of each file.
+//!
+//! Files are encrypted with XChaCha20-Poly1305 in 64 KiB chunks using the
+//! STREAM construction, so truncating, reordering or editing a file is caught
+//! on restore. The key is derived from a passphrase with Argon2id. Encrypted
+//! files are stored with the `.backr.enc` suffix and start with a header:
+//!
+//! ```text
+//! magic "BACKRENC" | version | flags | salt (16) | nonce prefix (19)
+//! ```
+//!
+//! The header is authenticated as associated data of every chunk.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{OsRng, Payload};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zstd::stream::read::Decoder;
+use zstd::stream::write::Encoder;
+
+
+/// Appended to the name of every encrypted file
+pub const SUFFIX: &str = ".backr.enc";
+
+/// Name of the file in the destination root holding the salt
+pub const SALT_FILE: &str = "backr.salt";
+
+const MAGIC: &[u8; 8] = b"BACKRENC";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_PREFIX_LEN: usize = 19;
+const HEADER_LEN: usize = MAGIC.len() + 2 + SALT_LEN + NONCE_PREFIX_LEN;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+/// Set in the header flags when the plaintext is zstd compressed
+const FLAG_COMPRESSED: u8 = 1;
+
+/// A key derived from a passphrase and the salt it was derived with
+#[derive(Clone)]
+pub struct Key {
+    salt: [u8; SALT_LEN],
+    key: [u8; 32],
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Key").field("salt", &self.salt).finish()
+    }
+}
+
+impl Key {
+    /// Derives a key from the passphrase with Argon2id
+    pub fn derive(passphrase: &str, salt: [u8; SALT_LEN]) -> io::Result<Key> {
+        let mut key = [0; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|error| io::Error::other(error.to_string()))?;
+        Ok(Key { salt, key })
+    }
+
+    /// Derives an independent key for another purpose, ie. encrypting names
+    pub fn subkey(&self, label: &[u8]) -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key).unwrap();
+        mac.update(label);
+        mac.finalize().into_bytes().into()
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.key.into())
+    }
+}
+
+/// Returns the key of a destination. The first backup to a destination
+/// picks a random salt and stores it in `backr.salt`, together with an empty
+/// encrypted stream that later backups use to check the passphrase. Every
+/// file in a destination shares the salt, so the key is only derived once per
+/// backup. `ask` is passed true when the passphrase should be confirmed,
+/// because it is used for the first time.
+pub fn destination_key<F>(dest: &Path, ask: F) -> io::Result<Key>
+where
+    F: FnOnce(bool) -> io::Result<String>,
+{
+    let path = dest.join(SALT_FILE);
+
+    match fs::read(&path) {
+        Ok(contents) => {
+            if contents.len() < SALT_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} is corrupt", path),
+                ));
+            }
+
+            let mut salt = [0; SALT_LEN];
+            salt.copy_from_slice(&contents[..SALT_LEN]);
+            let key = Key::derive(&ask(false)?, salt)?;
+
+            // decrypting the check only works with the right passphrase
+            let mut check = &contents[SALT_LEN..];
+            let header = read_header(&mut check)?;
+            match io::copy(&mut Decryptor::new(check, &header, &key), &mut io::sink()) {
+                Ok(_) => Ok(key),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "The passphrase does not match the one used for this destination",
+                )),
+            }
+        }
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+            let mut salt = [0; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            let key = Key::derive(&ask(true)?, salt)?;
+
+            let contents = Encryptor::new(salt.to_vec(), &key, false)?.finish()?;
+            fs::write(&path, contents)?;
+            Ok(key)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// Reads the salt of a destination without checking the passphrase
+pub fn destination_salt(dest: &Path) -> io::Result<[u8; SALT_LEN]> {
+    let mut salt = [0; SALT_LEN];
+    File::open(dest.join(SALT_FILE))?.read_exact(&mut salt)?;
+    Ok(salt)
+}
+
+/// Returns the path an encrypted copy of `dest` is stored under
+pub fn encrypted_path(dest: &Path) -> PathBuf {
+    let mut name = dest.as_os_str().to_os_string();
+    name.push(SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Returns the original path of an encrypted file, or None if `path` is not
+/// encrypted
+pub fn original_path(path: &Path) -> Option<PathBuf> {
+    path.to_str()
+        .and_then(|path| path.strip_suffix(SUFFIX))
+        .map(PathBuf::from)
+}
+
+fn crypto_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Decryption failed, the passphrase is wrong or the file was modified",
+    )
+}
+
+/// Reads until `buf` is full or the reader is empty, returning the number of
+/// bytes read
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => (),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(len)
+}
+
+/// Encrypts everything written to it. `finish` has to be called to write the
+/// final chunk, without it the file can not be decrypted.
+pub struct Encryptor<W: Write> {
+    inner: W,
+    stream: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    header: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Encryptor<W> {
+    /// Writes the header to `inner` and starts a new stream
+    pub fn new(mut inner: W, key: &Key, compressed: bool) -> io::Result<Encryptor<W>> {
+        let mut nonce = [0; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.push(if compressed { FLAG_COMPRESSED } else { 0 });
+        header.extend_from_slice(&key.salt);
+        header.extend_from_slice(&nonce);
+        inner.write_all(&header)?;
+
+        Ok(Encryptor {
+            inner,
+            stream: Some(EncryptorBE32::from_aead(key.cipher(), &nonce.into())),
+            header,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    /// Encrypts the last chunk and returns the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let stream = self.stream.take().unwrap();
+        let chunk = stream
+            .encrypt_last(Payload {
+                msg: &self.buf,
+                aad: &self.header,
+            }).map_err(|_| crypto_error())?;
+        self.inner.write_all(&chunk)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Encryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+
+        // a full chunk is never the last one, the last chunk is always shorter
+        // even if that means it is empty
+        if self.buf.len() == CHUNK_SIZE {
+            let chunk = self
+                .stream
+                .as_mut()
+                .unwrap()
+                .encrypt_next(Payload {
+                    msg: &self.buf,
+                    aad: &self.header,
+                }).map_err(|_| crypto_error())?;
+            self.inner.write_all(&chunk)?;
+            self.buf.clear();
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The parts of the header needed to decrypt a file
+#[derive(Debug)]
+pub struct Header {
+    pub compressed: bool,
+    pub salt: [u8; SALT_LEN],
+    nonce: [u8; NONCE_PREFIX_LEN],
+    raw: Vec<u8>,
+}
+
+/// Reads and validates the header of an encrypted file
+pub fn read_header<R: Read>(reader: &mut R) -> io::Result<Header> {
+    let mut raw = vec![0; HEADER_LEN];
+    if read_full(reader, &mut raw)? != HEADER_LEN || &raw[..MAGIC.len()] != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Not a file encrypted by backr",
+        ));
+    }
+    if raw[MAGIC.len()] != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The file was encrypted by a newer version of backr",
+        ));
+    }
+
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; NONCE_PREFIX_LEN];
+    let offset = MAGIC.len() + 2;
+    salt.copy_from_slice(&raw[offset..offset + SALT_LEN]);
+    nonce.copy_from_slice(&raw[offset + SALT_LEN..]);
+
+    Ok(Header {
+        compressed: raw[MAGIC.len() + 1] & FLAG_COMPRESSED != 0,
+        salt,
+        nonce,
+        raw,
+    })
+}
+
+/// Decrypts a stream as it is read
+pub struct Decryptor<R: Read> {
+    inner: R,
+    stream: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    header: Vec<u8>,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Decryptor<R> {
+    /// Starts decrypting `inner`, which has to be positioned right after the
+    /// header
+    pub fn new(inner: R, header: &Header, key: &Key) -> Decryptor<R> {
+        Decryptor {
+            inner,
+            stream: Some(DecryptorBE32::from_aead(key.cipher(), &header.nonce.into())),
+            header: header.raw.clone(),
+            plain: vec![],
+            pos: 0,
+        }
+    }
+
+    /// Decrypts the next chunk into `plain`
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = vec![0; CHUNK_SIZE + TAG_LEN];
+        let len = read_full(&mut self.inner, &mut chunk)?;
+        chunk.truncate(len);
+
+        let payload = Payload {
+            msg: &chunk,
+            aad: &self.header,
+        };
+
+        self.plain = if len == CHUNK_SIZE + TAG_LEN {
+            self.stream.as_mut().unwrap().decrypt_next(payload)
+        } else {
+            self.stream.take().unwrap().decrypt_last(payload)
+        }.map_err(|_| crypto_error())?;
+        self.pos = 0;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            // the last chunk was already decrypted
+            if self.stream.is_none() {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+
+        let len = buf.len().min(self.plain.len() - self.pos);
+        buf[..len].copy_from_slice(&self.plain[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Encrypts `src` into `dest`, compressing it first if a level is given
+pub fn encrypt_file(src: &Path, dest: &mut dyn Write, key: &Key, level: Option<i32>) -> io::Result<()> {
+    let mut reader = BufReader::new(File::open(src)?);
+    let mut encryptor = Encryptor::new(dest, key, level.is_some())?;
+
+    match level {
+        Some(level) => {
+            let mut encoder = Encoder::new(encryptor, level)?;
+            io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?.finish()?;
+        }
+        None => {
+            io::copy(&mut reader, &mut encryptor)?;
+            encryptor.finish()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Derives keys for restores. Files can come from backups with different
+/// salts, so every salt gets its own key, but the passphrase is only asked for
+/// once.
+pub struct Keys<F: FnMut() -> io::Result<String>> {
+    passphrase: Option<String>,
+    ask: F,
+    cache: HashMap<[u8; SALT_LEN], Key>,
+}
+
+impl<F: FnMut() -> io::Result<String>> Keys<F> {
+    /// `ask` is called the first time a passphrase is needed
+    pub fn new(ask: F) -> Keys<F> {
+        Keys {
+            passphrase: None,
+            ask,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Returns the key for `salt`
+    pub fn get(&mut self, salt: [u8; SALT_LEN]) -> io::Result<&Key> {
+        if !self.cache.contains_key(&salt) {
+            if self.passphrase.is_none() {
+                self.passphrase = Some((self.ask)()?);
+            }
+            let key = Key::derive(self.passphrase.as_ref().unwrap(), salt)?;
+            self.cache.insert(salt, key);
+        }
+        Ok(&self.cache[&salt])
+    }
+}
+
+/// Decrypts `src` into `dest`, decompressing it if it was compressed before
+/// it was encrypted. The permissions of `src` are kept.
+pub fn decrypt_file<F: FnMut() -> io::Result<String>>(
+    src: &Path,
+    dest: &Path,
+    keys: &mut Keys<F>,
+) -> io::Result<u64> {
+    let mut reader = BufReader::new(File::open(src)?);
+    let permissions = reader.get_ref().metadata()?.permissions();
+    let header = read_header(&mut reader)?;
+    let mut decryptor = Decryptor::new(reader, &header, keys.get(header.salt)?);
+
+    // write to a temporary file, so a failed authentication does not leave a
+    // truncated file behind
+    let mut tmp = dest.as_os_str().to_os_string();
+    tmp.push(".part");
+    let tmp = PathBuf::from(tmp);
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+
+    let result = match header.compressed {
+        true => Decoder::new(decryptor).and_then(|mut decoder| io::copy(&mut decoder, &mut writer)),
+        false => io::copy(&mut decryptor, &mut writer),
+    }.and_then(|len| writer.flush().map(|_| len));
+
+    match result {
+        Ok(len) => {
+            drop(writer);
+            fs::set_permissions(&tmp, permissions)?;
+            fs::rename(&tmp, dest)?;
+            Ok(len)
+        }
+        Err(error) => {
+            drop(writer);
+            let _ = fs::remove_file(&tmp);
+            Err(error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> Key {
+        Key {
+            salt: [byte; SALT_LEN],
+            key: [byte; 32],
+        }
+    }
+
+    fn encrypt(plain: &[u8], key: &Key) -> Vec<u8> {
+        let mut encryptor = Encryptor::new(vec![], key, false).unwrap();
+        encryptor.write_all(plain).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn decrypt(mut data: &[u8], key: &Key) -> io::Result<Vec<u8>> {
+        let header = read_header(&mut data)?;
+        let mut plain = vec![];
+        Decryptor::new(data, &header, key).read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    #[test]
+    fn round_trip_at_chunk_boundaries() {
+        let key = key(1);
+        for &len in &[0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 2 * CHUNK_SIZE, 3 * CHUNK_SIZE + 5] {
+            let plain: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let data = encrypt(&plain, &key);
+            // every chunk has a tag, a full last chunk is followed by an empty one
+            let chunks = len / CHUNK_SIZE + 1;
+            assert_eq!(data.len(), HEADER_LEN + len + chunks * TAG_LEN, "len {}", len);
+            assert_eq!(decrypt(&data, &key).unwrap(), plain, "len {}", len);
+        }
+    }
+
+    #[test]
+    fn header_records_compression() {
+        let data = Encryptor::new(vec![], &key(1), true).unwrap().finish().unwrap();
+        let header = read_header(&mut &data[..]).unwrap();
+        assert!(header.compressed);
+        assert_eq!(header.salt, [1; SALT_LEN]);
+        assert!(read_header(&mut &b"BACKRENC"[..]).is_err());
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        let data = encrypt(b"secret", &key(1));
+        assert!(decrypt(&data, &key(2)).is_err());
+    }
+
+    #[test]
+    fn modified_data_fails() {
+        let mut data = encrypt(b"secret", &key(1));
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert!(decrypt(&data, &key(1)).is_err());
+
+        // the header is authenticated too
+        let mut data = encrypt(b"secret", &key(1));
+        data[MAGIC.len() + 1] ^= FLAG_COMPRESSED;
+        assert!(decrypt(&data, &key(1)).is_err());
+    }
+
+    #[test]
+    fn truncated_data_fails() {
+        let plain = vec![7; 2 * CHUNK_SIZE];
+        let data = encrypt(&plain, &key(1));
+
+        // cut at a chunk boundary, leaving only full chunks
+        let cut = HEADER_LEN + 2 * (CHUNK_SIZE + TAG_LEN);
+        assert!(decrypt(&data[..cut], &key(1)).is_err());
+        let cut = HEADER_LEN + CHUNK_SIZE + TAG_LEN;
+        assert!(decrypt(&data[..cut], &key(1)).is_err());
+        assert!(decrypt(&data[..HEADER_LEN], &key(1)).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! The `gc` subcommand. Deletes the chunks of a repository that no snapshot
+//! uses anymore.
+
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use repo::lock::Lock;
+use repo::{self, Repository};
+
+/// Builds the clap subcommand
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("gc")
+        .about("Deletes the data of a repository that no snapshot uses anymore.")
+        .arg(
+            Arg::with_name("source")
+                .short("s")
+                .long("source")
+                .value_name("REPOSITORY_PATH")
+                .help("The repository, ie. the destination of backr -f repo")
+                .takes_value(true)
+                .required(true),
+        ).arg(
+            Arg::with_name("dry_run")
+                .short("n")
+                .long("dry-run")
+                .help("Only reports how many bytes would be freed"),
+        ).arg(
+            Arg::with_name("quite")
+                .short("q")
+                .long("quite")
+                .help("Stop backr from printing to stdout."),
+        )
+}
+
+/// Runs the gc subcommand and returns the errors it ran into
+pub fn run(cli: &ArgMatches) -> Vec<String> {
+    let source = PathBuf::from(cli.value_of("source").unwrap());
+    let dry_run = cli.is_present("dry_run");
+    let quite = !cli.is_present("quite");
+
+    if quite {
+        say!("** Collecting the unused data of {:?}", source);
+    }
+
+    // a dry run only reads, so it does not wait for a running backup
+    let result = Repository::open(&source).and_then(|repo| {
+        let _lock = match dry_run {
+            true => None,
+            false => Some(Lock::acquire(repo.root())?),
+        };
+        repo::gc::gc(&repo, dry_run, quite)
+    });
+
+    match result {
+        Ok(_) => vec![],
+        Err(error) => {
+            let error = format!("Error: Failed to collect the unused data of {:?} \n {}", source, error);
+            say!("{}", error);
+            vec![error]
+        }
+    }
+}
@@ -1,7 +1,49 @@
 // for cli parsing
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg};
+use std::fs;
 use std::path::PathBuf;
 use regex::Regex;
+use age::x25519::Recipient;
+use check;
+use compress::Compression;
+use delta;
+use gc;
+use prune;
+use pubkey;
+use prompt::Sources;
+use remote::{self, Url};
+use restore;
+use serve;
+use server;
+use snapshots;
+use specials::Policy;
+use storage;
+use throttle::parse_rate;
+
+/// How the backup is written to the destination
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Replicate the source tree in the destination
+    Plain,
+    /// Write a single tar archive
+    Tar,
+    /// Write a single zstd compressed tar archive
+    TarZst,
+    /// Write into a deduplicating repository, see repo/mod.rs
+    Repo,
+}
+
+impl Format {
+    /// Returns the file extension used for the format
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Plain => "",
+            Format::Tar => ".tar",
+            Format::TarZst => ".tar.zst",
+            Format::Repo => "",
+        }
+    }
+}
 
 /// Encapsulates information that is used throughout the program.
 /// This includes useful stats and the source and destination paths.
@@ -10,6 +52,8 @@ pub struct GlobalVars {
     // Path info
     /// The path to the source
     pub source: PathBuf,
+    /// The device of the source, if -x, --one-file-system keeps walk on it
+    pub source_dev: Option<u64>,
     /// The path to the destination
     pub destination: PathBuf,
 
@@ -34,6 +78,68 @@ pub struct GlobalVars {
 
     /// Flag that forces a log to be written
     pub force_log: bool,
+
+    /// Maximum bytes per second used by all threads combined, 0 is unlimited
+    pub bwlimit: u64,
+
+    /// Maximum I/O operations per second used by all threads combined, 0 is
+    /// unlimited
+    pub max_iops: u64,
+
+    /// File that is polled for new throttle limits during the backup
+    pub throttle_file: Option<PathBuf>,
+
+    /// How the backup is written to the destination
+    pub format: Format,
+
+    /// Flag that determines if the archive is streamed to stdout
+    pub stdout: bool,
+
+    /// Compression applied to each file in a plain backup
+    pub compress: Option<Compression>,
+
+    /// Files at least this large only have their changes sent, None to
+    /// always copy whole files
+    pub delta: Option<u64>,
+
+    /// Flag that determines if long runs of zeros become holes
+    pub sparse: bool,
+
+    /// Flag that determines if hard links are recreated
+    pub hard_links: bool,
+
+    /// Flag that determines if a manifest is kept without -R
+    pub manifest: bool,
+
+    /// What is done with FIFOs, sockets and device nodes
+    pub specials: Policy,
+
+    /// Flag that determines if each file is encrypted
+    pub encrypt: bool,
+
+    /// File holding the passphrase for unattended runs
+    pub keyfile: Option<PathBuf>,
+
+    /// Public keys each file is encrypted to
+    pub recipients: Vec<Recipient>,
+
+    /// Flag that determines if file and directory names are encrypted
+    pub encrypt_names: bool,
+
+    /// The url of a destination on another machine, see remote.rs
+    pub remote: Option<Url>,
+
+    /// Private key used to log in to sftp and ssh destinations
+    pub ssh_key: Option<PathBuf>,
+
+    /// Starts backr serve for ssh destinations instead of ssh
+    pub remote_command: Option<String>,
+
+    /// Trusted besides the system roots for backr destinations
+    pub server_ca: Option<PathBuf>,
+
+    /// Where the passphrase or password is read from besides the terminal
+    pub sources: Sources,
 }
 
 /// # Methods
@@ -43,6 +149,11 @@ impl GlobalVars {
         &self.source
     }
 
+    /// Returns the device of the source if walk should not leave it
+    pub fn source_dev(&self) -> Option<u64> {
+        self.source_dev
+    }
+
     /// Returns the destination path
     pub fn dest(&self) -> &PathBuf {
         &self.destination
@@ -84,10 +195,122 @@ impl GlobalVars {
         self.update
     }
 
-    /// Sets the output_file
+    /// Returns the bandwidth limit in bytes per second
+    pub fn bwlimit(&self) -> u64 {
+        self.bwlimit
+    }
+
+    /// Returns the limit of I/O operations per second
+    pub fn max_iops(&self) -> u64 {
+        self.max_iops
+    }
+
+    /// Returns the path of the throttle control file if there is one
+    pub fn throttle_file(&self) -> Option<&PathBuf> {
+        self.throttle_file.as_ref()
+    }
+
+    /// Returns the format the backup is written in
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Returns a bool determining if the archive is written to stdout
+    pub fn stdout(&self) -> bool {
+        self.stdout
+    }
+
+    /// Returns the per file compression settings
+    pub fn compress(&self) -> Option<Compression> {
+        self.compress
+    }
+
+    /// Returns the size from which files are sent as a delta, if at all
+    pub fn delta(&self) -> Option<u64> {
+        self.delta
+    }
+
+    /// Returns true if long runs of zeros are written as holes
+    pub fn sparse(&self) -> bool {
+        self.sparse
+    }
+
+    /// Returns true if hard links within the source are recreated
+    pub fn hard_links(&self) -> bool {
+        self.hard_links
+    }
+
+    /// Returns true if the destination gets a manifest either way
+    pub fn manifest(&self) -> bool {
+        self.manifest
+    }
+
+    /// Returns what is done with FIFOs, sockets and device nodes
+    pub fn specials(&self) -> Policy {
+        self.specials
+    }
+
+    /// Returns a bool determining if each file is encrypted
+    pub fn encrypt(&self) -> bool {
+        self.encrypt
+    }
+
+    /// Returns the path of the file holding the passphrase if there is one
+    pub fn keyfile(&self) -> Option<&PathBuf> {
+        self.keyfile.as_ref()
+    }
+
+    /// Returns the public keys each file is encrypted to
+    pub fn recipients(&self) -> &[Recipient] {
+        &self.recipients
+    }
+
+    /// Returns where the passphrase or password is read from
+    pub fn sources(&self) -> Sources {
+        self.sources
+    }
+
+    /// Returns a bool determining if file and directory names are encrypted
+    pub fn encrypt_names(&self) -> bool {
+        self.encrypt_names
+    }
+
+    /// Returns the url of a remote destination
+    pub fn remote(&self) -> Option<&Url> {
+        self.remote.as_ref()
+    }
+
+    /// Returns the private key used to log in to sftp and ssh destinations
+    pub fn ssh_key(&self) -> Option<&PathBuf> {
+        self.ssh_key.as_ref()
+    }
+
+    /// Returns the command that starts backr serve, if not ssh
+    pub fn remote_command(&self) -> Option<&str> {
+        self.remote_command.as_deref()
+    }
+
+    /// Returns the extra certificate trusted for backr destinations
+    pub fn server_ca(&self) -> Option<&PathBuf> {
+        self.server_ca.as_ref()
+    }
+
+    /// Sets the output_file. When the archive is streamed to stdout the
+    /// default log is written to stderr instead, since there is no
+    /// destination directory to hold it.
     pub fn set_of(&mut self, log: PathBuf) {
-        if log == PathBuf::from("") {
-            let mut path = self.destination.clone();
+        if log.as_os_str().is_empty() && self.stdout {
+            self.log = PathBuf::from("-");
+        } else if log.as_os_str().is_empty() && self.remote.is_some() {
+            // the log of a remote backup stays on this machine
+            self.log = PathBuf::from("backr_log.txt");
+        } else if log.as_os_str().is_empty() {
+            // archives do not create the destination directory, so their log
+            // sits next to the archive
+            let mut path = match self.format {
+                Format::Plain => self.destination.clone(),
+                _ => self.destination.parent().unwrap().to_path_buf(),
+            };
             path.push("backr_log.txt");
             self.log = path;
         } else {
@@ -103,10 +326,24 @@ impl GlobalVars {
     pub fn from(cli: &clap::ArgMatches) -> GlobalVars {
         // set the source path
         let source = PathBuf::from(cli.value_of("source").unwrap_or_default());
+        let source_dev = match cli.is_present("one_file_system") {
+            true => fs::metadata(&source).ok().and_then(|meta| storage::device(&meta)),
+            false => None,
+        };
+
+        // remote destinations are urls, the path is the part after the host
+        let remote = match cli.value_of("destination") {
+            Some(dest) if remote::is_remote(dest) => match Url::parse(dest) {
+                Ok(url) => Some(url),
+                Err(error) => clap::Error::with_description(&error, clap::ErrorKind::InvalidValue).exit(),
+            },
+            _ => None,
+        };
 
         // generate the dest path
-        let destination: PathBuf = match cli.value_of("destination") {
-            Some(path) => {
+        let destination: PathBuf = match (cli.value_of("destination"), &remote) {
+            (_, Some(url)) => url.path.join(source.file_name().unwrap()),
+            (Some(path), None) => {
                 let mut path = PathBuf::from(path);
                 path.push(source.file_name().unwrap());
                 path
@@ -115,7 +352,7 @@ impl GlobalVars {
         };
         // add the root source file/folder name to the dest
 
-        let log = match cli.value_of("log") {
+        let log = match cli.value_of("log_file") {
             Some(path) => PathBuf::from(path),
             _ => PathBuf::new(),
         };
@@ -131,115 +368,462 @@ impl GlobalVars {
             .parse::<i32>()
             .unwrap();
 
+        let bwlimit = match cli.value_of("bwlimit") {
+            Some(rate) => parse_rate(rate).unwrap(),
+            None => 0,
+        };
+
+        let max_iops = match cli.value_of("max_iops") {
+            Some(rate) => parse_rate(rate).unwrap(),
+            None => 0,
+        };
+
+        let delta = match (cli.is_present("whole_file"), cli.value_of("delta_threshold")) {
+            (true, _) => None,
+            (false, Some(size)) => Some(parse_rate(size).unwrap()),
+            (false, None) => Some(delta::DEFAULT_THRESHOLD),
+        };
+
+        let format = match cli.value_of("format") {
+            Some("tar") => Format::Tar,
+            Some("tar.zst") => Format::TarZst,
+            Some("repo") => Format::Repo,
+            _ => Format::Plain,
+        };
+
         //let update: bool = cli.value_of("update").unwrap().parse::<bool>().unwrap();
 
+        // clap only knows that --format was passed, not its value
+        if format != Format::Plain && cli.is_present("update") {
+            clap::Error::with_description(
+                "--update can only be used with --format plain",
+                clap::ErrorKind::ArgumentConflict,
+            ).exit();
+        }
+        if format != Format::Plain
+            && ["encrypt", "recipient", "recipients_file"]
+                .iter()
+                .any(|arg| cli.is_present(arg))
+        {
+            clap::Error::with_description(
+                "--encrypt and --recipient can only be used with --format plain",
+                clap::ErrorKind::ArgumentConflict,
+            ).exit();
+        }
+
+        // a destination of - streams the archive to stdout
+        let stdout = cli.value_of("destination") == Some("-");
+        if stdout && (format == Format::Plain || format == Format::Repo) {
+            clap::Error::with_description(
+                "A destination of - requires --format tar or tar.zst",
+                clap::ErrorKind::ArgumentConflict,
+            ).exit();
+        }
+
+        // files are uploaded as they are
+        if remote.is_some()
+            && (format != Format::Plain
+                || ["compress", "encrypt", "recipient", "recipients_file"]
+                    .iter()
+                    .any(|arg| cli.is_present(arg)))
+        {
+            clap::Error::with_description(
+                "Remote destinations only support plain backups without --compress, \
+                 --encrypt or --recipient",
+                clap::ErrorKind::ArgumentConflict,
+            ).exit();
+        }
+
+        // archives are compressed as a whole, repositories per chunk
+        if cli.is_present("compress") && (format == Format::Tar || format == Format::TarZst) {
+            clap::Error::with_description(
+                "--compress can only be used with --format plain or repo",
+                clap::ErrorKind::ArgumentConflict,
+            ).exit();
+        }
+
+        // collect the public keys from the cli and the recipients files
+        let mut recipients = vec![];
+        for recipient in cli.values_of("recipient").into_iter().flatten() {
+            recipients.push(pubkey::parse_recipient(recipient).unwrap());
+        }
+        for path in cli.values_of("recipients_file").into_iter().flatten() {
+            match pubkey::read_recipients(path.as_ref()) {
+                Ok(found) => recipients.extend(found),
+                Err(error) => {
+                    clap::Error::with_description(&error, clap::ErrorKind::InvalidValue).exit()
+                }
+            }
+        }
+
         // create the new struct that will hold data
-        GlobalVars {
+        let mut gvars = GlobalVars {
             source,
+            source_dev,
             destination,
-            log,
+            log: PathBuf::new(),
             regex: Regex::new(regex).unwrap(),
             threads,
             update: cli.is_present("update"),
-            bar: cli.is_present("progress"),
+            // the progress bar is drawn on stdout
+            bar: cli.is_present("progress") && !stdout,
             quite: cli.is_present("quite"),
             force_log: cli.is_present("force_log"),
-        }
+            bwlimit,
+            max_iops,
+            throttle_file: cli.value_of("throttle_file").map(PathBuf::from),
+            format,
+            stdout,
+            compress: cli
+                .value_of("compress")
+                .map(|value| Compression::parse(value).unwrap()),
+            delta,
+            sparse: cli.is_present("sparse"),
+            hard_links: cli.is_present("hard_links"),
+            manifest: cli.is_present("manifest"),
+            specials: Policy::parse(cli.value_of("specials").unwrap()).unwrap(),
+            encrypt: cli.is_present("encrypt"),
+            keyfile: cli.value_of("keyfile").map(PathBuf::from),
+            recipients,
+            encrypt_names: cli.is_present("encrypt_names"),
+            remote,
+            ssh_key: cli.value_of("ssh_key").map(PathBuf::from),
+            remote_command: cli.value_of("remote_command").map(String::from),
+            server_ca: cli.value_of("server_ca").map(PathBuf::from),
+            sources: Sources::from_cli(cli),
+        };
+        gvars.set_of(log);
+        gvars
     }
 
-    /// Creates a struct that manages defaults and cli
-    pub fn build() -> GlobalVars {
-        GlobalVars::from(
-            &App::new("Backr")
-                .version("0.5.0")
-                .author("martinak1 <https://github.com/martinak1>")
-                .about("Backs up user data.")
-                .arg(
-                    Arg::with_name("source")
-                        .short("s")
-                        .long("source")
-                        .value_name("SOURCE_PATH")
-                        .help("The path to the User directory you want to backup.")
-                        .takes_value(true)
-                        .default_value("./"),
-                ).arg(
-                    Arg::with_name("destination")
-                        .short("d")
-                        .long("destination")
-                        .value_name("DESTINATION_PATH")
-                        .help("The path to the location you want the data saved too.")
-                        .takes_value(true)
-                        .required(true),
-                ).arg(
-                    Arg::with_name("update")
-                        .short("u")
-                        .long("update")
-                        .help(
-                            "Tells backer to update the files instead of\
-                             overwriting them.",
-                        ).long_help(
-                            "If this flag is set, backr will check the\
-                             metadata of the source file and the already existing\
-                             destination file, and will keep the newest one.",
-                        ),
-                ).arg(
-                    Arg::with_name("log_file")
-                        .short("l")
-                        .long("log")
-                        .value_name("FILE_PATH")
-                        .help(
-                            "Specifies the log location that errors are\
-                             written to",
-                        ).takes_value(true)
-                        .default_value(""),
-                ).arg(
-                    Arg::with_name("regex")
-                        .short("r")
-                        .long("regex")
-                        .value_name("REGEX")
-                        .help(
-                            "Passes a regex to the program to \
-                             only backup matching files and directories.",
-                        ).takes_value(true)
-                        .default_value("Documents|Downloads|Movies|Music|Pictures|Videos"),
-                ).arg(
-                    Arg::with_name("threads")
-                        .short("t")
-                        .long("threads")
-                        .value_name("NUM")
-                        .help("Number of threads that will be used to backup files")
-                        .default_value("2"),
-                ).arg(
-                    Arg::with_name("all")
-                        .short("a")
-                        .long("backup-all")
-                        .help(
-                            "Backup all files found, overriding the regex. Because\
-                             of this, it conflicts with -r, --regex.",
-                        ).conflicts_with("regex"),
-                ).arg(
-                    Arg::with_name("progress")
-                        .short("p")
-                        .long("progress")
-                        .help("Displays a progress bar during the backup."),
-                ).arg(
-                    Arg::with_name("quite")
-                        .short("q")
-                        .long("quite")
-                        .conflicts_with("progress")
-                        .help(
-                            "Stop backr from printing to stdout. As such it\
-                             conflicts with -p, --progress",
-                        ),
-                ).arg(
-                    Arg::with_name("force_log")
-                        .short("L")
-                        .long("force-log")
-                        .help(
-                            "Forces a log to be written, even if there are no\
-                             errors to report.",
-                        ),
-                ).get_matches(),
-        )
+    /// Creates the clap App that manages defaults, the cli and the
+    /// subcommands
+    pub fn app() -> App<'static, 'static> {
+        App::new("Backr")
+            .version("0.5.0")
+            .author("martinak1 <https://github.com/martinak1>")
+            .about("Backs up user data.")
+            .arg(
+                Arg::with_name("source")
+                    .short("s")
+                    .long("source")
+                    .value_name("SOURCE_PATH")
+                    .help("The path to the User directory you want to backup.")
+                    .takes_value(true)
+                    .default_value("./"),
+            ).arg(
+                Arg::with_name("destination")
+                    .short("d")
+                    .long("destination")
+                    .value_name("DESTINATION_PATH")
+                    .help(
+                        "The path to the location you want the data saved too.\
+                         Use - to stream a tar archive to stdout, an\
+                         ssh://, sftp://, ftp://, ftps://, webdav:// or\
+                         webdavs://user@host/path url to upload to another\
+                         machine, an s3://bucket/prefix url to upload to an\
+                         object store, or a backr://client@host/name url to\
+                         upload to backr server.",
+                    )
+                    .takes_value(true)
+                    .required(true),
+            ).arg(
+                Arg::with_name("ssh_key")
+                    .long("ssh-key")
+                    .value_name("FILE_PATH")
+                    .help("The private key used to log in to sftp and ssh destinations")
+                    .long_help(
+                        "The private key used to log in to sftp and ssh\
+                         destinations. The ssh agent is tried first, and\
+                         without this option the default keys in ~/.ssh. A\
+                         password is asked for when no key works.",
+                    ).takes_value(true),
+            ).arg(
+                Arg::with_name("remote_command")
+                    .long("remote-command")
+                    .value_name("COMMAND")
+                    .help("Starts backr serve for ssh destinations instead of ssh")
+                    .long_help(
+                        "The command that starts backr serve for ssh://\
+                         destinations, ie. \"ssh -J jump nas backr serve\".\
+                         It is run by sh with -d and the path of the url\
+                         appended. [default: ssh [-p PORT] [USER@]HOST backr\
+                         serve]",
+                    ).takes_value(true),
+            ).arg(
+                Arg::with_name("server_ca")
+                    .long("server-ca")
+                    .value_name("FILE_PATH")
+                    .help("Trusts this PEM certificate for backr destinations")
+                    .long_help(
+                        "Trusts this PEM certificate for backr:// destinations\
+                         besides the system roots, ie. the self-signed\
+                         certificate of a backr server.",
+                    ).takes_value(true),
+            ).arg(
+                Arg::with_name("one_file_system")
+                    .short("x")
+                    .long("one-file-system")
+                    .help("Does not cross into other filesystems")
+                    .long_help(
+                        "Directories on another filesystem than the source,\
+                         ie. /proc, /sys or mounted network shares, are\
+                         skipped. Each skipped mount point is listed.",
+                    ),
+            ).arg(
+                Arg::with_name("update")
+                    .short("u")
+                    .long("update")
+                    .help(
+                        "Tells backer to update the files instead of\
+                         overwriting them.",
+                    ).long_help(
+                        "If this flag is set, backr will check the\
+                         metadata of the source file and the already existing\
+                         destination file, and will keep the newest one.",
+                    ),
+            ).arg(
+                Arg::with_name("log_file")
+                    .short("l")
+                    .long("log")
+                    .value_name("FILE_PATH")
+                    .help(
+                        "Specifies the log location that errors are\
+                         written to",
+                    ).takes_value(true)
+                    .default_value(""),
+            ).arg(
+                Arg::with_name("regex")
+                    .short("r")
+                    .long("regex")
+                    .value_name("REGEX")
+                    .help(
+                        "Passes a regex to the program to \
+                         only backup matching files and directories.",
+                    ).takes_value(true)
+                    .default_value("Documents|Downloads|Movies|Music|Pictures|Videos"),
+            ).arg(
+                Arg::with_name("threads")
+                    .short("t")
+                    .long("threads")
+                    .value_name("NUM")
+                    .help("Number of threads that will be used to backup files")
+                    .default_value("2"),
+            ).arg(
+                Arg::with_name("all")
+                    .short("a")
+                    .long("backup-all")
+                    .help(
+                        "Backup all files found, overriding the regex. Because\
+                         of this, it conflicts with -r, --regex.",
+                    ).conflicts_with("regex"),
+            ).arg(
+                Arg::with_name("progress")
+                    .short("p")
+                    .long("progress")
+                    .help("Displays a progress bar during the backup."),
+            ).arg(
+                Arg::with_name("quite")
+                    .short("q")
+                    .long("quite")
+                    .conflicts_with("progress")
+                    .help(
+                        "Stop backr from printing to stdout. As such it\
+                         conflicts with -p, --progress",
+                    ),
+            ).arg(
+                Arg::with_name("force_log")
+                    .short("L")
+                    .long("force-log")
+                    .help(
+                        "Forces a log to be written, even if there are no\
+                         errors to report.",
+                    ),
+            ).arg(
+                Arg::with_name("bwlimit")
+                    .long("bwlimit")
+                    .value_name("RATE")
+                    .help("Limits the bytes per second used by all threads, ie. 512K, 20M, 1G")
+                    .takes_value(true)
+                    .validator(|rate| parse_rate(&rate).map(|_| ())),
+            ).arg(
+                Arg::with_name("delta_threshold")
+                    .long("delta-threshold")
+                    .value_name("SIZE")
+                    .help("Only sends the changes of files at least this large [default: 64M]")
+                    .long_help(
+                        "Files at least this large that are already in the\
+                         destination only have their changed blocks written,\
+                         smaller ones are copied whole. Local destinations\
+                         compare the files block by block, backr serve and\
+                         backr server use a rolling checksum like rsync. Other\
+                         destinations and compressed or encrypted files are\
+                         always copied whole. [default: 64M]",
+                    ).takes_value(true)
+                    .validator(|size| parse_rate(&size).map(|_| ())),
+            ).arg(
+                Arg::with_name("hard_links")
+                    .short("H")
+                    .long("hard-links")
+                    .help("Recreates the hard links within the source")
+                    .long_help(
+                        "Files with several names in the source get the same\
+                         names in the destination, and are copied once. Needs\
+                         a local destination, elsewhere and in archives and\
+                         repositories every name is a file of its own.",
+                    ),
+            ).arg(
+                Arg::with_name("specials")
+                    .long("specials")
+                    .value_name("POLICY")
+                    .help("What is done with FIFOs, sockets and device nodes")
+                    .long_help(
+                        "What is done with FIFOs, sockets and device nodes.\
+                         copy makes the FIFOs and device nodes again on a local\
+                         destination, device nodes only when run as root. skip\
+                         leaves them out and warn adds them to the log as well.\
+                         Sockets and everything that is left out are always\
+                         listed.",
+                    ).possible_values(&["copy", "skip", "warn"])
+                    .default_value("skip"),
+            ).arg(
+                Arg::with_name("sparse")
+                    .long("sparse")
+                    .help("Also turns long runs of zeros into holes")
+                    .long_help(
+                        "Holes in sparse files, ie. VM images, are kept on\
+                         local destinations either way. With this flag\
+                         aligned runs of 32K zeros become holes as well.",
+                    ),
+            ).arg(
+                Arg::with_name("whole_file")
+                    .long("whole-file")
+                    .help("Always copies whole files")
+                    .conflicts_with("delta_threshold"),
+            ).arg(
+                Arg::with_name("max_iops")
+                    .long("max-iops")
+                    .value_name("NUM")
+                    .help("Limits the I/O operations per second used by all threads")
+                    .takes_value(true)
+                    .validator(|rate| parse_rate(&rate).map(|_| ())),
+            ).arg(
+                Arg::with_name("throttle_file")
+                    .long("throttle-file")
+                    .value_name("FILE_PATH")
+                    .help("A file that is checked every second for new throttle limits")
+                    .long_help(
+                        "A file that is checked every second for new throttle\
+                         limits, so they can be changed during a backup. Each\
+                         line is a `key = value` pair, ie. `bwlimit = 20M` or\
+                         `max-iops = 100`.",
+                    ).takes_value(true),
+            ).arg(
+                Arg::with_name("format")
+                    .short("f")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .help("How the backup is written to the destination")
+                    .long_help(
+                        "How the backup is written to the destination. plain\
+                         replicates the source tree, tar and tar.zst write a\
+                         single archive named after the source directory.\
+                         repo turns the destination into a deduplicating\
+                         repository that stores every chunk of file content\
+                         once and records each backup as a snapshot.\
+                         [default: plain]",
+                    ).possible_values(&["plain", "tar", "tar.zst", "repo"]),
+            ).arg(
+                Arg::with_name("compress")
+                    .short("z")
+                    .long("compress")
+                    .value_name("ALGORITHM[:LEVEL]")
+                    .help("Compresses each file, ie. zstd or zstd:19")
+                    .long_help(
+                        "Compresses each file that is worth compressing and\
+                         stores it with a .backr.zst suffix. Files that are\
+                         already compressed are detected by their extension\
+                         or their entropy and copied as is. Use the restore\
+                         subcommand to decompress them again. With --format\
+                         repo each chunk is compressed instead.",
+                    ).takes_value(true)
+                    .validator(|value| Compression::parse(&value).map(|_| ())),
+            ).arg(
+                Arg::with_name("encrypt")
+                    .short("e")
+                    .long("encrypt")
+                    .help("Encrypts each file with a passphrase")
+                    .long_help(
+                        "Encrypts each file with XChaCha20-Poly1305 and a key\
+                         derived from a passphrase with Argon2id. Encrypted\
+                         files are stored with a .backr.enc suffix. The\
+                         passphrase is read from --keyfile, --password-fd, the\
+                         BACKR_PASSPHRASE environment variable, the keyring\
+                         with --keyring or the terminal.",
+                    ),
+            ).arg(
+                Arg::with_name("keyfile")
+                    .long("keyfile")
+                    .value_name("FILE_PATH")
+                    .help("Reads the encryption passphrase from a file")
+                    .takes_value(true)
+                    .requires("encrypt"),
+            ).args(&Sources::args())
+            .arg(
+                Arg::with_name("encrypt_names")
+                    .long("encrypt-names")
+                    .help("Also encrypts file and directory names, requires -e")
+                    .long_help(
+                        "Also encrypts file and directory names. Names are\
+                         encrypted deterministically with a key derived from\
+                         the passphrase, so --update keeps working, and can\
+                         only be read again with the passphrase. Requires -e,\
+                         --encrypt.",
+                    ).requires("encrypt"),
+            ).arg(
+                Arg::with_name("recipient")
+                    .short("R")
+                    .long("recipient")
+                    .value_name("PUBLIC_KEY")
+                    .help("Encrypts each file to an age public key, can be repeated")
+                    .long_help(
+                        "Encrypts each file to an age public key, ie. age1...\
+                         Can be repeated to encrypt to several keys. Only the\
+                         public keys are needed for the backup, restoring\
+                         needs one of the private keys. Encrypted files are\
+                         stored with a .backr.age suffix and the keys are\
+                         recorded in the manifest.",
+                    ).takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(|key| pubkey::parse_recipient(&key).map(|_| ()))
+                    .conflicts_with("encrypt"),
+            ).arg(
+                Arg::with_name("manifest")
+                    .long("manifest")
+                    .help("Keeps a manifest of the backup for the check subcommand")
+                    .long_help(
+                        "Keeps DESTINATION/backr.manifest, which records every\
+                         file and the check subcommand verifies the backup\
+                         against. Implied by -R, --recipient, and kept up to\
+                         date once a destination has one.",
+                    ),
+            ).arg(
+                Arg::with_name("recipients_file")
+                    .long("recipients-file")
+                    .value_name("FILE_PATH")
+                    .help("Reads age public keys from a file, one per line")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .conflicts_with("encrypt"),
+            ).setting(AppSettings::SubcommandsNegateReqs)
+            .subcommand(check::subcommand())
+            .subcommand(gc::subcommand())
+            .subcommand(prune::subcommand())
+            .subcommand(restore::subcommand())
+            .subcommand(serve::subcommand())
+            .subcommand(server::subcommand())
+            .subcommand(snapshots::subcommand())
     }
 }
\ No newline at end of file
@@ -0,0 +1,134 @@
+//! Looks up secrets in the system keyring through the Secret Service D-Bus
+//! API, which GNOME Keyring and KeePassXC provide among others. backr only
+//! reads the keyring, secrets are stored with the `application` attribute
+//! set to backr, ie. with
+//! `secret-tool store --label=backr application backr kind passphrase`.
+
+use std::collections::HashMap;
+use std::io;
+
+use secret_service::blocking::SecretService;
+use secret_service::EncryptionType;
+
+fn keyring_error(error: secret_service::Error) -> io::Error {
+    io::Error::other(format!("Failed to read the keyring, {}", error))
+}
+
+/// The part of a keyring `lookup` needs, so it can run against a stand-in
+pub trait Search {
+    /// Returns the secret of the first item with all of the attributes in
+    /// `query`, asking the keyring to unlock it if needed
+    fn first_secret(&self, query: HashMap<&str, &str>) -> Result<Option<Vec<u8>>, secret_service::Error>;
+}
+
+impl<'a> Search for SecretService<'a> {
+    fn first_secret(&self, query: HashMap<&str, &str>) -> Result<Option<Vec<u8>>, secret_service::Error> {
+        let found = self.search_items(query)?;
+        match found.unlocked.into_iter().chain(found.locked).next() {
+            Some(item) => {
+                item.ensure_unlocked()?;
+                item.get_secret().map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Returns the secret of the first item with all of the attributes, asking
+/// the keyring to unlock it if needed. --keyring asked for it, so a keyring
+/// that can not be read or has no such item is an error.
+pub fn lookup(attributes: &[(&str, &str)]) -> io::Result<String> {
+    // the secret is only sent over an encrypted session
+    let service = SecretService::connect(EncryptionType::Dh).map_err(keyring_error)?;
+    lookup_in(&service, attributes)
+}
+
+/// Looks the secret up like `lookup`, in `keyring`
+pub fn lookup_in(keyring: &dyn Search, attributes: &[(&str, &str)]) -> io::Result<String> {
+    let mut query: HashMap<&str, &str> = attributes.iter().cloned().collect();
+    query.insert("application", "backr");
+
+    let secret = match keyring.first_secret(query).map_err(keyring_error)? {
+        Some(secret) => secret,
+        None => {
+            let attributes: Vec<String> = attributes
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect();
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("The keyring has no item with application=backr {}", attributes.join(" ")),
+            ));
+        }
+    };
+
+    String::from_utf8(secret)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "The secret in the keyring is not valid utf-8"))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// The attributes of an item and its secret
+    pub type Item = (Vec<(&'static str, &'static str)>, Vec<u8>);
+
+    /// A keyring holding items in memory
+    pub struct Keyring {
+        pub items: Vec<Item>,
+        /// Set when the user dismisses the unlock prompt
+        pub dismissed: bool,
+    }
+
+    impl Search for Keyring {
+        fn first_secret(&self, query: HashMap<&str, &str>) -> Result<Option<Vec<u8>>, secret_service::Error> {
+            let found = self.items.iter().find(|(attributes, _)| {
+                query
+                    .iter()
+                    .all(|(key, value)| attributes.iter().any(|attribute| attribute == &(*key, *value)))
+            });
+            match (found, self.dismissed) {
+                (Some(_), true) => Err(secret_service::Error::Prompt),
+                (found, _) => Ok(found.map(|(_, secret)| secret.clone())),
+            }
+        }
+    }
+
+    fn keyring() -> Keyring {
+        Keyring {
+            items: vec![
+                (vec![("application", "other"), ("kind", "passphrase")], b"not ours".to_vec()),
+                (vec![("application", "backr"), ("kind", "passphrase")], b"passphrase".to_vec()),
+                (
+                    vec![("application", "backr"), ("kind", "password"), ("host", "example.com")],
+                    vec![0xff, 0xfe],
+                ),
+            ],
+            dismissed: false,
+        }
+    }
+
+    #[test]
+    fn finds_the_item_of_backr() {
+        assert_eq!(lookup_in(&keyring(), &[("kind", "passphrase")]).unwrap(), "passphrase");
+    }
+
+    #[test]
+    fn missing_item_names_the_attributes() {
+        let error = lookup_in(&keyring(), &[("kind", "password"), ("host", "other.com")]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().contains("kind=password host=other.com"));
+    }
+
+    #[test]
+    fn bad_secrets_and_keyrings_fail() {
+        let error = lookup_in(&keyring(), &[("kind", "password"), ("host", "example.com")]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let dismissed = Keyring {
+            dismissed: true,
+            ..keyring()
+        };
+        assert!(lookup_in(&dismissed, &[("kind", "passphrase")]).is_err());
+    }
+}
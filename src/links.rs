@@ -0,0 +1,201 @@
+//! Hard links within the source tree, kept with -H, --hard-links.
+//!
+//! `walk` remembers the device and inode of every file with more than one
+//! name. The first name is copied like any other file, the later ones are
+//! linked to its copy once the backup threads are done, so the bytes are
+//! only copied once. A storage that can not make hard links gets a copy of
+//! every name, as do archives and repositories.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use copy::{self, Options};
+use storage::{Open, Storage};
+
+/// A later name of a file
+#[derive(Debug, Clone)]
+pub struct Link {
+    pub src: PathBuf,
+    pub dest: PathBuf,
+    /// Where the first name is backed up to
+    pub target: PathBuf,
+}
+
+/// The files seen by `walk` that have more than one name
+#[derive(Debug, Default)]
+pub struct Links {
+    /// The destination of the first name of every (dev, inode)
+    seen: HashMap<(u64, u64), PathBuf>,
+    pub later: Vec<Link>,
+}
+
+impl Links {
+    /// Returns true if `src` is a later name of a file that was seen
+    /// already, and remembers it as a link. Otherwise `dest` is remembered
+    /// as the copy of the file.
+    #[cfg(unix)]
+    pub fn add(&mut self, meta: &fs::Metadata, src: &Path, dest: &Path) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        if meta.nlink() < 2 {
+            return false;
+        }
+        match self.seen.get(&(meta.dev(), meta.ino())) {
+            Some(target) => {
+                self.later.push(Link {
+                    src: src.to_path_buf(),
+                    dest: dest.to_path_buf(),
+                    target: target.clone(),
+                });
+                true
+            }
+            None => {
+                self.seen.insert((meta.dev(), meta.ino()), dest.to_path_buf());
+                false
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn add(&mut self, _meta: &fs::Metadata, _src: &Path, _dest: &Path) -> bool {
+        false
+    }
+}
+
+/// Links the later names to the copies of their first names, or copies them
+/// if that is not possible. Returns the errors it ran into.
+pub fn create(open: &Open, links: Vec<Link>, opts: &Options, quite: bool) -> Vec<String> {
+    if links.is_empty() {
+        return vec![];
+    }
+    if quite {
+        say!("** Linking {} hard links", links.len());
+    }
+
+    let mut storage = match open() {
+        Ok(storage) => storage,
+        Err(error) => return vec![format!("Error: Failed to open the destination \n {}", error)],
+    };
+
+    let mut errors = vec![];
+    for link in links {
+        let linked = match link.dest.parent() {
+            Some(parent) => storage.mkdir_all(parent).and_then(|_| link_file(&mut *storage, &link, opts)),
+            None => Ok(false),
+        };
+        let result = match linked {
+            Ok(true) => Ok(()),
+            Ok(false) => copy::backup_file(&mut *storage, &link.src, &link.dest, opts),
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            let error = format!("Error: Failed to link {:?} -> {:?} \n {}", link.src, link.dest, error);
+            if quite {
+                say!("{}", &error);
+            }
+            errors.push(error);
+        }
+    }
+    errors
+}
+
+/// Links one name, under the same suffix the first name was stored with.
+/// Returns false if the first name is missing or the storage can not link.
+fn link_file(storage: &mut dyn Storage, link: &Link, opts: &Options) -> io::Result<bool> {
+    let (targets, paths) = (copy::stored_paths(&link.target), copy::stored_paths(&link.dest));
+    let index = match (0..targets.len()).find(|&index| matches!(storage.stat(&targets[index]), Ok(Some(_)))) {
+        Some(index) => index,
+        None => return Ok(false),
+    };
+
+    if !storage.hard_link(&targets[index], &paths[index])? {
+        return Ok(false);
+    }
+    for (_, stale) in paths.iter().enumerate().filter(|(other, _)| *other != index) {
+        storage.remove(stale)?;
+    }
+
+    copy::record(opts, &link.src, &link.dest, &paths[index]);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::Arc;
+
+    use tempfile::TempDir;
+
+    use compress::Compression;
+    use encrypt::Key;
+    use storage::Local;
+
+    /// Makes `a` and its second name `b` in `src`, stores `a` unless told
+    /// not to, and links `b`
+    fn link_both(src: &Path, dest: &Path, opts: &Options, store_first: bool) -> Vec<String> {
+        fs::write(src.join("a"), "two names, one file ".repeat(100)).unwrap();
+        fs::hard_link(src.join("a"), src.join("b")).unwrap();
+
+        let mut links = Links::default();
+        for name in ["a", "b"] {
+            let (src, dest) = (src.join(name), dest.join(name));
+            if !links.add(&fs::metadata(&src).unwrap(), &src, &dest) && store_first {
+                copy::backup_file(&mut Local, &src, &dest, opts).unwrap();
+            }
+        }
+        assert_eq!(links.later.len(), 1);
+
+        let open: Open = Arc::new(|| Ok(Box::new(Local) as Box<dyn Storage>));
+        create(&open, links.later, opts, false)
+    }
+
+    fn inode(path: &Path) -> u64 {
+        fs::metadata(path).unwrap().ino()
+    }
+
+    #[test]
+    fn names_of_one_file_stay_one_file() {
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        assert!(link_both(src.path(), dest.path(), &Options::default(), true).is_empty());
+        assert_eq!(inode(&dest.path().join("a")), inode(&dest.path().join("b")));
+    }
+
+    #[test]
+    fn unstored_first_names_are_copied() {
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        assert!(link_both(src.path(), dest.path(), &Options::default(), false).is_empty());
+        assert!(!dest.path().join("a").exists());
+        assert_eq!(fs::read(dest.path().join("b")).unwrap(), fs::read(src.path().join("a")).unwrap());
+        assert_eq!(fs::metadata(dest.path().join("b")).unwrap().nlink(), 1);
+    }
+
+    #[test]
+    fn links_keep_the_suffix_of_the_first_name() {
+        let compress = Options {
+            compress: Some(Compression::parse("zstd").unwrap()),
+            ..Options::default()
+        };
+        let encrypt = Options {
+            encrypt: Some(Key::derive("secret", [0; 16]).unwrap()),
+            ..Options::default()
+        };
+        for opts in [compress, encrypt] {
+            let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+            // a plain copy of the later name from an older backup goes away
+            fs::write(dest.path().join("b"), b"stale").unwrap();
+            assert!(link_both(src.path(), dest.path(), &opts, true).is_empty());
+
+            let index = match opts.compress {
+                Some(_) => 1,
+                None => 2,
+            };
+            let (first, later) = (copy::stored_paths(&dest.path().join("a")), copy::stored_paths(&dest.path().join("b")));
+            assert_eq!(inode(&first[index]), inode(&later[index]));
+            assert!(!later[0].exists());
+        }
+    }
+}
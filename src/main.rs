@@ -7,15 +7,36 @@
 //!     -a, --backup-all
 //!         Backup all files found, overriding the regex. Because of this, it conflicts with the regex option.
 //!
+//!     -e, --encrypt
+//!         Encrypts each file with a passphrase. The passphrase is read from
+//!         --keyfile, --password-fd, the BACKR_PASSPHRASE environment
+//!         variable, the keyring or the terminal.
+//!
+//!     --encrypt-names
+//!         Also encrypts file and directory names, requires -e
+//!
+//!     -H, --hard-links
+//!         Recreates the hard links within the source, copying the bytes once
+//!
 //!     -h, --help
 //!         Prints help information
 //!
+//!     --keyring
+//!         Looks the passphrase or password up in the system keyring
+//!
 //!     -L, --force-log
 //!         Force a log to be written even if there are no errors to report
 //!
+//!     --manifest
+//!         Keeps a manifest of the backup for the check subcommand
+//!
 //!     -p, --progress
 //!         Displays a progress bar during the backup.
 //!
+//!     --sparse
+//!         Also turns long runs of zeros into holes, the holes of sparse
+//!         files are kept either way
+//!
 //!     -u, --update
 //!         If this flag is set, backr will check the metadata of the source
 //!         file and the already existing destination file, and will keep the
@@ -24,26 +45,115 @@
 //!     -V, --version
 //!         Prints version information
 //!
+//!     --whole-file
+//!         Always copies whole files, see --delta-threshold
+//!
+//!     -x, --one-file-system
+//!         Skips the directories on other filesystems than the source
+//!
 //! OPTIONS:
+//!     --bwlimit <RATE>
+//!         Limits the bytes per second used by all threads, ie. 512K, 20M, 1G
+//!
+//!     --max-iops <NUM>
+//!         Limits the I/O operations per second used by all threads
+//!
+//!     --throttle-file <FILE_PATH>
+//!         A file that is checked every second for new throttle limits, so
+//!         they can be changed during a backup. Each line is a `key = value`
+//!         pair, ie. `bwlimit = 20M` or `max-iops = 100`.
+//!
 //!     -d, --destination <DESTINATION_PATH>
-//!         The path to the location you want the data saved too.
+//!         The path to the location you want the data saved too. Use - to
+//!         stream a tar archive to stdout, an ssh://, sftp://, ftp://,
+//!         ftps://, webdav:// or webdavs:// url to upload to another machine,
+//!         an s3://bucket/prefix url to upload to S3, MinIO or Ceph, or a
+//!         backr://client@host/name url to upload to backr server.
+//!
+//!     --delta-threshold <SIZE>
+//!         Only sends the changes of files at least this large that are
+//!         already in the destination. [default: 64M]
+//!
+//!     -f, --format <FORMAT>
+//!         How the backup is written to the destination. plain replicates the
+//!         source tree, tar and tar.zst write a single archive named after the
+//!         source directory, repo turns the destination into a deduplicating
+//!         repository of snapshots. [default: plain]
+//!
+//!     --keyfile <FILE_PATH>
+//!         Reads the encryption passphrase from a file
 //!
 //!     -l, --log <FILE_PATH>
 //!         Specifies the log location that errors are written to [default: ]
 //!
+//!     --password-fd <FD>
+//!         Reads the passphrase or password from a file descriptor
+//!
+//!     --remote-command <COMMAND>
+//!         Starts backr serve for ssh:// destinations instead of ssh
+//!
+//!     -R, --recipient <PUBLIC_KEY>
+//!         Encrypts each file to an age public key, can be repeated
+//!
+//!     --recipients-file <FILE_PATH>
+//!         Reads age public keys from a file, one per line
+//!
 //!     -r, --regex <REGEX>
 //!         Passes a regex to the program to only backup matching files and
 //!         directories.
 //!         [default:Documents|Downloads|Movies|Music|Pictures|Videos]
 //!
+//!     --server-ca <FILE_PATH>
+//!         Trusts this PEM certificate for backr:// destinations
+//!
+//!     --specials <POLICY>
+//!         What is done with FIFOs, sockets and device nodes, copy, skip or
+//!         warn. [default: skip]
+//!
 //!     -s, --source <SOURCE_PATH>
 //!         The path to the User directory you want to backup. [default: ./]
 //!
+//!     --ssh-key <FILE_PATH>
+//!         The private key used to log in to sftp and ssh destinations
+//!
+//!     -z, --compress <ALGORITHM[:LEVEL]>
+//!         Compresses each file that is worth compressing and stores it with a
+//!         .backr.zst suffix, ie. zstd or zstd:19. With -f repo each chunk is
+//!         compressed instead.
+//!
 //!     -t, --threads <NUM>
 //!         Number of threads that will be used to backup files [default: 2]
 //!
 //!     -L, --force-log
 //!         Writes a log, even if there are no errors to report
+//!
+//! SUBCOMMANDS:
+//!     check
+//!         Verifies a repository or a plain backup and reports missing,
+//!         damaged and orphaned data. --repair repairs what it can,
+//!         --read-data also hashes every file of a plain backup.
+//!
+//!     gc
+//!         Deletes the data of a repository that no snapshot uses anymore.
+//!
+//!     prune
+//!         Removes the snapshots of a repository that a retention policy
+//!         does not keep. --save applies the policy after every backup.
+//!
+//!     restore
+//!         Restores a plain backup or a repository snapshot, decrypting and
+//!         decompressing files as needed.
+//!
+//!     serve
+//!         Receives a backup over stdin and stdout, started by
+//!         backr -d ssh://host/path on the other end.
+//!
+//!     server
+//!         Takes backups from many clients over TLS, each in its own
+//!         namespace with an optional quota.
+//!
+//!     snapshots
+//!         Lists the snapshots in a repository.
 //! ```
 //!
 //!
@@ -55,23 +165,43 @@
 //! Copying the permissions is the cause of your error. Your files will still
 //! be transferred, but the permissions will not.
 
-/* TODO
-    Add secure way to receive username + password from consol
-    Add support for sftp
-*/
-
 // for cli parsing
 extern crate clap;
 
+// for routing messages to stdout or stderr
+#[macro_use]
+pub mod output;
+
 // for interacting with the filesystem
-use std::fs::{self, DirBuilder};
-use std::io::prelude::Write;
-use std::path::PathBuf;
+use std::fs;
+use std::io::{self, prelude::Write};
+use std::path::{Path, PathBuf};
+use std::process;
+#[cfg(unix)]
+extern crate libc;
 
 // for filtering the files to be backed up
 extern crate regex;
 use regex::Regex;
 
+// for the manifest
+#[macro_use]
+extern crate serde;
+extern crate serde_json;
+
+// for encrypting files
+extern crate age;
+extern crate argon2;
+extern crate chacha20poly1305;
+extern crate hmac;
+extern crate sha2;
+#[cfg(unix)]
+extern crate termios;
+
+// for reading secrets from the keyring
+extern crate secret_service;
+pub mod keyring;
+
 // for multi-threading
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -81,65 +211,451 @@ use std::time;
 extern crate progress;
 use progress::Bar;
 
+// for writing archives
+extern crate tar;
+extern crate zstd;
+pub mod archive;
+
+// for the deduplicating repository
+extern crate fastcdc;
+extern crate filetime;
+extern crate hex;
+pub mod repo;
+
 // for handeling cli and global settings
 pub mod globalvars;
 use globalvars::*;
 
+// for uploading to other machines
+extern crate base64;
+extern crate md5;
+extern crate native_tls;
+extern crate ssh2;
+extern crate ureq;
+pub mod remote;
+pub mod storage;
+use storage::{Local, Storage};
+
+// for the scratch directories and stand-in servers of the tests
+#[cfg(test)]
+extern crate futures;
+#[cfg(test)]
+extern crate russh;
+#[cfg(test)]
+extern crate russh_sftp;
+#[cfg(test)]
+extern crate tempfile;
+#[cfg(test)]
+extern crate tokio;
+
+// for copying files, compressing them and limiting bandwidth
+pub mod check;
+pub mod compress;
+pub mod copy;
+pub mod delta;
+pub mod encrypt;
+pub mod gc;
+pub mod links;
+pub mod manifest;
+pub mod names;
+pub mod prompt;
+pub mod prune;
+pub mod pubkey;
+pub mod restore;
+pub mod serve;
+pub mod server;
+pub mod snapshots;
+pub mod sparse;
+pub mod specials;
+pub mod throttle;
+use links::Links;
+use manifest::Manifest;
+use names::Names;
+use specials::{Policy, Special};
+use throttle::Throttle;
+
 fn main() {
-    let gvars = GlobalVars::build();
+    let cli = GlobalVars::app().get_matches();
 
-    match check_permissions(gvars.source(), gvars.dest()) {
-        true => {
-            if gvars.quite() {
-                println!(
-                    "** {:?} is being used as the source directory \
-                     \n** {:?} is being used as the destination directory \
-                     \n** Searching for files to backup...",
-                    gvars.source(),
-                    gvars.dest()
-                );
+    // subcommands have their own options
+    match cli.subcommand() {
+        ("restore", Some(sub)) => {
+            if !restore::run(sub).is_empty() {
+                process::exit(1);
+            }
+            return;
+        }
+        ("check", Some(sub)) => {
+            if !check::run(sub).is_empty() {
+                process::exit(1);
+            }
+            return;
+        }
+        ("gc", Some(sub)) => {
+            if !gc::run(sub).is_empty() {
+                process::exit(1);
+            }
+            return;
+        }
+        ("prune", Some(sub)) => {
+            if !prune::run(sub).is_empty() {
+                process::exit(1);
             }
+            return;
+        }
+        ("serve", Some(sub)) => {
+            if !serve::run(sub).is_empty() {
+                process::exit(1);
+            }
+            return;
+        }
+        ("server", Some(sub)) => {
+            if !server::run(sub).is_empty() {
+                process::exit(1);
+            }
+            return;
+        }
+        ("snapshots", Some(sub)) => {
+            if !snapshots::run(sub).is_empty() {
+                process::exit(1);
+            }
+            return;
+        }
+        _ => (),
+    }
+
+    let gvars = GlobalVars::from(&cli);
 
-            // get the job queue and read errors
-            let (queue, mut errors, ..) = walk(
-                Vec::<(PathBuf, PathBuf)>::new(),
-                Vec::<String>::new(),
-                gvars.source(),
-                gvars.dest(),
-                gvars.regex(),
-                gvars.update(),
+    // stdout carries the archive, so everything else goes to stderr
+    if gvars.stdout() {
+        output::use_stderr();
+    }
+
+    // archives are written next to the destination directory and the
+    // repository is the directory itself, so that is where write access is
+    // needed
+    let target = match gvars.format() {
+        Format::Plain => gvars.dest().clone(),
+        _ => gvars.dest().parent().unwrap().to_path_buf(),
+    };
+
+    // the destination is the filesystem of this machine or another one
+    let mut auth = remote::Auth {
+        key: gvars.ssh_key().cloned(),
+        password: None,
+    };
+    // backr serve on the other end of an ssh:// url, or backr server behind a
+    // backr:// one, takes the whole queue at once and checks its destination
+    // itself
+    let mut session = None;
+    let mut storage: Box<dyn Storage> = match gvars.remote() {
+        Some(url) if url.scheme == "ssh" => {
+            match serve::Session::open(url, gvars.remote_command(), gvars.ssh_key().map(|key| key.as_path())) {
+                Ok(opened) => session = Some(opened),
+                Err(error) => {
+                    say!("Error: Failed to start backr serve on {} \n{}", url, error);
+                    return;
+                }
+            }
+            Box::new(Local)
+        }
+        Some(url) if url.scheme == "backr" => {
+            let ca = gvars.server_ca().map(|ca| ca.as_path());
+            match remote::login_with(url, &mut auth, gvars.sources(), |url, auth| {
+                serve::Session::connect(url, auth, ca)
+            }) {
+                Ok(opened) => session = Some(opened),
+                Err(error) => {
+                    say!("Error: Failed to log in to {} \n{}", url, error);
+                    return;
+                }
+            }
+            Box::new(Local)
+        }
+        Some(url) => match remote::login(url, &mut auth, gvars.sources()) {
+            Ok(storage) => storage,
+            Err(error) => {
+                say!("Error: Failed to log in to {} \n{}", url, error);
+                return;
+            }
+        },
+        None => Box::new(Local),
+    };
+
+    let writable = match (gvars.stdout(), &session) {
+        (false, None) => Some(target.as_path()),
+        _ => None,
+    };
+
+    if !check_permissions(gvars.source(), &mut *storage, writable) {
+        return;
+    }
+
+    // derive the key before the backup starts, asking for the passphrase if
+    // needed
+    let key = match gvars.encrypt() {
+        true => match encrypt::destination_key(gvars.dest(), |confirm| {
+            prompt::passphrase(gvars.keyfile().map(|path| path.as_path()), gvars.sources(), confirm)
+        }) {
+            Ok(key) => Some(key),
+            Err(error) => {
+                say!("Error: Failed to get the encryption key \n{}", error);
+                return;
+            }
+        },
+        false => None,
+    };
+
+    if gvars.quite() {
+        say!(
+            "** {:?} is being used as the source directory \
+             \n** {:?} is being used as the destination directory \
+             \n** Searching for files to backup...",
+            gvars.source(),
+            match (gvars.stdout(), gvars.remote()) {
+                (true, _) => PathBuf::from("stdout"),
+                (false, Some(url)) => PathBuf::from(url.to_string()),
+                (false, None) => gvars.dest().clone(),
+            }
+        );
+    }
+
+    // mixing encrypted and plain names would leave two copies of every file
+    let names = match (gvars.encrypt_names(), &key) {
+        (true, Some(key)) => match names::enable(&target) {
+            Ok(_) => Some(Names::new(key)),
+            Err(error) => {
+                say!("Error: Failed to enable encrypted names \n{}", error);
+                return;
+            }
+        },
+        _ if gvars.remote().is_none() && names::enabled(&target) => {
+            say!(
+                "Error: {:?} uses encrypted names, pass -e, --encrypt and --encrypt-names",
+                target
             );
+            return;
+        }
+        _ => None,
+    };
 
-            // note the queues length
-            let queue_len = &queue.len();
+    // the manifest records the public keys of every file and is what check
+    // verifies a plain backup against. It is kept with -R, --manifest, or
+    // once the destination has one. One that can not be read is rebuilt,
+    // which means copying every file again.
+    let mut rebuild = false;
+    let manifest = match gvars.format() == Format::Plain
+        && gvars.remote().is_none()
+        && (!gvars.recipients().is_empty() || gvars.manifest() || Manifest::exists(&target))
+    {
+        true => match Manifest::load(&target) {
+            Ok(manifest) => Some(Arc::new(manifest)),
+            Err(error) => {
+                if gvars.quite() {
+                    say!("Warning: Failed to read the manifest, it is rebuilt \n{}", error);
+                }
+                rebuild = true;
+                Some(Arc::new(Manifest::new(&target)))
+            }
+        },
+        false => None,
+    };
+
+    // get the job queue and read errors
+    let mut links = match gvars.hard_links() {
+        true => Some(Links::default()),
+        false => None,
+    };
+    let mut specials = vec![];
+    let mut mounts = gvars.source_dev().map(|dev| Mounts { dev, skipped: vec![] });
+    let (mut queue, mut errors, ..) = walk(
+        Vec::<(PathBuf, PathBuf)>::new(),
+        Vec::<String>::new(),
+        gvars.source(),
+        gvars.dest(),
+        gvars.regex(),
+        // the server compares the files itself
+        gvars.update() && session.is_none() && !rebuild,
+        names.as_ref(),
+        links.as_mut(),
+        Some(&mut specials),
+        mounts.as_mut(),
+        &mut *storage,
+    );
 
-            // Collect the read errors
+    // -x, --one-file-system lists the mount points it did not enter and
+    // warns about them in the log
+    if let Some(mounts) = mounts.filter(|mounts| !mounts.skipped.is_empty()) {
+        if gvars.quite() {
+            say!("** Skipped {} mount points", mounts.skipped.len());
+        }
+        for mount in mounts.skipped {
+            let message = format!("Skipped the mount point {:?}", mount);
             if gvars.quite() {
-                println!(
-                    "** {} files to backup and {} read errors.",
-                    queue_len,
-                    errors.len()
-                );
+                say!("   {}", &message);
             }
+            errors.push(format!("Warning: {}", message));
+        }
+    }
 
-            // backup files and collect the errors
-            errors.extend(
-                backup(
-                    queue, gvars.threads(), gvars.bar(), gvars.quite()
-                ).into_iter()
-            );
+    // only plain backups can link, everything else gets every name as a file
+    let mut links = links.map(|links| links.later).unwrap_or_default();
+    if gvars.format() != Format::Plain || session.is_some() {
+        queue.extend(links.drain(..).map(|link| (link.src, link.dest)));
+    }
+
+    // special files are only made on local plain destinations, the others
+    // are reported right away
+    if gvars.specials() != Policy::Copy || gvars.format() != Format::Plain || gvars.remote().is_some() {
+        errors.extend(specials::report(&specials, gvars.specials(), gvars.quite()));
+        specials.clear();
+    }
+
+    // repositories make the directories files are written to as needed,
+    // backr serve creates and finishes the directories itself
+    if gvars.format() == Format::Repo {
+        queue.retain(|(src, _)| !src.is_dir());
+    }
+    let dirs: Vec<(PathBuf, PathBuf)> = queue.iter().filter(|(src, _)| src.is_dir()).cloned().collect();
+
+    // note the queues length and the read errors, so they are not counted
+    // as failed copies
+    let queue_len = &(queue.len() - dirs.len() + links.len());
+    let mut read_errors = errors.len();
+
+    // Collect the read errors
+    if gvars.quite() {
+        say!(
+            "** {} files and {} directories to backup and {} read errors.",
+            queue_len,
+            dirs.len(),
+            errors.len()
+        );
+    }
+
+    // limit the bandwidth and iops if requested
+    let throttle = match (gvars.bwlimit(), gvars.max_iops(), gvars.throttle_file()) {
+        (0, 0, None) => None,
+        (bwlimit, max_iops, control) => {
+            let throttle = Arc::new(Throttle::new(bwlimit, max_iops));
+            if let Some(control) = control {
+                throttle::watch(throttle.clone(), control.clone(), gvars.quite());
+            }
+            Some(throttle)
+        }
+    };
+
+    // every backup thread opens its own storage
+    let open: storage::Open = match gvars.remote() {
+        Some(url) => {
+            let (url, auth) = (url.clone(), auth.clone());
+            Arc::new(move || remote::connect(&url, &auth))
+        }
+        None => Arc::new(|| Ok(Box::new(Local) as Box<dyn Storage>)),
+    };
+    // the session used for the checks is not needed anymore
+    drop(storage);
+
+    // backup files and collect the errors
+    errors.extend(match gvars.format() {
+        Format::Plain if session.is_some() => session.take().unwrap().push(
+            queue,
+            &gvars.remote().unwrap().path,
+            gvars.update(),
+            gvars.delta(),
+            gvars.bar(),
+            gvars.quite(),
+            throttle,
+        ),
+        Format::Plain => {
+            let opts = copy::Options {
+                throttle,
+                compress: gvars.compress(),
+                encrypt: key,
+                recipients: match gvars.recipients().is_empty() {
+                    true => None,
+                    false => Some(Arc::new(gvars.recipients().to_vec())),
+                },
+                manifest: manifest.clone(),
+                delta: gvars.delta(),
+                sparse: gvars.sparse(),
+            };
+            let mut errors = backup(queue, open.clone(), gvars.threads(), gvars.bar(), gvars.quite(), opts.clone());
+            errors.extend(links::create(&open, links, &opts, gvars.quite()));
+
+            // the special files are no copies, so their errors are not
+            // counted as failed ones
+            let (special_errors, skipped) = specials::create(&open, specials, gvars.quite());
+            let special_errors = special_errors
+                .into_iter()
+                .chain(specials::report(&skipped, gvars.specials(), gvars.quite()))
+                .collect::<Vec<_>>();
+            read_errors += special_errors.len();
+            errors.extend(special_errors);
 
-            // Summarize
+            // writing the files changed the mtimes of their directories
+            match open() {
+                Ok(mut storage) => errors.extend(copy::finish_dirs(&mut *storage, dirs)),
+                Err(error) => errors.push(format!("Error: Failed to open the destination \n {}", error)),
+            }
+            errors
+        }
+        Format::Repo => repo::backup(
+            queue,
+            &target,
+            gvars.source(),
+            gvars.threads(),
+            gvars.bar(),
+            gvars.quite(),
+            gvars.compress(),
+            throttle,
+        ),
+        format if gvars.stdout() => {
+            let stdout = io::stdout();
+            archive::write_tar(
+                queue, &target, stdout.lock(), format, gvars.bar(), gvars.quite(), throttle
+            )
+        }
+        format => {
+            let path = archive::archive_path(gvars.dest(), format);
             if gvars.quite() {
-                println!("** Files Backed Up: {}", queue_len - errors.len());
-                println!("** Total errors {}", errors.len());
+                say!("** Writing archive to {:?}", path);
             }
 
-            // write log if needed
-            write_log(&mut errors, gvars.log(), gvars.quite(), gvars.force_log());
+            match fs::File::create(&path) {
+                Ok(file) => archive::write_tar(
+                    queue, &target, file, format, gvars.bar(), gvars.quite(), throttle
+                ),
+                Err(error) => vec![format!(
+                    "Error: Failed to create the archive {:?} \n {}",
+                    path, error
+                )],
+            }
         }
-        false => (),
+    });
+
+    if let Some(manifest) = manifest {
+        if let Err(error) = manifest.save() {
+            errors.push(format!("Error: Failed to write the manifest \n {}", error));
+        }
+    }
+
+    // repositories may have a retention policy to apply after each backup
+    if gvars.format() == Format::Repo && errors.is_empty() {
+        errors.extend(repo::prune::apply_saved(&target, gvars.quite()));
+    }
+
+    // Summarize
+    if gvars.quite() {
+        say!(
+            "** Files Backed Up: {}",
+            queue_len.saturating_sub(errors.len() - read_errors)
+        );
+        say!("** Total errors {}", errors.len());
     }
+
+    // write log if needed
+    write_log(&mut errors, gvars.log(), gvars.quite(), gvars.force_log());
 }
 
 /// Backs up user data, by spawning the specified number of threads and
@@ -148,12 +664,14 @@ fn main() {
 // TODO setup up an option return type for error handling
 fn backup(
     queue: Vec<(PathBuf, PathBuf)>,
+    open: storage::Open,
     threads: i32,
     progress: bool,
     quite: bool,
+    opts: copy::Options,
 ) -> Vec<String> {
     if quite {
-        println!("** Starting backup ");
+        say!("** Starting backup ");
     }
 
     // Keeps track of progress
@@ -169,36 +687,46 @@ fn backup(
 
     // create threads
     for _ in 0..threads {
-        let (queue, errors, completed) = (
+        let (queue, errors, completed, opts, open) = (
             queue_mutex.clone(),
             errors_mutex.clone(),
             completed_mutex.clone(),
+            opts.clone(),
+            open.clone(),
         );
 
         let handle = thread::spawn(move || {
             // collect local errors
             let mut local_errors = vec![];
 
+            let mut storage = match open() {
+                Ok(storage) => storage,
+                Err(error) => {
+                    errors
+                        .lock()
+                        .unwrap()
+                        .push(format!("Error: Failed to open the destination \n {}", error));
+                    return;
+                }
+            };
+
             'main: loop {
                 // capture the current values then release the mutex
                 let next = queue.lock().unwrap().next();
 
                 match next {
                     Some((src, dest)) => {
-                        // create parent dir if not already existing
-                        if !dest.parent().unwrap().is_dir() {
-                            DirBuilder::new()
-                                .recursive(true)
-                                .create(dest.parent().unwrap())
-                                .unwrap();
-                        }
-
-                        // copy the file
-                        match fs::copy(&src, &dest) {
+                        // create the parent dir if not already existing,
+                        // then copy the file or create the dir
+                        let result = storage.mkdir_all(dest.parent().unwrap()).and_then(|_| match src.is_dir() {
+                            true => copy::backup_dir(&mut *storage, &src, &dest),
+                            false => copy::backup_file(&mut *storage, &src, &dest, &opts),
+                        });
+                        match result {
                             Ok(_) => (),
                             Err(error) => {
                                 if quite {
-                                    println!("{}", &error);
+                                    say!("{}", &error);
                                 }
                                 let mut _errors = errors.lock().unwrap();
                                 local_errors.push(format!(
@@ -218,7 +746,7 @@ fn backup(
             }
             // add all of the local errors to the programs error vec
             // then die
-            errors.lock().unwrap().extend(local_errors.into_iter());
+            errors.lock().unwrap().extend(local_errors);
         });
 
         // collect the thread handles
@@ -237,7 +765,9 @@ fn backup(
             let percent = ((completed as f32 / total as f32) * 100.0) as i32;
             bar.reach_percent(percent);
 
-            if percent >= 100 {
+            // a thread that failed to open the destination leaves files in
+            // the queue
+            if percent >= 100 || handles.iter().all(|handle| handle.is_finished()) {
                 break 'bar;
             }
             // sleep so it doesn't interfere with the backup threads
@@ -258,12 +788,13 @@ fn backup(
 
 /// Verify permissions on the src & dest. It reads the
 /// first level of the src dir and creates, then deletes a file in the dest.
-fn check_permissions(src: &PathBuf, dest: &PathBuf) -> bool {
+/// A dest of `None` is only used when streaming to stdout.
+fn check_permissions(src: &PathBuf, storage: &mut dyn Storage, dest: Option<&Path>) -> bool {
     // verify read on src
     let src_read = match fs::read_dir(src) {
         Ok(_) => true,
         Err(error) => {
-            println!(
+            say!(
                 "Error: Failed to read the source directory {:?} \n{}.",
                 src, error
             );
@@ -272,44 +803,42 @@ fn check_permissions(src: &PathBuf, dest: &PathBuf) -> bool {
     };
 
     // verify write on dest
-    let write_error_msg = format!("Error: You do not have write permissions for {:?}", dest);
-
-    let dest_write = match dest.exists() {
-        // Dest exists try to create a file in it
-        true => {
+    let dest_write = match dest {
+        // streaming to stdout, there is nothing to check
+        None => true,
+        Some(dest) => {
             let tmp_path = dest.join("CanIWriteHere?.txt");
-            match fs::File::create(&tmp_path) {
+            match storage
+                .mkdir_all(dest)
+                .and_then(|_| storage.write(&tmp_path, None, &mut |_| Ok(())))
+            {
                 Ok(_) => {
-                    match fs::remove_file(tmp_path) {
-                        Ok(_) => (),
-                        Err(_) => {
-                            println!("Error: Failed to delete the test file. The program will continue, but verify the backup after completion.");
-                            ()
-                        }
+                    if storage.remove(&tmp_path).is_err() {
+                        say!("Error: Failed to delete the test file. The program will continue, but verify the backup after completion.");
                     }
                     true
                 }
                 Err(error) => {
-                    println!("{} \n{}", write_error_msg, error);
+                    say!("Error: You do not have write permissions for {:?} \n{}", dest, error);
                     false
                 }
             }
         }
-        // Dest does not exist, attempt to create it
-        false => match DirBuilder::new().recursive(true).create(&dest) {
-            Ok(_) => true,
-            Err(error) => {
-                println!("{} \n{}", write_error_msg, error);
-                false
-            }
-        },
     };
 
     src_read && dest_write
 }
 
+/// The mount points below the source that -x, --one-file-system skips
+struct Mounts {
+    /// The device of the source
+    dev: u64,
+    skipped: Vec<PathBuf>,
+}
+
 /// Iterates through the source directory and adds files that match a regex
 /// to a queue. It also collects read errors
+#[allow(clippy::too_many_arguments)]
 fn walk(
     mut queue: Vec<(PathBuf, PathBuf)>,
     mut errors: Vec<String>,
@@ -317,9 +846,14 @@ fn walk(
     dest: &PathBuf,
     regex: &Regex,
     update: bool,
+    names: Option<&Names>,
+    mut links: Option<&mut Links>,
+    mut specials: Option<&mut Vec<Special>>,
+    mut mounts: Option<&mut Mounts>,
+    storage: &mut dyn Storage,
 ) -> (Vec<(PathBuf, PathBuf)>, Vec<String>) {
     // Verify the source dir
-    let iter = match fs::read_dir(&source) {
+    let iter = match fs::read_dir(source) {
         Ok(iter) => iter,
         Err(error) => {
             errors.push(format!("Failed to read {:?}.\n{}", &source, &error));
@@ -333,26 +867,57 @@ fn walk(
         let src = match path {
             Ok(path) => path.path(),
             Err(err) => {
-                println!("Error: Failed to read a path. Skipping! \n{}", err);
+                say!("Error: Failed to read a path. Skipping! \n{}", err);
                 continue;
             }
         };
 
         // if it matches the regex and is not a symlink
-        if regex.is_match(&src.to_str().unwrap()) {
+        if regex.is_match(src.to_str().unwrap()) {
             let mut tmp_dest: PathBuf = PathBuf::from(&dest);
-            tmp_dest.push(src.file_name().unwrap());
+            match names {
+                Some(names) => match names.encrypt(src.file_name().unwrap()) {
+                    Ok(name) => tmp_dest.push(name),
+                    Err(error) => {
+                        errors.push(format!("Failed to encrypt the name of {:?}.\n{}", &src, &error));
+                        continue;
+                    }
+                },
+                None => tmp_dest.push(src.file_name().unwrap()),
+            }
 
             // if src is a file
             if src.is_file() {
+                // later names of a file are linked to the first one
+                if let (Some(links), Ok(meta)) = (links.as_deref_mut(), src.metadata()) {
+                    if links.add(&meta, &src, &tmp_dest) {
+                        continue;
+                    }
+                }
+
                 match update {
                     // update flag is set
                     true => {
-                        // If the existing destination file is newer than the source file, ignore it and continue looping
-                        if tmp_dest.exists()
-                            && (src.metadata().unwrap().modified().unwrap()
-                                < tmp_dest.metadata().unwrap().modified().unwrap())
-                        {
+                        // the file may have been stored compressed or
+                        // encrypted, only a plain copy has the same size
+                        let existing = copy::stored_paths(&tmp_dest)
+                            .iter()
+                            .enumerate()
+                            .find_map(|(index, path)| {
+                                storage.stat(path).ok().flatten().map(|stat| (index, stat))
+                            });
+
+                        // If the existing destination file is newer than the source file, ignore it and continue looping.
+                        // Copies keep the mtime of the source, so one with the same mtime is the copy of the last
+                        // backup, unless a plain copy has another size
+                        let up_to_date = match (existing, src.metadata()) {
+                            (Some((index, stat)), Ok(meta)) => {
+                                let mtime = storage::mtime(&meta);
+                                stat.mtime > mtime || (stat.mtime == mtime && (index > 0 || stat.size == meta.len()))
+                            }
+                            _ => false,
+                        };
+                        if up_to_date {
                             continue;
                         } else {
                             queue.push((src, tmp_dest));
@@ -364,21 +929,39 @@ fn walk(
                     }
                 }
             // if src is a dir
+            // directories are queued before what is in them
             } else if src.is_dir() {
+                // a directory on another device is a mount point
+                if let Some(mounts) = mounts.as_deref_mut() {
+                    if src.metadata().ok().and_then(|meta| storage::device(&meta)) != Some(mounts.dev) {
+                        mounts.skipped.push(src);
+                        continue;
+                    }
+                }
+
+                queue.push((src.clone(), tmp_dest.clone()));
                 let (child_queue, child_errors) =
-                    walk(vec![], vec![], &src, &tmp_dest, regex, update);
+                    walk(
+                        vec![], vec![], &src, &tmp_dest, regex, update, names,
+                        links.as_deref_mut(), specials.as_deref_mut(), mounts.as_deref_mut(), storage
+                    );
 
-                queue.extend(child_queue.into_iter());
+                queue.extend(child_queue);
 
-                errors.extend(child_errors.into_iter());
+                errors.extend(child_errors);
+            // FIFOs, sockets and device nodes are handled by --specials
+            } else if let (Some(specials), Ok(meta)) = (specials.as_deref_mut(), src.metadata()) {
+                if specials::is_special(&meta) {
+                    specials.push(Special { src, dest: tmp_dest, meta });
+                }
             }
         }
     }
     (queue, errors)
 }
 
-/// Writes all the read/write errors to a specified file. If there are no
-/// errors creating a log will be skipped
+/// Writes all the read/write errors to a specified file, or to stderr if the
+/// file is `-`. If there are no errors creating a log will be skipped
 fn write_log(errors: &mut Vec<String>, log: &PathBuf, quite: bool, force_log: bool) {
     if errors.is_empty() {
         match force_log {
@@ -387,36 +970,128 @@ fn write_log(errors: &mut Vec<String>, log: &PathBuf, quite: bool, force_log: bo
             }
             false => {
                 if quite {
-                    println!("** There are no errors to report, so creating a log will be skipped");
+                    say!("** There are no errors to report, so creating a log will be skipped");
                 }
-                return ();
+                return;
             }
         }
     }
 
+    // a log of - is written to stderr
+    if log.as_os_str() == "-" {
+        for error in errors {
+            eprintln!("{}", error);
+        }
+        return;
+    }
+
     match fs::File::create(log) {
         Ok(mut file) => {
             if quite {
-                println!("** Writing log to {:?}", log);
+                say!("** Writing log to {:?}", log);
             }
             for error in errors {
                 //file.write_fmt(format_args!("{}", error)).unwrap();
                 match file.write_fmt(format_args!("{}", error)) {
                     Ok(_) => (),
                     Err(_) => {
-                        println!("Error: {}", error);
+                        say!("Error: {}", error);
                     }
                 }
             }
         }
         Err(error) => {
             if quite {
-                println!("ERROR: Failed to create log file \n{}", error);
-                println!("** Dumping errors to stdout\n");
+                say!("ERROR: Failed to create log file \n{}", error);
+                say!("** Dumping errors to stdout\n");
                 for error in errors {
-                    println!("{}", error);
+                    say!("{}", error);
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use filetime::{self, FileTime};
+    use tempfile::TempDir;
+
+    fn put(path: &Path, data: &[u8], mtime: i64) {
+        fs::write(path, data).unwrap();
+        filetime::set_file_mtime(path, FileTime::from_unix_time(mtime, 0)).unwrap();
+    }
+
+    /// Walks `source` into `dest` and returns the names of the queued files
+    fn queued(source: &Path, dest: &Path, update: bool) -> Vec<String> {
+        let regex = Regex::new(".*").unwrap();
+        let (source, dest) = (source.to_path_buf(), dest.to_path_buf());
+        let (queue, errors) = walk(vec![], vec![], &source, &dest, &regex, update, None, None, None, None, &mut Local);
+        assert!(errors.is_empty());
+        let mut names: Vec<_> = queue
+            .iter()
+            .map(|(_, dest)| dest.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn update_keeps_the_newest() {
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        for name in ["missing", "older", "same", "resized", "newer", "newer_resized"] {
+            put(&src.path().join(name), b"source", 1_600_000_000);
+        }
+        put(&dest.path().join("older"), b"source", 1_500_000_000);
+        put(&dest.path().join("same"), b"source", 1_600_000_000);
+        put(&dest.path().join("resized"), b"half", 1_600_000_000);
+        put(&dest.path().join("newer"), b"edited", 1_700_000_000);
+        put(&dest.path().join("newer_resized"), b"edited more", 1_700_000_000);
+
+        assert_eq!(queued(src.path(), dest.path(), true), ["missing", "older", "resized"]);
+        assert_eq!(queued(src.path(), dest.path(), false).len(), 6);
+
+        // a compressed copy has another size
+        put(&copy::stored_paths(&dest.path().join("missing"))[1], b"zst", 1_600_000_000);
+        assert_eq!(queued(src.path(), dest.path(), true), ["older", "resized"]);
+    }
+
+    #[test]
+    fn directories_are_queued_before_their_files() {
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        fs::create_dir_all(src.path().join("dir/empty")).unwrap();
+        put(&src.path().join("dir/file"), b"file", 1_600_000_000);
+
+        let regex = Regex::new(".*").unwrap();
+        let (source, target) = (src.path().to_path_buf(), dest.path().to_path_buf());
+        let (queue, _) = walk(vec![], vec![], &source, &target, &regex, false, None, None, None, None, &mut Local);
+        let position = |name: &str| queue.iter().position(|(_, dest)| *dest == target.join(name)).unwrap();
+        assert_eq!(queue.len(), 3);
+        assert!(position("dir") < position("dir/empty"));
+        assert!(position("dir") < position("dir/file"));
+    }
+
+    #[test]
+    fn other_devices_are_not_entered() {
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        fs::create_dir(src.path().join("dir")).unwrap();
+        put(&src.path().join("dir/file"), b"file", 1_600_000_000);
+        put(&src.path().join("file"), b"file", 1_600_000_000);
+
+        let regex = Regex::new(".*").unwrap();
+        let (source, target) = (src.path().to_path_buf(), dest.path().to_path_buf());
+        let walk_on = |dev: u64| {
+            let mut mounts = Mounts { dev, skipped: vec![] };
+            let (queue, _) =
+                walk(vec![], vec![], &source, &target, &regex, false, None, None, None, Some(&mut mounts), &mut Local);
+            (queue.len(), mounts.skipped)
+        };
+
+        // the source device is entered, any other one is a mount point
+        let dev = storage::device(&fs::metadata(src.path()).unwrap()).unwrap();
+        assert_eq!(walk_on(dev), (3, vec![]));
+        assert_eq!(walk_on(dev.wrapping_add(1)), (1, vec![src.path().join("dir")]));
+    }
+}
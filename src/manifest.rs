@@ -0,0 +1,194 @@
+//! The manifest of a plain backup.
+//!
+//! `backr.manifest` sits in the destination root and holds one JSON object
+//! per line for every file backed up into the destination: where it is stored,
+//! the size and mtime of the source, the sha256 of what was stored and the
+//! public keys it was encrypted to. Each backup updates the entries of the
+//! files it wrote.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use hex;
+use serde_json;
+use sha2::{Digest, Sha256};
+
+/// Name of the manifest in the destination root
+pub const MANIFEST_FILE: &str = "backr.manifest";
+
+/// A single file in the manifest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// The path of the file, relative to the destination root
+    pub path: String,
+    /// The path the file is stored under, including any suffix
+    pub stored: String,
+    /// The size of the source file in bytes
+    pub size: u64,
+    /// The mtime of the source file in seconds since the unix epoch
+    pub mtime: u64,
+    /// The public keys the file was encrypted to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<String>,
+    /// The sha256 of the stored file, which check --read-data compares it
+    /// with. Manifests written before it was added do not have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// The entries of a destination, shared by every backup thread
+#[derive(Debug)]
+pub struct Manifest {
+    root: PathBuf,
+    entries: Mutex<BTreeMap<String, Entry>>,
+}
+
+impl Manifest {
+    /// Starts an empty manifest for the destination `root`
+    pub fn new(root: &Path) -> Manifest {
+        Manifest {
+            root: root.to_path_buf(),
+            entries: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns true if the destination `root` has a manifest
+    pub fn exists(root: &Path) -> bool {
+        root.join(MANIFEST_FILE).exists()
+    }
+
+    /// Loads the manifest of the destination `root`, or starts an empty one
+    pub fn load(root: &Path) -> io::Result<Manifest> {
+        let mut entries = BTreeMap::new();
+
+        match File::open(root.join(MANIFEST_FILE)) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let entry: Entry = serde_json::from_str(&line?)
+                        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+                    entries.insert(entry.path.clone(), entry);
+                }
+            }
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => (),
+            Err(error) => return Err(error),
+        }
+
+        Ok(Manifest {
+            root: root.to_path_buf(),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Records that `src` was backed up to `dest`, stored as `stored`
+    pub fn record(&self, src: &Path, dest: &Path, stored: &Path, recipients: Vec<String>) {
+        let meta = fs::metadata(src).ok();
+        let entry = Entry {
+            path: self.relative(dest),
+            stored: self.relative(stored),
+            size: meta.as_ref().map(|meta| meta.len()).unwrap_or_default(),
+            mtime: meta
+                .and_then(|meta| meta.modified().ok())
+                .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+                .map(|mtime| mtime.as_secs())
+                .unwrap_or_default(),
+            recipients,
+            // the stored file is read back, so a broken write is caught
+            sha256: hash_file(stored).ok(),
+        };
+
+        self.entries.lock().unwrap().insert(entry.path.clone(), entry);
+    }
+
+    /// Returns a copy of every entry
+    pub fn entries(&self) -> Vec<Entry> {
+        self.entries.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Forgets the entry of a file
+    pub fn remove(&self, path: &str) {
+        self.entries.lock().unwrap().remove(path);
+    }
+
+    /// Writes the manifest back to the destination
+    pub fn save(&self) -> io::Result<()> {
+        let path = self.root.join(MANIFEST_FILE);
+        let mut tmp = path.as_os_str().to_os_string();
+        tmp.push(".part");
+
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for entry in self.entries.lock().unwrap().values() {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        fs::rename(tmp, path)
+    }
+
+    fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Returns the hex encoded sha256 of a file
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn entries_survive_a_round_trip() {
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let root = dest.path();
+        fs::write(src.path().join("file"), b"source").unwrap();
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("dir/file.backr.age"), b"stored").unwrap();
+
+        assert!(!Manifest::exists(root));
+        let manifest = Manifest::load(root).unwrap();
+        let stored = root.join("dir/file.backr.age");
+        manifest.record(&src.path().join("file"), &root.join("dir/file"), &stored, vec!["age1key".to_string()]);
+        manifest.record(&src.path().join("gone"), &root.join("gone"), &root.join("gone"), vec![]);
+        manifest.remove("gone");
+        manifest.save().unwrap();
+        assert!(Manifest::exists(root));
+
+        let entries = Manifest::load(root).unwrap().entries();
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!((entry.path.as_str(), entry.stored.as_str()), ("dir/file", "dir/file.backr.age"));
+        assert_eq!(entry.size, 6);
+        let mtime = fs::metadata(src.path().join("file")).unwrap().modified().unwrap();
+        assert_eq!(entry.mtime, mtime.duration_since(UNIX_EPOCH).unwrap().as_secs());
+        assert_eq!(entry.recipients, ["age1key"]);
+        assert_eq!(entry.sha256, Some(hash_file(&stored).unwrap()));
+    }
+
+    #[test]
+    fn older_and_broken_manifests() {
+        let dest = TempDir::new().unwrap();
+        // entries written before sha256 and without recipients still load
+        let old = "{\"path\":\"a\",\"stored\":\"a\",\"size\":1,\"mtime\":2}\n";
+        fs::write(dest.path().join(MANIFEST_FILE), old).unwrap();
+        let entries = Manifest::load(dest.path()).unwrap().entries();
+        assert_eq!((entries[0].sha256.clone(), entries[0].recipients.len()), (None, 0));
+
+        fs::write(dest.path().join(MANIFEST_FILE), "not json\n").unwrap();
+        assert_eq!(Manifest::load(dest.path()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
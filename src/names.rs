@@ -0,0 +1,241 @@
+//! Optional encryption of file and directory names in the destination.
+//!
+//! Names are encrypted deterministically, so the same source path always maps
+//! to the same destination path and `--update` keeps working. A 128 bit
+//! HMAC-SHA256 of the name is used as a synthetic IV (SIV) and as the nonce
+//! for XChaCha20-Poly1305. The result is base32 encoded, which is safe on case
+//! insensitive filesystems. Equal names encrypt to equal names, so the only
+//! thing the destination reveals is the shape of the tree.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use encrypt::Key;
+
+/// Marks a destination that uses encrypted names
+pub const NAMES_FILE: &str = "backr.names";
+
+const SIV_LEN: usize = 16;
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Encrypts and decrypts names with keys derived from the destination key
+#[derive(Clone)]
+pub struct Names {
+    mac_key: [u8; 32],
+    cipher: XChaCha20Poly1305,
+}
+
+impl ::std::fmt::Debug for Names {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.write_str("Names")
+    }
+}
+
+impl Names {
+    /// Derives the name keys from the destination key
+    pub fn new(key: &Key) -> Names {
+        Names {
+            mac_key: key.subkey(b"backr name mac"),
+            cipher: XChaCha20Poly1305::new(&key.subkey(b"backr name cipher").into()),
+        }
+    }
+
+    fn siv(&self, name: &[u8]) -> [u8; SIV_LEN] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.mac_key).unwrap();
+        mac.update(name);
+        let mut siv = [0; SIV_LEN];
+        siv.copy_from_slice(&mac.finalize().into_bytes()[..SIV_LEN]);
+        siv
+    }
+
+    fn nonce(siv: &[u8]) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[..SIV_LEN].copy_from_slice(siv);
+        nonce
+    }
+
+    /// Encrypts a single file or directory name
+    pub fn encrypt(&self, name: &OsStr) -> io::Result<OsString> {
+        let name = name.to_str().ok_or_else(|| invalid(name))?.as_bytes();
+        let siv = self.siv(name);
+        let sealed = self
+            .cipher
+            .encrypt(&Names::nonce(&siv), name)
+            .map_err(|_| invalid(OsStr::new("")))?;
+
+        let mut raw = siv.to_vec();
+        raw.extend_from_slice(&sealed);
+        let encrypted = base32_encode(&raw);
+
+        // most filesystems can not store longer names
+        if encrypted.len() > 240 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The name is too long to be encrypted",
+            ));
+        }
+        Ok(OsString::from(encrypted))
+    }
+
+    /// Decrypts a single file or directory name. Anything after the first `.`
+    /// is a suffix added by backr and is kept as is.
+    pub fn decrypt(&self, name: &OsStr) -> io::Result<OsString> {
+        let full = name.to_str().ok_or_else(|| invalid(name))?;
+        let (encrypted, suffix) = match full.find('.') {
+            Some(dot) => full.split_at(dot),
+            None => (full, ""),
+        };
+
+        let raw = base32_decode(encrypted).ok_or_else(|| invalid(name))?;
+        if raw.len() < SIV_LEN {
+            return Err(invalid(name));
+        }
+
+        let (siv, sealed) = raw.split_at(SIV_LEN);
+        let plain = self
+            .cipher
+            .decrypt(&Names::nonce(siv), sealed)
+            .map_err(|_| invalid(name))?;
+        if self.siv(&plain)[..] != *siv {
+            return Err(invalid(name));
+        }
+
+        let mut plain = String::from_utf8(plain).map_err(|_| invalid(name))?;
+        plain.push_str(suffix);
+        Ok(OsString::from(plain))
+    }
+
+    /// Decrypts every component of a relative path
+    pub fn decrypt_path(&self, path: &Path) -> io::Result<PathBuf> {
+        let mut decrypted = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => decrypted.push(self.decrypt(name)?),
+                other => decrypted.push(other.as_os_str()),
+            }
+        }
+        Ok(decrypted)
+    }
+}
+
+/// Returns true if the destination uses encrypted names
+pub fn enabled(dest: &Path) -> bool {
+    dest.join(NAMES_FILE).exists()
+}
+
+/// Marks the destination as using encrypted names
+pub fn enable(dest: &Path) -> io::Result<()> {
+    fs::write(dest.join(NAMES_FILE), "backr encrypted names v1\n")
+}
+
+fn invalid(name: &OsStr) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Failed to encrypt or decrypt the name {:?}", name),
+    )
+}
+
+/// RFC 4648 base32 in lowercase and without padding
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+
+    for byte in data {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+
+    out
+}
+
+fn base32_decode(data: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+
+    for c in data.bytes() {
+        let value = BASE32.iter().position(|b| *b == c.to_ascii_lowercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(passphrase: &str) -> Names {
+        Names::new(&Key::derive(passphrase, [3; 16]).unwrap())
+    }
+
+    #[test]
+    fn round_trip_is_deterministic() {
+        let names = names("passphrase");
+        for name in &["a", "Documents", "résumé.txt", ".hidden", "with space"] {
+            let encrypted = names.encrypt(OsStr::new(name)).unwrap();
+            assert_eq!(encrypted, names.encrypt(OsStr::new(name)).unwrap());
+            assert!(encrypted.to_str().unwrap().bytes().all(|b| BASE32.contains(&b)));
+            assert_eq!(names.decrypt(&encrypted).unwrap(), OsStr::new(name));
+        }
+        assert_ne!(names.encrypt(OsStr::new("a")).unwrap(), names.encrypt(OsStr::new("b")).unwrap());
+    }
+
+    #[test]
+    fn suffixes_are_kept() {
+        let names = names("passphrase");
+        let mut encrypted = names.encrypt(OsStr::new("notes.txt")).unwrap();
+        encrypted.push(".zst.backr.enc");
+        assert_eq!(names.decrypt(&encrypted).unwrap(), OsStr::new("notes.txt.zst.backr.enc"));
+
+        let path = Path::new(&names.encrypt(OsStr::new("dir")).unwrap()).join(names.encrypt(OsStr::new("file")).unwrap());
+        assert_eq!(names.decrypt_path(&path).unwrap(), Path::new("dir/file"));
+    }
+
+    #[test]
+    fn other_keys_and_damage_fail() {
+        let (names, other) = (names("passphrase"), names("other"));
+        let encrypted = names.encrypt(OsStr::new("file")).unwrap();
+        assert_ne!(encrypted, other.encrypt(OsStr::new("file")).unwrap());
+        assert!(other.decrypt(&encrypted).is_err());
+
+        let mut damaged = encrypted.into_string().unwrap();
+        let first = if damaged.starts_with('a') { "b" } else { "a" };
+        damaged.replace_range(..1, first);
+        assert!(names.decrypt(OsStr::new(&damaged)).is_err());
+        assert!(names.decrypt(OsStr::new("short")).is_err());
+        assert!(names.decrypt(OsStr::new("not base32!")).is_err());
+    }
+
+    #[test]
+    fn long_names_are_rejected() {
+        assert!(names("passphrase").encrypt(OsStr::new(&"x".repeat(200))).is_err());
+    }
+
+    #[test]
+    fn base32_round_trip() {
+        for len in 0..12 {
+            let data: Vec<u8> = (0..len).map(|i| (i * 37) as u8).collect();
+            assert_eq!(base32_decode(&base32_encode(&data)).unwrap(), data);
+        }
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+    }
+}
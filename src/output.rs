@@ -0,0 +1,54 @@
+//! Routes human readable messages to stdout, or to stderr when stdout is
+//! carrying the backup itself.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Sends every following message to stderr
+pub fn use_stderr() {
+    TO_STDERR.store(true, Ordering::SeqCst);
+}
+
+/// Returns true if messages are being sent to stderr
+pub fn to_stderr() -> bool {
+    TO_STDERR.load(Ordering::SeqCst)
+}
+
+/// Works like `println!`, but prints to stderr once `use_stderr` is called
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if ::output::to_stderr() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use tempfile::TempDir;
+
+    use globalvars::GlobalVars;
+
+    #[test]
+    fn archives_on_stdout_move_messages_to_stderr() {
+        let src = TempDir::new().unwrap();
+        let source = src.path().to_string_lossy().into_owned();
+        let cli = GlobalVars::app().get_matches_from(["backr", "-p", "-s", &source, "-d", "-", "-f", "tar"]);
+        let gvars = GlobalVars::from(&cli);
+
+        // the archive owns stdout, the bar and the log stay off it
+        assert!(gvars.stdout());
+        assert!(!gvars.bar());
+        assert_eq!(gvars.log(), Path::new("-"));
+
+        use_stderr();
+        assert!(to_stderr());
+    }
+}
@@ -0,0 +1,295 @@
+//! Reads passphrases and passwords, from a file descriptor, the environment,
+//! the keyring or the terminal without echoing them.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use clap::{Arg, ArgMatches};
+
+use keyring;
+
+/// The environment variable checked for a passphrase on unattended runs
+pub const PASSPHRASE_VAR: &str = "BACKR_PASSPHRASE";
+
+/// The environment variable checked for the password of a remote destination
+pub const PASSWORD_VAR: &str = "BACKR_PASSWORD";
+
+/// Where secrets are read from before asking on the terminal
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sources {
+    /// A file descriptor to read the secret from, ie. a pipe from a password
+    /// manager. Only the first line is read.
+    pub fd: Option<u32>,
+    /// Look the secret up in the keyring
+    pub keyring: bool,
+}
+
+impl Sources {
+    /// The --password-fd and --keyring arguments, shared by every command
+    /// that may need a secret
+    pub fn args() -> Vec<Arg<'static, 'static>> {
+        vec![
+            Arg::with_name("password_fd")
+                .long("password-fd")
+                .value_name("FD")
+                .help("Reads the passphrase or password from a file descriptor")
+                .long_help(
+                    "Reads the passphrase or password from the first line of\
+                     an inherited file descriptor, ie. --password-fd 3\
+                     3< <(pass show backr).",
+                ).takes_value(true)
+                .validator(|fd| {
+                    fd.parse::<u32>()
+                        .map(|_| ())
+                        .map_err(|_| format!("{:?} is not a file descriptor", fd))
+                }),
+            Arg::with_name("keyring")
+                .long("keyring")
+                .help("Looks the passphrase or password up in the system keyring")
+                .long_help(
+                    "Looks the passphrase or password up in the system keyring\
+                     through the Secret Service API. Items need the attribute\
+                     application=backr, and kind=passphrase or kind=password\
+                     with the protocol, host and user of the destination.",
+                ),
+        ]
+    }
+
+    pub fn from_cli(cli: &ArgMatches) -> Sources {
+        Sources {
+            fd: cli.value_of("password_fd").map(|fd| fd.parse().unwrap()),
+            keyring: cli.is_present("keyring"),
+        }
+    }
+}
+
+/// Reads the first line of an inherited file descriptor
+fn read_fd(fd: u32) -> io::Result<String> {
+    let file = fs::File::open(format!("/dev/fd/{}", fd))?;
+    let mut line = String::new();
+    BufReader::new(file).read_line(&mut line)?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// Returns the secret from the file descriptor, the environment variable
+/// `var` or the keyring item with `attributes`, in that order, without ever
+/// asking for it
+pub fn secret(sources: Sources, var: &str, attributes: &[(&str, &str)]) -> io::Result<Option<String>> {
+    secret_with(sources, var, attributes, keyring::lookup)
+}
+
+/// Returns the secret like `secret`, with `lookup` reading the keyring
+pub fn secret_with<F>(sources: Sources, var: &str, attributes: &[(&str, &str)], lookup: F) -> io::Result<Option<String>>
+where
+    F: FnOnce(&[(&str, &str)]) -> io::Result<String>,
+{
+    if let Some(fd) = sources.fd {
+        return read_fd(fd).map(Some);
+    }
+
+    if let Ok(secret) = env::var(var) {
+        return Ok(Some(secret));
+    }
+
+    match sources.keyring {
+        true => lookup(attributes).map(Some),
+        false => Ok(None),
+    }
+}
+
+/// Returns the passphrase from the keyfile, the file descriptor, the
+/// BACKR_PASSPHRASE environment variable, the keyring or the terminal, in
+/// that order. `confirm` asks for the passphrase twice when it is read from
+/// the terminal. An empty passphrase is an error wherever it comes from.
+pub fn passphrase(keyfile: Option<&Path>, sources: Sources, confirm: bool) -> io::Result<String> {
+    passphrase_with(keyfile, sources, confirm, keyring::lookup)
+}
+
+/// Returns the passphrase like `passphrase`, with `lookup` reading the
+/// keyring
+pub fn passphrase_with<F>(keyfile: Option<&Path>, sources: Sources, confirm: bool, lookup: F) -> io::Result<String>
+where
+    F: FnOnce(&[(&str, &str)]) -> io::Result<String>,
+{
+    let passphrase = match keyfile {
+        Some(keyfile) => {
+            let contents = fs::read_to_string(keyfile)?;
+            contents.trim_end_matches(&['\r', '\n'][..]).to_string()
+        }
+        None => match secret_with(sources, PASSPHRASE_VAR, &[("kind", "passphrase")], lookup)? {
+            Some(passphrase) => passphrase,
+            None => {
+                let passphrase = read_hidden("Passphrase: ")?;
+                if confirm && read_hidden("Confirm passphrase: ")? != passphrase {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "The passphrases do not match",
+                    ));
+                }
+                passphrase
+            }
+        },
+    };
+
+    if passphrase.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The passphrase can not be empty",
+        ));
+    }
+
+    Ok(passphrase)
+}
+
+/// Prints the prompt to the terminal and reads a line with echo turned off.
+/// The terminal is used directly, so this works while stdout and stdin are
+/// redirected.
+#[cfg(unix)]
+pub fn read_hidden(prompt: &str) -> io::Result<String> {
+    use std::os::unix::io::AsRawFd;
+    use termios::{tcsetattr, Termios, ECHO, ECHONL, TCSANOW};
+
+    let tty = fs::OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    let fd = tty.as_raw_fd();
+
+    let original = Termios::from_fd(fd)?;
+    let mut hidden = original;
+    hidden.c_lflag &= !ECHO;
+    hidden.c_lflag |= ECHONL;
+    tcsetattr(fd, TCSANOW, &hidden)?;
+
+    let result = (&tty)
+        .write_all(prompt.as_bytes())
+        .and_then(|_| (&tty).flush())
+        .and_then(|_| {
+            let mut line = String::new();
+            BufReader::new(&tty).read_line(&mut line).map(|_| line)
+        });
+
+    // always give the terminal its echo back
+    tcsetattr(fd, TCSANOW, &original)?;
+
+    result.map(|line| line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// Without termios the passphrase can only be read with echo turned on
+#[cfg(not(unix))]
+pub fn read_hidden(prompt: &str) -> io::Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::io::AsRawFd;
+
+    use tempfile::NamedTempFile;
+
+    use keyring::lookup_in;
+    use keyring::tests::Keyring;
+
+    fn keyring(passphrase: &'static str) -> Keyring {
+        Keyring {
+            items: vec![
+                (vec![("application", "backr"), ("kind", "passphrase")], passphrase.as_bytes().to_vec()),
+                (
+                    vec![("application", "backr"), ("kind", "password"), ("host", "example.com")],
+                    b"password".to_vec(),
+                ),
+            ],
+            dismissed: false,
+        }
+    }
+
+    /// Returns a file holding `contents` and its descriptor
+    fn fd(contents: &str) -> (fs::File, u32) {
+        let file = NamedTempFile::new().unwrap();
+        fs::write(file.path(), contents).unwrap();
+        let file = file.reopen().unwrap();
+        let fd = file.as_raw_fd() as u32;
+        (file, fd)
+    }
+
+    #[test]
+    fn sources_in_order() {
+        let (keyring, var) = (keyring("passphrase"), "BACKR_TEST_SECRET");
+        let attributes = [("kind", "password"), ("host", "example.com")];
+        let lookup = |attributes: &[(&str, &str)]| lookup_in(&keyring, attributes);
+        let sources = |fd: Option<u32>, keyring: bool| Sources { fd, keyring };
+
+        assert_eq!(secret_with(sources(None, false), var, &attributes, lookup).unwrap(), None);
+        assert_eq!(
+            secret_with(sources(None, true), var, &attributes, lookup).unwrap().as_deref(),
+            Some("password")
+        );
+
+        env::set_var(var, "from the environment");
+        assert_eq!(
+            secret_with(sources(None, true), var, &attributes, lookup).unwrap().as_deref(),
+            Some("from the environment")
+        );
+
+        // only the first line of the descriptor is read
+        let (_file, fd) = fd("from the fd\nsecond line\n");
+        assert_eq!(
+            secret_with(sources(Some(fd), true), var, &attributes, lookup).unwrap().as_deref(),
+            Some("from the fd")
+        );
+        env::remove_var(var);
+    }
+
+    #[test]
+    fn missing_keyring_item_fails() {
+        let keyring = keyring("passphrase");
+        let sources = Sources {
+            fd: None,
+            keyring: true,
+        };
+        let lookup = |attributes: &[(&str, &str)]| lookup_in(&keyring, attributes);
+        let error = secret_with(sources, "BACKR_TEST_UNSET", &[("kind", "password"), ("host", "other.com")], lookup);
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn empty_passphrases_are_rejected() {
+        env::remove_var(PASSPHRASE_VAR);
+        let keyfile = NamedTempFile::new().unwrap();
+        let rejected = |result: io::Result<String>| result.unwrap_err().kind() == io::ErrorKind::InvalidInput;
+        let none = Sources::default();
+
+        fs::write(keyfile.path(), "\n").unwrap();
+        let keyring = keyring("passphrase");
+        assert!(rejected(passphrase_with(Some(keyfile.path()), none, false, |a| lookup_in(&keyring, a))));
+        fs::write(keyfile.path(), "keyfile\r\n").unwrap();
+        assert_eq!(
+            passphrase_with(Some(keyfile.path()), none, false, |a| lookup_in(&keyring, a)).unwrap(),
+            "keyfile"
+        );
+
+        let (_file, fd) = fd("\n");
+        let sources = Sources {
+            fd: Some(fd),
+            keyring: false,
+        };
+        assert!(rejected(passphrase_with(None, sources, false, |a| lookup_in(&keyring, a))));
+
+        let sources = Sources {
+            fd: None,
+            keyring: true,
+        };
+        let empty = self::keyring("");
+        assert!(rejected(passphrase_with(None, sources, false, |a| lookup_in(&empty, a))));
+        assert_eq!(
+            passphrase_with(None, sources, false, |a| lookup_in(&keyring, a)).unwrap(),
+            "passphrase"
+        );
+    }
+}
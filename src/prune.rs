@@ -0,0 +1,126 @@
+//! The `prune` subcommand. Removes the snapshots of a repository that a
+//! retention policy does not keep, then frees their data.
+
+use std::io;
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use repo::lock::Lock;
+use repo::prune::{self, Policy};
+use repo::Repository;
+
+/// Builds the clap subcommand
+pub fn subcommand() -> App<'static, 'static> {
+    let keep = |name: &'static str, long: &'static str, help: &'static str| {
+        Arg::with_name(name)
+            .long(long)
+            .value_name("NUM")
+            .help(help)
+            .takes_value(true)
+            .validator(|value| {
+                value
+                    .parse::<u32>()
+                    .map(|_| ())
+                    .map_err(|_| format!("{:?} is not a number", value))
+            })
+    };
+
+    SubCommand::with_name("prune")
+        .about("Removes the snapshots of a repository that a retention policy does not keep.")
+        .arg(
+            Arg::with_name("source")
+                .short("s")
+                .long("source")
+                .value_name("REPOSITORY_PATH")
+                .help("The repository, ie. the destination of backr -f repo")
+                .takes_value(true)
+                .required(true),
+        ).arg(keep("keep_last", "keep-last", "Keeps the newest NUM snapshots"))
+        .arg(keep("keep_daily", "keep-daily", "Keeps the newest snapshot of the last NUM days with one"))
+        .arg(keep("keep_weekly", "keep-weekly", "Keeps the newest snapshot of the last NUM weeks with one"))
+        .arg(keep("keep_monthly", "keep-monthly", "Keeps the newest snapshot of the last NUM months with one"))
+        .arg(
+            Arg::with_name("save")
+                .long("save")
+                .help("Saves the policy in the repository, so it is applied after every backup")
+                .long_help(
+                    "Saves the policy in the repository config, so it is\
+                     applied after every backup that finished without\
+                     errors. Without any --keep option the saved policy is\
+                     removed.",
+                ),
+        ).arg(
+            Arg::with_name("dry_run")
+                .short("n")
+                .long("dry-run")
+                .help("Only reports which snapshots would be removed")
+                .conflicts_with("save"),
+        ).arg(
+            Arg::with_name("quite")
+                .short("q")
+                .long("quite")
+                .help("Stop backr from printing to stdout."),
+        )
+}
+
+/// Runs the prune subcommand and returns the errors it ran into
+pub fn run(cli: &ArgMatches) -> Vec<String> {
+    let source = PathBuf::from(cli.value_of("source").unwrap());
+    let dry_run = cli.is_present("dry_run");
+    let quite = !cli.is_present("quite");
+
+    let value = |name| cli.value_of(name).map(|value| value.parse().unwrap());
+    let given = [
+        value("keep_last"),
+        value("keep_daily"),
+        value("keep_weekly"),
+        value("keep_monthly"),
+    ];
+    let policy = Policy {
+        last: given[0].unwrap_or_default(),
+        daily: given[1].unwrap_or_default(),
+        weekly: given[2].unwrap_or_default(),
+        monthly: given[3].unwrap_or_default(),
+    };
+
+    let result = Repository::open(&source).and_then(|mut repo| {
+        let _lock = match dry_run {
+            true => None,
+            false => Some(Lock::acquire(repo.root())?),
+        };
+
+        if cli.is_present("save") {
+            repo.set_policy(policy)?;
+            if quite {
+                match policy.is_empty() {
+                    true => say!("** Removed the retention policy"),
+                    false => say!("** Saved the retention policy {:?}", policy),
+                }
+            }
+        }
+
+        // without any --keep option the saved policy is used
+        let policy = match given.iter().all(Option::is_none) {
+            true => repo.config().keep,
+            false => policy,
+        };
+        match policy.is_empty() {
+            true if cli.is_present("save") => Ok(()),
+            true => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "There is no retention policy, pass at least one --keep option",
+            )),
+            false => prune::prune_and_gc(&repo, &policy, dry_run, quite),
+        }
+    });
+
+    match result {
+        Ok(_) => vec![],
+        Err(error) => {
+            let error = format!("Error: Failed to prune {:?} \n {}", source, error);
+            say!("{}", error);
+            vec![error]
+        }
+    }
+}
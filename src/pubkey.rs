@@ -0,0 +1,210 @@
+//! Optional encryption of each file to one or more age public keys.
+//!
+//! Unlike `--encrypt` the backup only needs the public keys, so the machine
+//! running the backup and the machine storing it can write files but can
+//! never read them back. Restoring needs one of the matching age identities.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use age::x25519::Recipient;
+use age::{Decryptor, Encryptor, Identity, IdentityFile};
+use zstd::stream::read::Decoder;
+use zstd::stream::write::Encoder;
+
+
+/// Appended to the name of every file encrypted to public keys
+pub const SUFFIX: &str = ".backr.age";
+
+/// Appended instead of `SUFFIX` when the file was compressed first
+pub const COMPRESSED_SUFFIX: &str = ".backr.zst.age";
+
+/// Parses an age public key, ie. `age1...`
+pub fn parse_recipient(recipient: &str) -> Result<Recipient, String> {
+    Recipient::from_str(recipient.trim())
+        .map_err(|error| format!("{:?} is not an age public key. {}", recipient, error))
+}
+
+/// Reads a recipients file with one public key per line. Empty lines and
+/// lines starting with # are ignored.
+pub fn read_recipients(path: &Path) -> Result<Vec<Recipient>, String> {
+    let file = File::open(path).map_err(|error| format!("Failed to read {:?}. {}", path, error))?;
+
+    let mut recipients = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|error| format!("Failed to read {:?}. {}", path, error))?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            recipients.push(parse_recipient(line)?);
+        }
+    }
+
+    Ok(recipients)
+}
+
+/// Reads every identity in an age identity file
+pub fn read_identities(path: &Path) -> io::Result<Vec<Box<dyn Identity>>> {
+    IdentityFile::from_file(path.to_string_lossy().into_owned())?
+        .into_identities()
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))
+}
+
+/// Returns the path `dest` is stored under when encrypted to public keys
+pub fn encrypted_path(dest: &Path, compressed: bool) -> PathBuf {
+    let mut name = dest.as_os_str().to_os_string();
+    name.push(if compressed { COMPRESSED_SUFFIX } else { SUFFIX });
+    PathBuf::from(name)
+}
+
+/// Returns the original path of a file encrypted to public keys and if it was
+/// compressed, or None if `path` was not encrypted to public keys
+pub fn original_path(path: &Path) -> Option<(PathBuf, bool)> {
+    let path = path.to_str()?;
+
+    if let Some(original) = path.strip_suffix(COMPRESSED_SUFFIX) {
+        Some((PathBuf::from(original), true))
+    } else {
+        path.strip_suffix(SUFFIX).map(|original| (PathBuf::from(original), false))
+    }
+}
+
+fn age_error<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Encrypts `src` into `dest` for every recipient, compressing it first if a
+/// level is given
+pub fn encrypt_file(
+    src: &Path,
+    dest: &mut dyn Write,
+    recipients: &[Recipient],
+    level: Option<i32>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(File::open(src)?);
+
+    let encryptor = Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn age::Recipient))
+        .map_err(age_error)?;
+    let mut stream = encryptor.wrap_output(dest)?;
+
+    match level {
+        Some(level) => {
+            let mut encoder = Encoder::new(stream, level)?;
+            io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?.finish()?.flush()?;
+        }
+        None => {
+            io::copy(&mut reader, &mut stream)?;
+            stream.finish()?.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Decrypts `src` into `dest` with the first identity that fits,
+/// decompressing it if needed. The permissions of `src` are kept.
+pub fn decrypt_file(
+    src: &Path,
+    dest: &Path,
+    identities: &[Box<dyn Identity>],
+    compressed: bool,
+) -> io::Result<u64> {
+    if identities.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The file is encrypted to public keys, pass the private key with --identity",
+        ));
+    }
+
+    let reader = BufReader::new(File::open(src)?);
+    let permissions = reader.get_ref().metadata()?.permissions();
+    let mut decrypted = Decryptor::new_buffered(reader)
+        .and_then(|decryptor| decryptor.decrypt(identities.iter().map(|i| i.as_ref())))
+        .map_err(age_error)?;
+
+    // write to a temporary file, so a failed authentication does not leave a
+    // truncated file behind
+    let mut tmp = dest.as_os_str().to_os_string();
+    tmp.push(".part");
+    let tmp = PathBuf::from(tmp);
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+
+    let result = match compressed {
+        true => Decoder::new(decrypted).and_then(|mut decoder| io::copy(&mut decoder, &mut writer)),
+        false => io::copy(&mut decrypted, &mut writer),
+    }.and_then(|len| writer.flush().map(|_| len));
+    drop(writer);
+
+    match result {
+        Ok(len) => {
+            fs::set_permissions(&tmp, permissions)?;
+            fs::rename(&tmp, dest)?;
+            Ok(len)
+        }
+        Err(error) => {
+            let _ = fs::remove_file(&tmp);
+            Err(error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use age::x25519;
+    use tempfile::TempDir;
+
+    #[test]
+    fn recipients_files() {
+        let dir = TempDir::new().unwrap();
+        let key = x25519::Identity::generate().to_public().to_string();
+        let path = dir.path().join("recipients");
+        fs::write(&path, format!("# the backup key\n\n  {}  \n", key)).unwrap();
+        assert_eq!(read_recipients(&path).unwrap().len(), 1);
+
+        fs::write(&path, "age1nope\n").unwrap();
+        assert!(read_recipients(&path).is_err());
+        assert!(parse_recipient(&key).is_ok());
+    }
+
+    #[test]
+    fn paths() {
+        let dest = Path::new("backup/file");
+        for compressed in [false, true] {
+            let path = encrypted_path(dest, compressed);
+            assert_eq!(original_path(&path), Some((dest.to_path_buf(), compressed)));
+        }
+        assert_eq!(encrypted_path(dest, true), Path::new("backup/file.backr.zst.age"));
+        assert_eq!(original_path(dest), None);
+    }
+
+    #[test]
+    fn files_survive_a_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let (identity, other) = (x25519::Identity::generate(), x25519::Identity::generate());
+        let data = "encrypted to a public key ".repeat(1000);
+        let src = dir.path().join("src");
+        fs::write(&src, &data).unwrap();
+
+        for level in [None, Some(3)] {
+            let stored = dir.path().join("stored");
+            encrypt_file(&src, &mut File::create(&stored).unwrap(), &[identity.to_public()], level).unwrap();
+            assert!(!fs::read(&stored).unwrap().windows(9).any(|window| window == b"encrypted"));
+
+            let restored = dir.path().join("restored");
+            let identities: Vec<Box<dyn Identity>> = vec![Box::new(other.clone()), Box::new(identity.clone())];
+            assert_eq!(decrypt_file(&stored, &restored, &identities, level.is_some()).unwrap(), data.len() as u64);
+            assert_eq!(fs::read_to_string(&restored).unwrap(), data);
+
+            // the wrong key leaves nothing behind
+            fs::remove_file(&restored).unwrap();
+            let wrong: Vec<Box<dyn Identity>> = vec![Box::new(other.clone())];
+            assert!(decrypt_file(&stored, &restored, &wrong, level.is_some()).is_err());
+            assert!(decrypt_file(&stored, &restored, &[], level.is_some()).is_err());
+            assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! Verifies a repository and repairs what can be repaired.
+//!
+//! Every pack is read and every chunk in it is hashed, so a check reads the
+//! whole repository. Missing or damaged chunks are errors. Chunks no snapshot
+//! uses and chunks the index does not know about are only reported, they
+//! take up space but do not harm any backup. Repairing copies the intact
+//! chunks of damaged packs into new packs.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use serde_json;
+
+use super::pack::{self, PackWriter};
+use super::snapshot::Snapshot;
+use super::{Location, Repository, INDEX_DIR, SNAPSHOTS_DIR};
+
+/// Checks the repository and, if `repair` is set, salvages damaged packs,
+/// rebuilds the index from the packs, drops the snapshots that reference missing chunks and removes
+/// the files left behind by interrupted backups. Returns the errors that
+/// were found, and those the repair ran into.
+pub fn check(repo: &Repository, repair: bool, quite: bool) -> io::Result<Vec<String>> {
+    let mut errors = vec![];
+
+    // every intact chunk, where it is stored and how much space it takes
+    let mut chunks: HashMap<String, (Location, u64)> = HashMap::new();
+    let mut intact = HashSet::new();
+    let mut damaged = vec![];
+    let packs = repo.packs()?;
+    for path in &packs {
+        let id = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let scan = match pack::scan(path) {
+            Ok(scan) => scan,
+            Err(error) => {
+                errors.push(format!("Error: Failed to read the pack {:?} \n {}", path, error));
+                continue;
+            }
+        };
+
+        if !scan.problems.is_empty() {
+            for problem in &scan.problems {
+                errors.push(format!("Error: {}", problem));
+            }
+            damaged.push((path.clone(), id.clone(), scan.chunks.clone()));
+        }
+        for (hash, offset, size) in scan.chunks {
+            intact.insert((hash.clone(), id.clone(), offset));
+            chunks.entry(hash.clone()).or_insert((
+                Location {
+                    hash,
+                    pack: id.clone(),
+                    offset,
+                },
+                size,
+            ));
+        }
+    }
+    if quite {
+        say!("** Read {} packs with {} intact chunks", packs.len(), chunks.len());
+    }
+
+    // the index has to point at intact chunks and know every one of them
+    let mut rebuild = false;
+    let mut indexed = HashSet::new();
+    let index_files = repo.list(INDEX_DIR, "json")?;
+    for path in &index_files {
+        let locations = File::open(path).and_then(|file| {
+            serde_json::from_reader::<_, Vec<Location>>(BufReader::new(file))
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+        });
+        let locations = match locations {
+            Ok(locations) => locations,
+            Err(error) => {
+                errors.push(format!("Error: Failed to read the index {:?} \n {}", path, error));
+                rebuild = true;
+                continue;
+            }
+        };
+
+        for location in locations {
+            if intact.contains(&(location.hash.clone(), location.pack.clone(), location.offset)) {
+                indexed.insert(location.hash);
+            } else {
+                errors.push(format!(
+                    "Error: The index points at chunk {} in pack {}, which is missing or damaged",
+                    location.hash, location.pack
+                ));
+                rebuild = true;
+            }
+        }
+    }
+    let unindexed = chunks.keys().filter(|hash| !indexed.contains(*hash)).count();
+    if unindexed > 0 {
+        if quite {
+            say!("** {} chunks are in the packs but not in the index", unindexed);
+        }
+        rebuild = true;
+    }
+
+    // every snapshot needs every one of its chunks
+    let mut used = HashSet::new();
+    let mut broken = vec![];
+    let snapshot_files = repo.list(SNAPSHOTS_DIR, "json")?;
+    for path in &snapshot_files {
+        let snapshot = match Snapshot::load(path) {
+            Ok(snapshot) => snapshot,
+            Err(error) => {
+                errors.push(format!("Error: Failed to read the snapshot {:?} \n {}", path, error));
+                // only a snapshot that can not be parsed is corrupt, one that
+                // could not be read may be fine the next time
+                if error.kind() == io::ErrorKind::InvalidData {
+                    broken.push(path.clone());
+                }
+                continue;
+            }
+        };
+
+        let mut missing = 0;
+        for file in &snapshot.files {
+            for hash in &file.chunks {
+                used.insert(hash.clone());
+                if !chunks.contains_key(hash) {
+                    missing += 1;
+                }
+            }
+        }
+        if missing > 0 {
+            errors.push(format!(
+                "Error: Snapshot {} is missing {} chunks",
+                snapshot.id, missing
+            ));
+            broken.push(path.clone());
+        }
+    }
+    if quite {
+        say!(
+            "** Checked {} snapshots, {} of them are broken",
+            snapshot_files.len(),
+            broken.len()
+        );
+    }
+
+    let (unused, unused_bytes) = chunks
+        .iter()
+        .filter(|(hash, _)| !used.contains(*hash))
+        .fold((0, 0), |(count, bytes), (_, (_, size))| (count + 1, bytes + size));
+    if unused > 0 && quite {
+        say!(
+            "** {} chunks ({} bytes) are not used by any snapshot",
+            unused,
+            unused_bytes
+        );
+    }
+
+    let leftovers = repo.leftovers()?;
+    if !leftovers.is_empty() && quite {
+        say!("** {} files were left behind by interrupted backups", leftovers.len());
+    }
+
+    if !repair {
+        return Ok(errors);
+    }
+
+    // the packs are the source of truth, the rest is rebuilt from them
+    let mut replaced = vec![];
+    for (path, id, intact) in damaged {
+        match salvage(repo, &path, &intact) {
+            Ok((new, offsets)) => {
+                for ((hash, ..), offset) in intact.into_iter().zip(offsets) {
+                    if let Some((location, _)) = chunks.get_mut(&hash) {
+                        if location.pack == id {
+                            location.pack = new.clone();
+                            location.offset = offset;
+                        }
+                    }
+                }
+                if quite {
+                    say!("** Moved the intact chunks of {:?} into pack {}", path, new);
+                }
+                replaced.push(path);
+                rebuild = true;
+            }
+            Err(error) => errors.push(format!("Error: Failed to salvage {:?} \n {}", path, error)),
+        }
+    }
+
+    // the old index and packs are only removed once the new index is saved
+    if rebuild {
+        let locations: Vec<Location> = chunks.values().map(|(location, _)| location.clone()).collect();
+        match repo.save_index(&locations) {
+            Ok(_) => {
+                for path in index_files.iter().chain(replaced.iter()) {
+                    if let Err(error) = fs::remove_file(path) {
+                        errors.push(format!("Error: Failed to remove {:?} \n {}", path, error));
+                    }
+                }
+                if quite {
+                    say!("** Rebuilt the index with {} chunks", locations.len());
+                }
+            }
+            Err(error) => errors.push(format!("Error: Failed to rebuild the index \n {}", error)),
+        }
+    }
+
+    for path in broken.iter().chain(leftovers.iter()) {
+        match fs::remove_file(path) {
+            Ok(_) => {
+                if quite {
+                    say!("** Removed {:?}", path);
+                }
+            }
+            Err(error) => errors.push(format!("Error: Failed to remove {:?} \n {}", path, error)),
+        }
+    }
+
+    Ok(errors)
+}
+
+/// Copies the intact chunks of a damaged pack into a new pack as they are
+/// stored. Returns the id of the new pack and the new offsets of the chunks.
+fn salvage(repo: &Repository, path: &Path, intact: &[(String, u64, u64)]) -> io::Result<(String, Vec<u64>)> {
+    let mut pack = PackWriter::create(repo.root(), None)?;
+    let mut offsets = vec![];
+    for (hash, offset, _) in intact {
+        let (flags, stored) = pack::read_entry(path, *offset, hash)?;
+        offsets.push(pack.add_stored(hash, flags, &stored)?);
+    }
+
+    let id = pack.id.clone();
+    pack.finish()?;
+    Ok((id, offsets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    use repo;
+
+    #[test]
+    fn repair_keeps_what_is_intact() {
+        let (src, root) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (plain, other) = (vec![b'a'; 10_000], b"the other file".to_vec());
+        fs::write(src.path().join("plain"), &plain).unwrap();
+        fs::write(src.path().join("other"), &other).unwrap();
+        let queue = ["plain", "other"]
+            .iter()
+            .map(|name| (src.path().join(name), root.path().join(name)))
+            .collect();
+        assert!(repo::backup(queue, root.path(), src.path(), 1, false, false, None, None).is_empty());
+        let repo = Repository::open(root.path()).unwrap();
+        assert!(check(&repo, false, false).unwrap().is_empty());
+
+        // damage the other chunk
+        let path = repo.packs().unwrap().pop().unwrap();
+        let (_, offset, _) = pack::entries(&path)
+            .unwrap()
+            .into_iter()
+            .find(|(hash, ..)| *hash == pack::hash(&other))
+            .unwrap();
+        let mut data = fs::read(&path).unwrap();
+        data[(offset + pack::ENTRY_HEADER_LEN) as usize] ^= 1;
+        fs::write(&path, data).unwrap();
+
+        // a snapshot that can not be parsed is corrupt, a directory only can
+        // not be read
+        let snapshots = root.path().join(SNAPSHOTS_DIR);
+        fs::write(snapshots.join("corrupt.json"), b"{").unwrap();
+        fs::create_dir(snapshots.join("unreadable.json")).unwrap();
+
+        let errors = check(&repo, true, false).unwrap();
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+        assert!(!path.exists());
+        assert!(!snapshots.join("corrupt.json").exists());
+        assert!(snapshots.join("unreadable.json").exists());
+        fs::remove_dir(snapshots.join("unreadable.json")).unwrap();
+        assert!(repo.snapshots().unwrap().is_empty());
+
+        // the intact chunk of a repository without compression stays plain
+        let location = repo.load_index().unwrap()[&pack::hash(&plain)].clone();
+        let path = pack::pack_path(root.path(), &location.pack);
+        assert_eq!(pack::read_entry(&path, location.offset, &location.hash).unwrap(), (0, plain));
+        assert!(check(&repo, false, false).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,226 @@
+//! Reclaims the space of chunks that no snapshot uses anymore.
+//!
+//! Packs are never changed once written. A pack without any used chunk is
+//! deleted, a pack that is mostly unused is repacked: its used chunks are
+//! copied into a new pack and the old one is deleted. Packs where only a
+//! little is unused are left alone, rewriting them would cost more than it
+//! saves. The new index is saved before anything is deleted, so an
+//! interrupted gc never loses a chunk.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use super::pack::{self, PackWriter, TARGET_SIZE};
+use super::{Location, Repository, INDEX_DIR};
+
+/// Packs with more than this share unused are repacked
+const REPACK_SHARE: u64 = 5;
+
+/// What gc did, or would do on a dry run
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Chunks no snapshot uses anymore
+    pub unused_chunks: u64,
+    /// Packs that are deleted because none of their chunks are used
+    pub deleted_packs: u64,
+    /// Packs whose used chunks are copied into new packs
+    pub repacked_packs: u64,
+    /// Bytes freed by deleting and repacking packs
+    pub reclaimable: u64,
+    /// Unused bytes left in packs that are mostly used
+    pub kept: u64,
+}
+
+/// Finds the chunks no snapshot uses and, unless `dry_run` is set, deletes
+/// them. The caller has to hold the repository lock.
+pub fn gc(repo: &Repository, dry_run: bool, quite: bool) -> io::Result<Report> {
+    let mut report = Report::default();
+
+    // a snapshot that can not be read would have all its chunks collected
+    let mut used = HashSet::new();
+    let snapshots = repo.snapshots()?;
+    for snapshot in &snapshots {
+        used.extend(snapshot.files.iter().flat_map(|file| file.chunks.iter().cloned()));
+    }
+
+    let mut index = repo.load_index()?;
+    if let Some(hash) = used.iter().find(|hash| !index.contains_key(*hash)) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "The chunk {} is used by a snapshot but not in the index, run backr check --repair",
+                hash
+            ),
+        ));
+    }
+    let indexed = index.len();
+    index.retain(|hash, _| used.contains(hash));
+    report.unused_chunks = (indexed - index.len()) as u64;
+
+    // sort the packs into the ones to delete, to repack and to keep
+    let mut delete: Vec<PathBuf> = vec![];
+    let mut repack: Vec<(PathBuf, Vec<(String, u64)>)> = vec![];
+    for path in repo.packs()? {
+        let id = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let total = fs::metadata(&path)?.len();
+
+        let mut live = vec![];
+        let mut live_bytes = pack::HEADER_LEN;
+        for (hash, offset, size) in pack::entries(&path)? {
+            let indexed = index
+                .get(&hash)
+                .map(|location| location.pack == id && location.offset == offset)
+                .unwrap_or(false);
+            if indexed {
+                live.push((hash, offset));
+                live_bytes += size;
+            }
+        }
+
+        let unused = total - live_bytes;
+        if live.is_empty() {
+            report.deleted_packs += 1;
+            report.reclaimable += total;
+            delete.push(path);
+        } else if unused * REPACK_SHARE > total {
+            report.repacked_packs += 1;
+            report.reclaimable += unused;
+            repack.push((path, live));
+        } else {
+            report.kept += unused;
+        }
+    }
+
+    // files of interrupted backups, nothing else is writing while gc runs
+    let leftovers = repo.leftovers()?;
+    for path in &leftovers {
+        report.reclaimable += fs::metadata(path).map(|meta| meta.len()).unwrap_or_default();
+    }
+
+    if quite {
+        say!(
+            "** {} snapshots use {} chunks, {} chunks are unused",
+            snapshots.len(),
+            used.len(),
+            report.unused_chunks
+        );
+        say!(
+            "** {} packs {} deleted and {} {} repacked, freeing {} bytes",
+            report.deleted_packs,
+            if dry_run { "would be" } else { "are" },
+            report.repacked_packs,
+            if dry_run { "would be" } else { "are" },
+            report.reclaimable
+        );
+        if report.kept > 0 {
+            say!("** {} unused bytes are kept in packs that are mostly used", report.kept);
+        }
+    }
+
+    if dry_run {
+        return Ok(report);
+    }
+
+    // copy the used chunks out of the packs that are repacked
+    let mut writer: Option<PackWriter> = None;
+    for (path, live) in &repack {
+        for (hash, offset) in live {
+            if writer.as_ref().map(|writer| writer.size() >= TARGET_SIZE) != Some(false) {
+                if let Some(full) = writer.take() {
+                    full.finish()?;
+                }
+                writer = Some(PackWriter::create(repo.root(), None)?);
+            }
+            let writer = writer.as_mut().unwrap();
+
+            let (flags, stored) = pack::read_entry(path, *offset, hash)?;
+            let new = writer.add_stored(hash, flags, &stored)?;
+            index.insert(
+                hash.clone(),
+                Location {
+                    hash: hash.clone(),
+                    pack: writer.id.clone(),
+                    offset: new,
+                },
+            );
+        }
+    }
+    if let Some(writer) = writer {
+        writer.finish()?;
+    }
+
+    // the new index replaces the old ones before any pack is deleted
+    let index_files = repo.list(INDEX_DIR, "json")?;
+    let locations: Vec<Location> = index.into_values().collect();
+    repo.save_index(&locations)?;
+    for path in index_files
+        .iter()
+        .chain(delete.iter())
+        .chain(repack.iter().map(|(path, _)| path))
+        .chain(leftovers.iter())
+    {
+        fs::remove_file(path)?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use tempfile::TempDir;
+
+    use delta::tests::noise;
+    use repo::{self, SNAPSHOTS_DIR};
+
+    /// Backs up `files` of `src` into the repository as one snapshot
+    fn backup(src: &Path, root: &Path, files: &[&str]) {
+        let queue = files.iter().map(|name| (src.join(name), root.join(name))).collect();
+        assert!(repo::backup(queue, root, src, 1, false, false, None, None).is_empty());
+    }
+
+    fn packs_size(repo: &Repository) -> u64 {
+        repo.packs().unwrap().iter().map(|path| fs::metadata(path).unwrap().len()).sum()
+    }
+
+    #[test]
+    fn dry_runs_count_what_gc_frees() {
+        let (src, root) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        for (seed, name) in ["kept", "dropped", "added", "forgotten"].iter().enumerate() {
+            fs::write(src.path().join(name), noise(100_000, 2 * seed as u64 + 1)).unwrap();
+        }
+        // the first pack ends up half unused and the third one unused
+        backup(src.path(), root.path(), &["kept", "dropped"]);
+        backup(src.path(), root.path(), &["kept", "added"]);
+        backup(src.path(), root.path(), &["forgotten"]);
+
+        let repo = Repository::open(root.path()).unwrap();
+        for snapshot in repo.snapshots().unwrap() {
+            if snapshot.files.iter().any(|file| file.path.ends_with("dropped") || file.path.ends_with("forgotten")) {
+                fs::remove_file(root.path().join(SNAPSHOTS_DIR).join(format!("{}.json", snapshot.id))).unwrap();
+            }
+        }
+        fs::write(root.path().join(SNAPSHOTS_DIR).join("interrupted.tmp"), [0; 100]).unwrap();
+
+        let before = packs_size(&repo);
+        let report = gc(&repo, true, false).unwrap();
+        assert!(report.unused_chunks >= 2);
+        assert_eq!((report.deleted_packs, report.repacked_packs), (1, 1));
+        assert_eq!(packs_size(&repo), before);
+        assert!(root.path().join(SNAPSHOTS_DIR).join("interrupted.tmp").exists());
+
+        // the bytes a dry run counts are the bytes gc frees
+        let freed = gc(&repo, false, false).unwrap();
+        assert_eq!((freed.unused_chunks, freed.reclaimable), (report.unused_chunks, report.reclaimable));
+        assert_eq!(before - packs_size(&repo), report.reclaimable - 100);
+        assert!(repo.leftovers().unwrap().is_empty());
+        let used: HashSet<_> = repo.snapshots().unwrap()[0].files.iter().flat_map(|file| file.chunks.clone()).collect();
+        assert_eq!(repo.load_index().unwrap().len(), used.len());
+        assert_eq!(gc(&repo, true, false).unwrap().reclaimable, 0);
+    }
+}
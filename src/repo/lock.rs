@@ -0,0 +1,156 @@
+//! An exclusive lock on a repository.
+//!
+//! Anything that writes to a repository holds the lock, so gc never deletes a
+//! chunk a running backup has just decided not to store again. The lock is a
+//! file in the repository root that records who holds it. A lock left behind
+//! by a crashed backr on the same host is detected and taken over.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use serde_json;
+
+use super::snapshot;
+
+/// Name of the lock file in the repository root
+pub const LOCK_FILE: &str = "lock";
+
+/// Who holds a lock
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Holder {
+    pid: u32,
+    host: String,
+    time: u64,
+}
+
+/// Held until dropped
+#[derive(Debug)]
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Locks the repository at `root`, or fails if someone else holds the lock
+    pub fn acquire(root: &Path) -> io::Result<Lock> {
+        let path = root.join(LOCK_FILE);
+        let holder = Holder {
+            pid: process::id(),
+            host: hostname(),
+            time: snapshot::now(),
+        };
+
+        // a second attempt is only made after removing a stale lock
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(&serde_json::to_vec(&holder)?)?;
+                    file.sync_all()?;
+                    return Ok(Lock { path });
+                }
+                Err(ref error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                    let other: Option<Holder> = fs::read(&path)
+                        .ok()
+                        .and_then(|data| serde_json::from_slice(&data).ok());
+
+                    match other {
+                        Some(ref other) if other.host == holder.host && !running(other.pid) => {
+                            fs::remove_file(&path)?;
+                        }
+                        Some(other) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::WouldBlock,
+                                format!(
+                                    "The repository is locked by backr {} on {} since {}. \
+                                     Remove {:?} if that backr is no longer running.",
+                                    other.pid,
+                                    other.host,
+                                    snapshot::format_time(other.time),
+                                    path
+                                ),
+                            ))
+                        }
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::WouldBlock,
+                                format!("The repository is locked, remove {:?} if no backr is running", path),
+                            ))
+                        }
+                    }
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!("Failed to lock the repository, remove {:?}", path),
+        ))
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| ::std::env::var("HOSTNAME").ok())
+        .or_else(|| ::std::env::var("COMPUTERNAME").ok())
+        .map(|host| host.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Returns false if there is certainly no process with the id
+#[cfg(target_os = "linux")]
+fn running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn running(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    fn held_by(root: &Path, pid: u32, host: &str) {
+        let holder = Holder {
+            pid,
+            host: host.to_string(),
+            time: snapshot::now(),
+        };
+        fs::write(root.join(LOCK_FILE), serde_json::to_vec(&holder).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn stale_locks_are_taken_over() {
+        let root = TempDir::new().unwrap();
+        let lock = Lock::acquire(root.path()).unwrap();
+        assert_eq!(Lock::acquire(root.path()).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        drop(lock);
+        assert!(!root.path().join(LOCK_FILE).exists());
+
+        // a backr that is gone on this host left the lock behind
+        held_by(root.path(), u32::MAX, &hostname());
+        drop(Lock::acquire(root.path()).unwrap());
+
+        // one that is running, or on another host, or a lock that can not
+        // be read, is left alone
+        held_by(root.path(), process::id(), &hostname());
+        assert!(Lock::acquire(root.path()).is_err());
+        held_by(root.path(), u32::MAX, "elsewhere");
+        assert!(Lock::acquire(root.path()).unwrap_err().to_string().contains("elsewhere"));
+        fs::write(root.path().join(LOCK_FILE), b"{").unwrap();
+        assert!(Lock::acquire(root.path()).is_err());
+        assert!(root.path().join(LOCK_FILE).exists());
+    }
+}
@@ -0,0 +1,673 @@
+//! A content addressed, deduplicating repository.
+//!
+//! Instead of copying whole files, each file is split into chunks with
+//! content defined chunking (FastCDC), so an edit only changes the chunks
+//! around it and a renamed or duplicated file does not change any. Every
+//! chunk is stored once, by its sha256, in a pack file. Each backup records a
+//! snapshot listing its files and their chunks. The layout of a repository:
+//!
+//! ```text
+//! config                  the chunker settings and the retention policy
+//! packs/<xx>/<id>.pack    the chunks, see pack.rs
+//! index/<id>.json         where each chunk is stored, one file per backup
+//! snapshots/<id>.json     the files of each backup
+//! lock                    held by whoever is writing, see lock.rs
+//! ```
+
+pub mod check;
+pub mod gc;
+pub mod lock;
+pub mod pack;
+pub mod prune;
+pub mod snapshot;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{self, UNIX_EPOCH};
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use fastcdc::v2020::StreamCDC;
+use filetime::{self, FileTime};
+use progress::Bar;
+use serde_json;
+
+use compress::Compression;
+use throttle::Throttle;
+
+use self::lock::Lock;
+use self::pack::PackWriter;
+use self::prune::Policy;
+use self::snapshot::Snapshot;
+
+pub const CONFIG_FILE: &str = "config";
+pub const PACKS_DIR: &str = "packs";
+pub const INDEX_DIR: &str = "index";
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+
+const VERSION: u32 = 1;
+
+/// Returns a random 16 character hex id
+pub fn random_id() -> String {
+    let mut id = [0; 8];
+    OsRng.fill_bytes(&mut id);
+    hex::encode(id)
+}
+
+fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// The settings a repository is created with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    pub version: u32,
+    pub min_chunk: u32,
+    pub avg_chunk: u32,
+    pub max_chunk: u32,
+    /// Applied after every backup without errors, see prune.rs
+    #[serde(default, skip_serializing_if = "Policy::is_empty")]
+    pub keep: Policy,
+}
+
+/// Where a chunk is stored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Location {
+    pub hash: String,
+    pub pack: String,
+    pub offset: u64,
+}
+
+/// An open repository
+#[derive(Debug)]
+pub struct Repository {
+    root: PathBuf,
+    config: Config,
+}
+
+impl Repository {
+    /// Returns true if `root` holds a repository
+    pub fn exists(root: &Path) -> bool {
+        root.join(CONFIG_FILE).is_file()
+    }
+
+    /// Opens an existing repository
+    pub fn open(root: &Path) -> io::Result<Repository> {
+        let config: Config = serde_json::from_slice(&fs::read(root.join(CONFIG_FILE))?)
+            .map_err(invalid)?;
+        if config.version != VERSION {
+            return Err(invalid("The repository was created by a newer version of backr"));
+        }
+
+        Ok(Repository {
+            root: root.to_path_buf(),
+            config,
+        })
+    }
+
+    /// Opens the repository at `root`, creating it if it does not exist yet
+    pub fn open_or_init(root: &Path) -> io::Result<Repository> {
+        if Repository::exists(root) {
+            return Repository::open(root);
+        }
+
+        for dir in &[PACKS_DIR, INDEX_DIR, SNAPSHOTS_DIR] {
+            DirBuilder::new().recursive(true).create(root.join(dir))?;
+        }
+
+        let config = Config {
+            version: VERSION,
+            min_chunk: 512 * 1024,
+            avg_chunk: 1024 * 1024,
+            max_chunk: 8 * 1024 * 1024,
+            keep: Policy::default(),
+        };
+
+        let repo = Repository {
+            root: root.to_path_buf(),
+            config,
+        };
+        repo.save_config()?;
+        Ok(repo)
+    }
+
+    /// Returns the path of the repository
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the settings of the repository
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Changes the retention policy and saves it in the config
+    pub fn set_policy(&mut self, policy: Policy) -> io::Result<()> {
+        self.config.keep = policy;
+        self.save_config()
+    }
+
+    fn save_config(&self) -> io::Result<()> {
+        write_atomic(
+            &self.root.join(CONFIG_FILE),
+            &serde_json::to_vec_pretty(&self.config).map_err(invalid)?,
+        )
+    }
+
+    /// Returns the paths of the files in one of the repository directories
+    pub fn list(&self, dir: &str, ext: &str) -> io::Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        for entry in fs::read_dir(self.root.join(dir))? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some(ext) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Returns the paths of every finished pack
+    pub fn packs(&self) -> io::Result<Vec<PathBuf>> {
+        self.pack_files("pack")
+    }
+
+    /// Returns the temporary files left behind by interrupted backups
+    pub fn leftovers(&self) -> io::Result<Vec<PathBuf>> {
+        let mut paths = self.pack_files("tmp")?;
+        paths.extend(self.list(INDEX_DIR, "tmp")?);
+        paths.extend(self.list(SNAPSHOTS_DIR, "tmp")?);
+        Ok(paths)
+    }
+
+    fn pack_files(&self, ext: &str) -> io::Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        for entry in fs::read_dir(self.root.join(PACKS_DIR))? {
+            let dir = entry?.path();
+            if dir.is_dir() {
+                for entry in fs::read_dir(dir)? {
+                    let path = entry?.path();
+                    if path.extension().and_then(|e| e.to_str()) == Some(ext) {
+                        paths.push(path);
+                    }
+                }
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Loads every index file into a single map from hash to location
+    pub fn load_index(&self) -> io::Result<HashMap<String, Location>> {
+        let mut index = HashMap::new();
+        for path in self.list(INDEX_DIR, "json")? {
+            let locations: Vec<Location> =
+                serde_json::from_reader(BufReader::new(File::open(&path)?)).map_err(invalid)?;
+            for location in locations {
+                index.insert(location.hash.clone(), location);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Writes the locations of the chunks added by a backup as a new index file
+    pub fn save_index(&self, locations: &[Location]) -> io::Result<()> {
+        if locations.is_empty() {
+            return Ok(());
+        }
+        let path = self.root.join(INDEX_DIR).join(format!("{}.json", random_id()));
+        write_atomic(&path, &serde_json::to_vec(locations).map_err(invalid)?)
+    }
+
+    /// Loads every snapshot, oldest first
+    pub fn snapshots(&self) -> io::Result<Vec<Snapshot>> {
+        let mut snapshots = vec![];
+        for path in self.list(SNAPSHOTS_DIR, "json")? {
+            snapshots.push(Snapshot::load(&path)?);
+        }
+        snapshots.sort_by_key(|snapshot| snapshot.time);
+        Ok(snapshots)
+    }
+
+    /// Finds a snapshot by its id, a prefix of its id or `latest`
+    pub fn find_snapshot(&self, id: &str) -> io::Result<Snapshot> {
+        let snapshots = self.snapshots()?;
+        let found = match id {
+            "latest" => snapshots.last().cloned(),
+            _ => {
+                let mut matches = snapshots.iter().filter(|snapshot| snapshot.id.starts_with(id));
+                match (matches.next(), matches.next()) {
+                    (Some(snapshot), None) => Some(snapshot.clone()),
+                    (Some(_), Some(_)) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("{:?} matches more than one snapshot", id),
+                        ))
+                    }
+                    _ => None,
+                }
+            }
+        };
+
+        found.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("There is no snapshot {:?}", id),
+            )
+        })
+    }
+
+    /// Writes a snapshot into the repository
+    pub fn save_snapshot(&self, snapshot: &Snapshot) -> io::Result<()> {
+        let path = self
+            .root
+            .join(SNAPSHOTS_DIR)
+            .join(format!("{}.json", snapshot.id));
+        write_atomic(&path, &serde_json::to_vec_pretty(snapshot).map_err(invalid)?)
+    }
+
+    /// Reads a chunk back from its pack
+    pub fn read_chunk(&self, location: &Location) -> io::Result<Vec<u8>> {
+        pack::read_chunk(
+            &pack::pack_path(&self.root, &location.pack),
+            location.offset,
+            &location.hash,
+        )
+    }
+}
+
+/// Writes to a temporary file first, so a crash never leaves half a file
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(tmp, path)
+}
+
+/// The permission bits of a file, 0 where there are none
+#[cfg(unix)]
+fn mode(meta: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode()
+}
+
+#[cfg(not(unix))]
+fn mode(_meta: &fs::Metadata) -> u32 {
+    0
+}
+
+/// Chunks a single file, adding the chunks the repository does not know yet
+/// to the pack. `parent` is the file in the previous snapshot, if any.
+#[allow(clippy::too_many_arguments)]
+fn backup_file(
+    repo: &Repository,
+    src: &Path,
+    name: String,
+    parent: Option<&snapshot::File>,
+    known: &Mutex<HashSet<String>>,
+    pack: &mut PackWriter,
+    level: Option<i32>,
+    locations: &mut Vec<Location>,
+) -> io::Result<snapshot::File> {
+    let file = File::open(src)?;
+    let meta = file.metadata()?;
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map(|mtime| mtime.as_secs())
+        .unwrap_or_default();
+
+    // an unchanged file is not read again
+    if let Some(parent) = parent {
+        if parent.size == meta.len() && parent.mtime == mtime {
+            return Ok(snapshot::File {
+                path: name,
+                mode: mode(&meta),
+                ..parent.clone()
+            });
+        }
+    }
+
+    let mut chunks = vec![];
+
+    let chunker = StreamCDC::new(
+        BufReader::new(file),
+        repo.config.min_chunk,
+        repo.config.avg_chunk,
+        repo.config.max_chunk,
+    );
+
+    for chunk in chunker {
+        let chunk = chunk.map_err(io::Error::from)?;
+        let hash = pack::hash(&chunk.data);
+
+        // only the first thread to see a chunk stores it
+        if known.lock().unwrap().insert(hash.clone()) {
+            let offset = match pack.add(&hash, &chunk.data, level) {
+                Ok(offset) => offset,
+                Err(error) => {
+                    // the next file with this chunk stores it instead
+                    known.lock().unwrap().remove(&hash);
+                    return Err(error);
+                }
+            };
+            locations.push(Location {
+                hash: hash.clone(),
+                pack: pack.id.clone(),
+                offset,
+            });
+        }
+        chunks.push(hash);
+    }
+
+    Ok(snapshot::File {
+        path: name,
+        size: meta.len(),
+        mtime,
+        mode: mode(&meta),
+        chunks,
+    })
+}
+
+/// Backs up the queue into the repository at `repo_root` and records it as a
+/// new snapshot. File names are the destination paths relative to
+/// `repo_root`, so they start with the name of the source directory like
+/// they do in archives. Chunks are written by `threads`
+/// threads, each into their own pack.
+#[allow(clippy::too_many_arguments)]
+pub fn backup(
+    queue: Vec<(PathBuf, PathBuf)>,
+    repo_root: &Path,
+    source: &Path,
+    threads: i32,
+    progress: bool,
+    quite: bool,
+    compress: Option<Compression>,
+    throttle: Option<Arc<Throttle>>,
+) -> Vec<String> {
+    // the lock is taken before a new repository is created, so two first
+    // backups do not create it at the same time
+    let locked = DirBuilder::new().recursive(true).create(repo_root).and_then(|_| Lock::acquire(repo_root));
+    let (repo, _lock, index, parent) = match locked.and_then(|lock| {
+        let repo = Repository::open_or_init(repo_root)?;
+        let index = repo.load_index()?;
+        let parent = repo.snapshots()?.pop();
+        Ok((repo, lock, index, parent))
+    }) {
+        Ok((repo, lock, index, parent)) => (Arc::new(repo), lock, index, parent),
+        Err(error) => {
+            return vec![format!(
+                "Error: Failed to open the repository {:?} \n {}",
+                repo_root, error
+            )]
+        }
+    };
+
+    if quite {
+        say!("** Starting backup into the repository {:?}", repo_root);
+    }
+
+    // files of the last snapshot whose chunks are all still indexed
+    let parent: HashMap<String, snapshot::File> = parent
+        .map(|parent| parent.files)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|file| file.chunks.iter().all(|hash| index.contains_key(hash)))
+        .map(|file| (file.path.clone(), file))
+        .collect();
+    let parent = Arc::new(parent);
+
+    let total = queue.len();
+    let indexed: HashSet<String> = index.into_keys().collect();
+    let known = Arc::new(Mutex::new(indexed.clone()));
+    let queue_mutex = Arc::new(Mutex::new(queue.into_iter()));
+    let files_mutex = Arc::new(Mutex::new(vec![]));
+    let locations_mutex = Arc::new(Mutex::new(vec![]));
+    let errors_mutex = Arc::new(Mutex::new(Vec::<String>::new()));
+    let completed_mutex = Arc::new(Mutex::new(0));
+    let root = repo_root.to_path_buf();
+    let level = compress.map(|compression| compression.level);
+
+    let mut handles = vec![];
+    for _ in 0..threads {
+        let (repo, parent, known, queue, files, locations, errors, completed, throttle, root) = (
+            repo.clone(),
+            parent.clone(),
+            known.clone(),
+            queue_mutex.clone(),
+            files_mutex.clone(),
+            locations_mutex.clone(),
+            errors_mutex.clone(),
+            completed_mutex.clone(),
+            throttle.clone(),
+            root.clone(),
+        );
+
+        handles.push(thread::spawn(move || {
+            let mut local_files = vec![];
+            let mut local_locations = vec![];
+            let mut local_errors = vec![];
+            let mut pack: Option<PackWriter> = None;
+
+            loop {
+                let next = queue.lock().unwrap().next();
+                let (src, dest) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+
+                // start a new pack when there is none or the last one is full
+                if pack.as_ref().map(|pack| pack.size() >= pack::TARGET_SIZE) != Some(false) {
+                    if let Some(full) = pack.take() {
+                        let id = full.id.clone();
+                        if let Err(error) = full.finish() {
+                            local_errors.push(format!("Error: Failed to write a pack \n {}", error));
+                            local_locations.retain(|l: &Location| l.pack != id);
+                        }
+                    }
+                    match PackWriter::create(repo.root(), throttle.as_deref()) {
+                        Ok(new) => pack = Some(new),
+                        Err(error) => {
+                            local_errors.push(format!("Error: Failed to create a pack \n {}", error));
+                            break;
+                        }
+                    }
+                }
+
+                let name = dest
+                    .strip_prefix(&root)
+                    .unwrap_or(&dest)
+                    .to_string_lossy()
+                    .into_owned();
+                let mut chunk_locations = vec![];
+
+                match backup_file(
+                    &repo,
+                    &src,
+                    name.clone(),
+                    parent.get(&name),
+                    &known,
+                    pack.as_mut().unwrap(),
+                    level,
+                    &mut chunk_locations,
+                ) {
+                    Ok(file) => local_files.push(file),
+                    Err(error) => {
+                        if quite {
+                            say!("{}", &error);
+                        }
+                        local_errors.push(format!(
+                            "Error: Failed to backup {:?} -> {:?} \n {}",
+                            src, dest, error
+                        ));
+                        // chunks of a failed file are still in the pack and
+                        // still usable, so they are kept
+                    }
+                }
+                local_locations.extend(chunk_locations);
+
+                *completed.lock().unwrap() += 1;
+            }
+
+            if let Some(pack) = pack {
+                match local_locations.iter().any(|l: &Location| l.pack == pack.id) {
+                    true => {
+                        let id = pack.id.clone();
+                        if let Err(error) = pack.finish() {
+                            local_errors.push(format!("Error: Failed to write a pack \n {}", error));
+                            local_locations.retain(|l| l.pack != id);
+                        }
+                    }
+                    false => pack.abort(),
+                }
+            }
+
+            files.lock().unwrap().extend(local_files);
+            locations.lock().unwrap().extend(local_locations);
+            errors.lock().unwrap().extend(local_errors);
+        }));
+    }
+
+    if progress {
+        let mut bar = Bar::new();
+        bar.set_job_title("Backup");
+
+        loop {
+            let completed = *completed_mutex.lock().unwrap();
+            let percent = ((completed as f32 / total as f32) * 100.0) as i32;
+            bar.reach_percent(percent);
+
+            // a thread that ran into an error leaves files in the queue
+            if percent >= 100 || handles.iter().all(|handle| handle.is_finished()) {
+                break;
+            }
+            thread::sleep(time::Duration::from_secs(5));
+        }
+    }
+
+    handles.into_iter().for_each(|handle| {
+        handle.join().unwrap();
+    });
+
+    let mut errors = Arc::try_unwrap(errors_mutex).unwrap().into_inner().unwrap();
+    let locations = Arc::try_unwrap(locations_mutex).unwrap().into_inner().unwrap();
+    let mut files = Arc::try_unwrap(files_mutex).unwrap().into_inner().unwrap();
+    files.sort_by(|a: &snapshot::File, b| a.path.cmp(&b.path));
+
+    // a file whose chunks are in no pack, because its pack failed to be
+    // written or another thread failed to add them, could not be restored
+    let stored: HashSet<&String> = indexed.iter().chain(locations.iter().map(|l| &l.hash)).collect();
+    files.retain(|file| {
+        let complete = file.chunks.iter().all(|hash| stored.contains(hash));
+        if !complete {
+            errors.push(format!(
+                "Error: Failed to backup {:?} \n The pack holding its chunks could not be written",
+                file.path
+            ));
+        }
+        complete
+    });
+
+    // the packs are on disk, now the index, and last the snapshot that
+    // references them
+    let snapshot = Snapshot {
+        id: random_id(),
+        time: snapshot::now(),
+        source: source.to_string_lossy().into_owned(),
+        files,
+    };
+    match repo
+        .save_index(&locations)
+        .and_then(|_| repo.save_snapshot(&snapshot))
+    {
+        Ok(_) => {
+            if quite {
+                say!(
+                    "** Saved snapshot {} with {} new chunks",
+                    snapshot.id,
+                    locations.len()
+                );
+            }
+        }
+        Err(error) => errors.push(format!("Error: Failed to save the snapshot \n {}", error)),
+    }
+
+    errors
+}
+
+/// Restores a snapshot into `dest`, keeping the permissions and mtimes of
+/// the files
+pub fn restore(repo: &Repository, id: &str, dest: &Path, quite: bool) -> Vec<String> {
+    let (snapshot, index) = match repo
+        .find_snapshot(id)
+        .and_then(|snapshot| Ok((snapshot, repo.load_index()?)))
+    {
+        Ok(found) => found,
+        Err(error) => return vec![format!("Error: Failed to read the repository \n {}", error)],
+    };
+
+    if quite {
+        say!(
+            "** Restoring snapshot {} from {} into {:?}",
+            snapshot.id,
+            snapshot::format_time(snapshot.time),
+            dest
+        );
+    }
+
+    let mut errors = vec![];
+    for file in &snapshot.files {
+        let path = dest.join(&file.path);
+        if let Err(error) = restore_file(repo, &index, file, &path) {
+            if quite {
+                say!("{}", &error);
+            }
+            errors.push(format!("Error: Failed to restore {:?} \n {}", path, error));
+        }
+    }
+
+    if quite {
+        say!("** Files Restored: {}", snapshot.files.len() - errors.len());
+        say!("** Total errors {}", errors.len());
+    }
+
+    errors
+}
+
+fn restore_file(
+    repo: &Repository,
+    index: &HashMap<String, Location>,
+    file: &snapshot::File,
+    path: &Path,
+) -> io::Result<()> {
+    DirBuilder::new().recursive(true).create(path.parent().unwrap())?;
+
+    let mut out = File::create(path)?;
+    for hash in &file.chunks {
+        let location = index.get(hash).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("The chunk {} is missing from the repository", hash),
+            )
+        })?;
+        out.write_all(&repo.read_chunk(location)?)?;
+    }
+    drop(out);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(file.mode))?;
+    }
+    filetime::set_file_mtime(path, FileTime::from_unix_time(file.mtime as i64, 0))
+}
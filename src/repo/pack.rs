@@ -0,0 +1,368 @@
+//! Pack files hold the chunks of a repository.
+//!
+//! A pack starts with a magic number and is followed by entries that each
+//! describe themselves, so the index can always be rebuilt from the packs:
+//!
+//! ```text
+//! "BACKRPCK" | version | (sha256 (32) | flags (1) | length u32 BE | data)*
+//! ```
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use zstd::stream::{decode_all, encode_all};
+
+use throttle::{Throttle, Throttled};
+
+const MAGIC: &[u8; 8] = b"BACKRPCK";
+const VERSION: u8 = 1;
+
+/// Length of the pack header
+pub const HEADER_LEN: u64 = 9;
+
+/// Length of the header in front of every chunk
+pub const ENTRY_HEADER_LEN: u64 = 32 + 1 + 4;
+
+/// Set in the entry flags when the chunk is zstd compressed
+pub const FLAG_COMPRESSED: u8 = 1;
+
+/// Packs are closed once they grow past this size
+pub const TARGET_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Returns the hex encoded sha256 of `data`
+pub fn hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Returns the path of a pack inside the repository
+pub fn pack_path(root: &Path, id: &str) -> PathBuf {
+    root.join(super::PACKS_DIR)
+        .join(&id[..2])
+        .join(format!("{}.pack", id))
+}
+
+/// Writes a new pack. The pack is written under a temporary name and only
+/// shows up in the repository once `finish` is called.
+pub struct PackWriter<'a> {
+    pub id: String,
+    path: PathBuf,
+    tmp: PathBuf,
+    writer: Throttled<'a, BufWriter<File>>,
+    size: u64,
+}
+
+impl<'a> PackWriter<'a> {
+    /// Starts a new pack with a random id
+    pub fn create(root: &Path, throttle: Option<&'a Throttle>) -> io::Result<PackWriter<'a>> {
+        let id = super::random_id();
+        let path = pack_path(root, &id);
+        fs::create_dir_all(path.parent().unwrap())?;
+
+        let mut tmp = path.as_os_str().to_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let mut writer = Throttled::new(BufWriter::new(File::create(&tmp)?), throttle);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+
+        Ok(PackWriter {
+            id,
+            path,
+            tmp,
+            writer,
+            size: HEADER_LEN,
+        })
+    }
+
+    /// Returns the number of bytes written so far
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Appends a chunk, compressing it if a level is given and it actually
+    /// gets smaller. Returns the offset of the new entry.
+    pub fn add(&mut self, hash: &str, data: &[u8], level: Option<i32>) -> io::Result<u64> {
+        let compressed = match level {
+            Some(level) => Some(encode_all(data, level)?).filter(|c| c.len() < data.len()),
+            None => None,
+        };
+        match compressed {
+            Some(ref compressed) => self.add_stored(hash, FLAG_COMPRESSED, compressed),
+            None => self.add_stored(hash, 0, data),
+        }
+    }
+
+    /// Appends a chunk as it is stored in another pack, see `read_entry`
+    pub fn add_stored(&mut self, hash: &str, flags: u8, stored: &[u8]) -> io::Result<u64> {
+        let offset = self.size;
+        self.writer.write_all(&hex::decode(hash).unwrap())?;
+        self.writer.write_all(&[flags])?;
+        self.writer.write_all(&(stored.len() as u32).to_be_bytes())?;
+        self.writer.write_all(stored)?;
+        self.size += ENTRY_HEADER_LEN + stored.len() as u64;
+
+        Ok(offset)
+    }
+
+    /// Syncs the pack to disk and moves it into place
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        drop(self.writer);
+        File::open(&self.tmp)?.sync_all()?;
+        fs::rename(&self.tmp, &self.path)
+    }
+
+    /// Throws away an unfinished pack
+    pub fn abort(self) {
+        drop(self.writer);
+        let _ = fs::remove_file(&self.tmp);
+    }
+}
+
+fn corrupt(path: &Path, what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{:?} is corrupt: {}", path, what),
+    )
+}
+
+/// Reads the entry at `offset` as it is stored, returning its flags and data
+pub fn read_entry(path: &Path, offset: u64, expected: &str) -> io::Result<(u8, Vec<u8>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    reader.seek(SeekFrom::Start(offset))?;
+
+    let mut header = [0; ENTRY_HEADER_LEN as usize];
+    reader
+        .read_exact(&mut header)
+        .map_err(|_| corrupt(path, "unexpected end"))?;
+    if hex::encode(&header[..32]) != expected {
+        return Err(corrupt(path, &format!("chunk {} is not at {}", expected, offset)));
+    }
+
+    let mut len = [0; 4];
+    len.copy_from_slice(&header[33..]);
+
+    let mut stored = vec![0; u32::from_be_bytes(len) as usize];
+    reader
+        .read_exact(&mut stored)
+        .map_err(|_| corrupt(path, "truncated chunk"))?;
+
+    Ok((header[32], stored))
+}
+
+/// Reads the chunk at `offset` and verifies it is the chunk with `expected`
+/// as its hash
+pub fn read_chunk(path: &Path, offset: u64, expected: &str) -> io::Result<Vec<u8>> {
+    let (flags, stored) = read_entry(path, offset, expected)?;
+
+    let data = match flags & FLAG_COMPRESSED {
+        0 => stored,
+        _ => decode_all(stored.as_slice()).map_err(|_| corrupt(path, "bad compression"))?,
+    };
+
+    if hash(&data) != expected {
+        return Err(corrupt(path, &format!("chunk {} does not match its hash", expected)));
+    }
+
+    Ok(data)
+}
+
+/// Lists the hash, offset and stored size of every entry by only reading
+/// the entry headers. Unlike `scan` nothing is verified.
+pub fn entries(path: &Path) -> io::Result<Vec<(String, u64, u64)>> {
+    let mut file = File::open(path)?;
+    let end = file.metadata()?.len();
+    let mut reader = BufReader::new(&mut file);
+
+    let mut header = [0; HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+        return Err(corrupt(path, "not a pack"));
+    }
+
+    let mut entries = vec![];
+    let mut offset = HEADER_LEN;
+    while offset < end {
+        let mut entry = [0; ENTRY_HEADER_LEN as usize];
+        reader
+            .read_exact(&mut entry)
+            .map_err(|_| corrupt(path, "unexpected end"))?;
+
+        let mut len = [0; 4];
+        len.copy_from_slice(&entry[33..]);
+        let size = ENTRY_HEADER_LEN + u64::from(u32::from_be_bytes(len));
+
+        entries.push((hex::encode(&entry[..32]), offset, size));
+        offset += size;
+        reader.seek_relative((size - ENTRY_HEADER_LEN) as i64)?;
+    }
+    if offset > end {
+        return Err(corrupt(path, "truncated chunk"));
+    }
+
+    Ok(entries)
+}
+
+/// What scanning a pack found
+#[derive(Debug, Default)]
+pub struct Scan {
+    /// The hash, offset and stored size of every intact chunk
+    pub chunks: Vec<(String, u64, u64)>,
+    /// Everything that is wrong with the pack
+    pub problems: Vec<String>,
+}
+
+/// Reads a whole pack and verifies every chunk in it. Scanning stops at the
+/// first entry that can not be read, the chunks before it are still usable.
+pub fn scan(path: &Path) -> io::Result<Scan> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut scan = Scan::default();
+
+    let mut header = [0; HEADER_LEN as usize];
+    if reader.read_exact(&mut header).is_err() || &header[..8] != MAGIC {
+        scan.problems.push(format!("{:?} is not a pack", path));
+        return Ok(scan);
+    }
+    if header[8] != VERSION {
+        scan.problems.push(format!("{:?} has an unknown version {}", path, header[8]));
+        return Ok(scan);
+    }
+
+    let mut offset = HEADER_LEN;
+    loop {
+        let mut entry = [0; ENTRY_HEADER_LEN as usize];
+        match reader.read(&mut entry[..1])? {
+            0 => break,
+            _ => {
+                if reader.read_exact(&mut entry[1..]).is_err() {
+                    scan.problems.push(corrupt(path, "unexpected end").to_string());
+                    break;
+                }
+            }
+        }
+
+        let expected = hex::encode(&entry[..32]);
+        let mut len = [0; 4];
+        len.copy_from_slice(&entry[33..]);
+        let len = u32::from_be_bytes(len) as u64;
+
+        let mut stored = vec![0; len as usize];
+        if reader.read_exact(&mut stored).is_err() {
+            scan.problems.push(corrupt(path, "truncated chunk").to_string());
+            break;
+        }
+
+        let data = match entry[32] & FLAG_COMPRESSED {
+            0 => Ok(stored),
+            _ => decode_all(stored.as_slice()),
+        };
+        match data {
+            Ok(ref data) if hash(data) == expected => {
+                scan.chunks.push((expected, offset, ENTRY_HEADER_LEN + len))
+            }
+            _ => scan.problems.push(
+                corrupt(path, &format!("chunk {} does not match its hash", expected)).to_string(),
+            ),
+        }
+
+        offset += ENTRY_HEADER_LEN + len;
+    }
+
+    Ok(scan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    /// Writes a pack holding `chunks` and returns its path with the hash and
+    /// offset of every chunk
+    fn write_pack(root: &Path, chunks: &[&[u8]], level: Option<i32>) -> (PathBuf, Vec<(String, u64)>) {
+        let mut writer = PackWriter::create(root, None).unwrap();
+        let added = chunks
+            .iter()
+            .map(|chunk| {
+                let hash = hash(chunk);
+                let offset = writer.add(&hash, chunk, level).unwrap();
+                (hash, offset)
+            }).collect();
+        let path = pack_path(root, &writer.id);
+        writer.finish().unwrap();
+        (path, added)
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new().unwrap();
+        let big = vec![b'a'; 100_000];
+        let chunks: [&[u8]; 3] = [b"first", &big, b""];
+
+        for &level in &[None, Some(3)] {
+            let (path, added) = write_pack(dir.path(), &chunks, level);
+            for (chunk, (hash, offset)) in chunks.iter().zip(&added) {
+                assert_eq!(read_chunk(&path, *offset, hash).unwrap(), *chunk);
+            }
+
+            let listed = entries(&path).unwrap();
+            let scanned = scan(&path).unwrap();
+            assert!(scanned.problems.is_empty(), "{:?}", scanned.problems);
+            assert_eq!(scanned.chunks, listed);
+            assert_eq!(listed.iter().map(|e| (e.0.clone(), e.1)).collect::<Vec<_>>(), added);
+            assert_eq!(
+                HEADER_LEN + listed.iter().map(|e| e.2).sum::<u64>(),
+                fs::metadata(&path).unwrap().len()
+            );
+        }
+    }
+
+    #[test]
+    fn compression_only_when_smaller() {
+        let dir = TempDir::new().unwrap();
+        let big = vec![b'a'; 100_000];
+        let (path, added) = write_pack(dir.path(), &[b"x", &big], Some(3));
+        assert_eq!(read_entry(&path, added[0].1, &added[0].0).unwrap(), (0, b"x".to_vec()));
+        let (flags, stored) = read_entry(&path, added[1].1, &added[1].0).unwrap();
+        assert_eq!(flags, FLAG_COMPRESSED);
+        assert!(stored.len() < big.len());
+    }
+
+    #[test]
+    fn wrong_offset_and_damage() {
+        let dir = TempDir::new().unwrap();
+        let (path, added) = write_pack(dir.path(), &[b"first", b"second"], None);
+        assert!(read_chunk(&path, added[1].1, &added[0].0).is_err());
+
+        // flip a byte of the second chunk
+        let mut contents = fs::read(&path).unwrap();
+        let last = contents.len() - 1;
+        contents[last] ^= 1;
+        fs::write(&path, &contents).unwrap();
+        assert!(read_chunk(&path, added[1].1, &added[1].0).is_err());
+        let scanned = scan(&path).unwrap();
+        assert_eq!(scanned.chunks.len(), 1);
+        assert_eq!(scanned.problems.len(), 1);
+
+        // cut the second chunk short
+        fs::write(&path, &contents[..last]).unwrap();
+        assert!(entries(&path).is_err());
+        let scanned = scan(&path).unwrap();
+        assert_eq!(scanned.chunks.len(), 1);
+        assert_eq!(scanned.problems.len(), 1);
+    }
+
+    #[test]
+    fn aborted_pack_leaves_nothing() {
+        let dir = TempDir::new().unwrap();
+        let mut writer = PackWriter::create(dir.path(), None).unwrap();
+        writer.add(&hash(b"chunk"), b"chunk", None).unwrap();
+        let path = pack_path(dir.path(), &writer.id);
+        writer.abort();
+        assert!(!path.exists());
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 0);
+    }
+}
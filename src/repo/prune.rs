@@ -0,0 +1,239 @@
+//! Retention policies, which decide the snapshots worth keeping.
+//!
+//! Snapshots are walked from the newest to the oldest. `last` keeps the
+//! newest snapshots, `daily`, `weekly` and `monthly` keep the newest snapshot
+//! of that many days, weeks and months that have a snapshot. A snapshot is
+//! kept if any rule keeps it. Days, weeks and months are in UTC and weeks
+//! start on monday.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::gc;
+use super::lock::Lock;
+use super::snapshot::{self, Snapshot};
+use super::{Repository, SNAPSHOTS_DIR};
+
+/// How many snapshots of each kind to keep. Zero keeps none of that kind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    pub last: u32,
+    pub daily: u32,
+    pub weekly: u32,
+    pub monthly: u32,
+}
+
+impl Policy {
+    /// Returns true if the policy keeps nothing, which is never applied
+    pub fn is_empty(&self) -> bool {
+        *self == Policy::default()
+    }
+
+    /// Returns the ids of the snapshots the policy keeps
+    pub fn keep(&self, snapshots: &[Snapshot]) -> HashSet<String> {
+        let mut newest_first: Vec<&Snapshot> = snapshots.iter().collect();
+        newest_first.sort_by_key(|snapshot| ::std::cmp::Reverse(snapshot.time));
+
+        let mut keep = HashSet::new();
+        keep.extend(
+            newest_first
+                .iter()
+                .take(self.last as usize)
+                .map(|snapshot| snapshot.id.clone()),
+        );
+
+        let rules: [(u32, Period); 3] = [
+            (self.daily, day),
+            (self.weekly, week),
+            (self.monthly, month),
+        ];
+        for (count, period) in rules.iter() {
+            let mut last = None;
+            let mut kept = 0;
+            for snapshot in &newest_first {
+                if kept == *count {
+                    break;
+                }
+                let current = period(snapshot.time);
+                if last != Some(current) {
+                    keep.insert(snapshot.id.clone());
+                    last = Some(current);
+                    kept += 1;
+                }
+            }
+        }
+
+        keep
+    }
+}
+
+/// Maps a time to the day, week or month it falls in
+type Period = fn(u64) -> i64;
+
+fn day(time: u64) -> i64 {
+    (time / 86_400) as i64
+}
+
+// the epoch was a thursday, moving it by three days makes weeks start on
+// monday
+fn week(time: u64) -> i64 {
+    (day(time) + 3).div_euclid(7)
+}
+
+fn month(time: u64) -> i64 {
+    let (year, month, _) = snapshot::civil_date(time);
+    year * 12 + i64::from(month)
+}
+
+/// Removes the snapshots the policy does not keep, unless `dry_run` is set,
+/// and returns how many there are. The caller has to hold the repository
+/// lock.
+pub fn prune(repo: &Repository, policy: &Policy, dry_run: bool, quite: bool) -> io::Result<usize> {
+    let snapshots = repo.snapshots()?;
+    let keep = policy.keep(&snapshots);
+
+    let mut removed = 0;
+    for snapshot in &snapshots {
+        let kept = keep.contains(&snapshot.id);
+        if quite {
+            say!(
+                "** {} {} from {}",
+                match (kept, dry_run) {
+                    (true, _) => "Keeping",
+                    (false, true) => "Would remove",
+                    (false, false) => "Removing",
+                },
+                snapshot.id,
+                snapshot::format_time(snapshot.time)
+            );
+        }
+
+        if !kept {
+            if !dry_run {
+                fs::remove_file(
+                    repo.root()
+                        .join(SNAPSHOTS_DIR)
+                        .join(format!("{}.json", snapshot.id)),
+                )?;
+            }
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Prunes with `policy` and, if any snapshot was removed, runs gc to free
+/// their data. The caller has to hold the repository lock.
+pub fn prune_and_gc(repo: &Repository, policy: &Policy, dry_run: bool, quite: bool) -> io::Result<()> {
+    let removed = prune(repo, policy, dry_run, quite)?;
+    if quite {
+        say!(
+            "** {} snapshots {} removed",
+            removed,
+            if dry_run { "would be" } else { "were" }
+        );
+    }
+
+    if removed > 0 && !dry_run {
+        gc::gc(repo, false, quite)?;
+    }
+    Ok(())
+}
+
+/// Applies the policy saved in the config of the repository at `root`, if
+/// there is one. Runs after every backup without errors.
+pub fn apply_saved(root: &Path, quite: bool) -> Vec<String> {
+    let result = Repository::open(root).and_then(|repo| {
+        let policy = repo.config().keep;
+        if policy.is_empty() {
+            return Ok(());
+        }
+
+        let _lock = Lock::acquire(repo.root())?;
+        if quite {
+            say!("** Applying the retention policy {:?}", policy);
+        }
+        prune_and_gc(&repo, &policy, false, quite)
+    });
+
+    match result {
+        Ok(_) => vec![],
+        Err(error) => vec![format!(
+            "Error: Failed to apply the retention policy of {:?} \n {}",
+            root, error
+        )],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 86_400;
+    // 2024-01-01, a monday
+    const START: u64 = 1_704_067_200;
+
+    fn snapshots(times: &[u64]) -> Vec<Snapshot> {
+        times
+            .iter()
+            .map(|time| Snapshot {
+                id: time.to_string(),
+                time: *time,
+                source: "src".to_string(),
+                files: vec![],
+            }).collect()
+    }
+
+    fn kept(policy: Policy, times: &[u64]) -> Vec<u64> {
+        let mut kept: Vec<u64> = policy
+            .keep(&snapshots(times))
+            .iter()
+            .map(|id| id.parse().unwrap())
+            .collect();
+        kept.sort();
+        kept
+    }
+
+    #[test]
+    fn empty_policy_keeps_nothing() {
+        assert!(Policy::default().is_empty());
+        assert!(kept(Policy::default(), &[START, START + DAY]).is_empty());
+    }
+
+    #[test]
+    fn last_keeps_the_newest() {
+        let policy = Policy { last: 2, ..Policy::default() };
+        assert_eq!(kept(policy, &[START + 2, START, START + 1]), vec![START + 1, START + 2]);
+        assert_eq!(kept(policy, &[START]), vec![START]);
+    }
+
+    #[test]
+    fn daily_keeps_the_newest_of_each_day() {
+        let times = [START, START + 10, START + DAY, START + DAY + 10, START + 3 * DAY];
+        let policy = Policy { daily: 2, ..Policy::default() };
+        assert_eq!(kept(policy, &times), vec![START + DAY + 10, START + 3 * DAY]);
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        // a saturday and sunday share a week, the monday after starts the next
+        let times = [START - 2 * DAY, START - DAY, START, START + 6 * DAY];
+        let policy = Policy { weekly: 5, ..Policy::default() };
+        assert_eq!(kept(policy, &times), vec![START - DAY, START + 6 * DAY]);
+    }
+
+    #[test]
+    fn monthly_and_rules_combine() {
+        // 2023-12-31, 2024-01-01, 2024-01-31 and 2024-02-01
+        let times = [START - DAY, START, START + 30 * DAY, START + 31 * DAY];
+        let policy = Policy { monthly: 2, ..Policy::default() };
+        assert_eq!(kept(policy, &times), vec![START + 30 * DAY, START + 31 * DAY]);
+
+        let policy = Policy { last: 1, monthly: 3, ..Policy::default() };
+        assert_eq!(kept(policy, &times), vec![START - DAY, START + 30 * DAY, START + 31 * DAY]);
+    }
+}
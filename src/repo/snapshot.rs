@@ -0,0 +1,122 @@
+//! Snapshots record the files of a single backup and the chunks they are made
+//! of.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+/// A single file in a snapshot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct File {
+    /// The path of the file, starting with the name of the source directory
+    pub path: String,
+    /// The size of the file in bytes
+    pub size: u64,
+    /// The mtime of the file in seconds since the unix epoch
+    pub mtime: u64,
+    /// The unix permission bits of the file
+    pub mode: u32,
+    /// The hashes of the chunks the file is made of, in order
+    pub chunks: Vec<String>,
+}
+
+/// A single backup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The random id of the snapshot
+    pub id: String,
+    /// When the backup was taken, in seconds since the unix epoch
+    pub time: u64,
+    /// The source directory that was backed up
+    pub source: String,
+    /// Every file in the snapshot
+    pub files: Vec<File>,
+}
+
+impl Snapshot {
+    /// Reads a snapshot file
+    pub fn load(path: &Path) -> io::Result<Snapshot> {
+        serde_json::from_slice(&fs::read(path)?)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    /// Returns the total size of the files in bytes
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+}
+
+/// Returns the current time in seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// Splits seconds since the unix epoch into a UTC (year, month, day) date
+pub fn civil_date(time: u64) -> (i64, u32, u32) {
+    // Howard Hinnant's days_from_civil, run backwards
+    let days = (time / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Returns the days between the unix epoch and a UTC date, see civil_date
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Formats seconds since the unix epoch as `YYYY-MM-DD HH:MM:SS` in UTC
+pub fn format_time(time: u64) -> String {
+    let (year, month, day) = civil_date(time);
+    let secs = time % 86_400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(86_399), (1970, 1, 1));
+        assert_eq!(civil_date(86_400), (1970, 1, 2));
+        // leap days, including the century rules
+        assert_eq!(civil_date(951_782_400), (2000, 2, 29));
+        assert_eq!(civil_date(1_709_164_800), (2024, 2, 29));
+        assert_eq!(civil_date(4_107_542_400), (2100, 3, 1));
+        assert_eq!(civil_date(1_704_067_199), (2023, 12, 31));
+        assert_eq!(civil_date(1_704_067_200), (2024, 1, 1));
+    }
+
+    #[test]
+    fn format_times() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00");
+        assert_eq!(format_time(1_709_251_199), "2024-02-29 23:59:59");
+    }
+}
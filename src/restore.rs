@@ -0,0 +1,222 @@
+//! The `restore` subcommand. Copies a plain backup back into place, undoing
+//! anything the backup did to the files on the way, like compression, or
+//! restores a snapshot from a repository.
+
+use std::fs::DirBuilder;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use regex::Regex;
+
+use copy::{self, Secrets};
+use encrypt::{self, Keys};
+use manifest::MANIFEST_FILE;
+use names::{self, Names};
+use prompt::{self, Sources};
+use pubkey;
+use repo::{self, Repository};
+use storage::Local;
+
+/// Builds the clap subcommand
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("restore")
+        .about("Restores a plain backup or a repository snapshot, decrypting and decompressing files as needed.")
+        .arg(
+            Arg::with_name("source")
+                .short("s")
+                .long("source")
+                .value_name("BACKUP_PATH")
+                .help("The backup directory or repository to restore from, ie. backup_dir/home")
+                .takes_value(true)
+                .required(true),
+        ).arg(
+            Arg::with_name("destination")
+                .short("d")
+                .long("destination")
+                .value_name("DESTINATION_PATH")
+                .help("The directory the backup is restored into.")
+                .takes_value(true)
+                .required(true),
+        ).arg(
+            Arg::with_name("keyfile")
+                .long("keyfile")
+                .value_name("FILE_PATH")
+                .help("Reads the passphrase of an encrypted backup from a file")
+                .takes_value(true),
+        ).args(&Sources::args())
+        .arg(
+            Arg::with_name("identity")
+                .short("i")
+                .long("identity")
+                .value_name("FILE_PATH")
+                .help("An age identity file, for backups encrypted to public keys")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        ).arg(
+            Arg::with_name("snapshot")
+                .long("snapshot")
+                .value_name("ID")
+                .help("The repository snapshot to restore, or a prefix of its id [default: latest]")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("quite")
+                .short("q")
+                .long("quite")
+                .help("Stop backr from printing to stdout."),
+        )
+}
+
+/// Runs the restore subcommand and returns the errors it ran into
+pub fn run(cli: &ArgMatches) -> Vec<String> {
+    let source = PathBuf::from(cli.value_of("source").unwrap());
+    let dest = PathBuf::from(cli.value_of("destination").unwrap());
+    let quite = !cli.is_present("quite");
+    let keyfile = cli.value_of("keyfile").map(PathBuf::from);
+    let sources = Sources::from_cli(cli);
+
+    // repositories keep their own record of the files
+    if Repository::exists(&source) {
+        let errors = match Repository::open(&source) {
+            Ok(repo) => repo::restore(
+                &repo,
+                cli.value_of("snapshot").unwrap_or("latest"),
+                &dest,
+                quite,
+            ),
+            Err(error) => vec![format!(
+                "Error: Failed to open the repository {:?} \n {}",
+                source, error
+            )],
+        };
+        if quite {
+            for error in &errors {
+                say!("{}", error);
+            }
+        }
+        return errors;
+    }
+
+    let mut identities = vec![];
+    for path in cli.values_of("identity").into_iter().flatten() {
+        match pubkey::read_identities(path.as_ref()) {
+            Ok(found) => identities.extend(found),
+            Err(error) => {
+                say!("Error: Failed to read the identity file {:?} \n{}", path, error);
+                return vec![error.to_string()];
+            }
+        }
+    }
+
+    // only asks for a passphrase once an encrypted file shows up
+    let mut secrets = Secrets {
+        keys: Keys::new(|| prompt::passphrase(keyfile.as_deref(), sources, false)),
+        identities,
+    };
+
+    if quite {
+        say!("** Restoring {:?} into {:?}", source, dest);
+    }
+
+    let dest_root = dest.clone();
+    let (queue, mut errors) = ::walk(
+        vec![],
+        vec![],
+        &source,
+        &dest,
+        &Regex::new(".*").unwrap(),
+        false,
+        None,
+        None,
+        None,
+        None,
+        &mut Local,
+    );
+    let mut restored = 0;
+
+    // the files backr keeps about the backup are not part of the backup
+    let internal = [
+        source.join("backr_log.txt"),
+        source.join(encrypt::SALT_FILE),
+        source.join(MANIFEST_FILE),
+        source.join(names::NAMES_FILE),
+    ];
+
+    // the names of the backup may need to be decrypted first
+    let names = match names::enabled(&source) {
+        true => {
+            let key = encrypt::destination_salt(&source)
+                .and_then(|salt| secrets.keys.get(salt).map(Names::new));
+            match key {
+                Ok(names) => Some(names),
+                Err(error) => {
+                    say!("Error: Failed to get the key for the encrypted names \n{}", error);
+                    return vec![error.to_string()];
+                }
+            }
+        }
+        false => None,
+    };
+
+    let mut dirs = vec![];
+    for (src, dest) in queue.into_iter().filter(|(src, _)| !internal.contains(src)) {
+        // directories get their permissions and mtime once they are filled
+        if src.is_dir() {
+            let result = restore_path(&dest, &dest_root, names.as_ref())
+                .and_then(|dest| copy::backup_dir(&mut Local, &src, &dest).map(|_| dest));
+            match result {
+                Ok(dest) => dirs.push((src, dest)),
+                Err(error) => errors.push(format!(
+                    "Error: Failed to restore {:?} -> {:?} \n {}",
+                    src, dest, error
+                )),
+            }
+            continue;
+        }
+
+        let result = restore_path(&dest, &dest_root, names.as_ref()).and_then(|dest| {
+            DirBuilder::new()
+                .recursive(true)
+                .create(dest.parent().unwrap())
+                .and_then(|_| copy::restore_file(&src, &dest, &mut secrets))
+        });
+
+        match result {
+            Ok(_) => restored += 1,
+            Err(error) => {
+                if quite {
+                    say!("{}", &error);
+                }
+                errors.push(format!(
+                    "Error: Failed to restore {:?} -> {:?} \n {}",
+                    src, dest, error
+                ));
+            }
+        }
+    }
+
+    errors.extend(copy::finish_dirs(&mut Local, dirs));
+
+    if quite {
+        say!("** Files Restored: {}", restored);
+        say!("** Total errors {}", errors.len());
+        for error in &errors {
+            say!("{}", error);
+        }
+    }
+
+    errors
+}
+
+/// Returns the path a file is restored to, decrypting the names below the
+/// restore root if the backup uses encrypted names
+fn restore_path(dest: &Path, root: &Path, names: Option<&Names>) -> io::Result<PathBuf> {
+    match names {
+        Some(names) => {
+            let relative = dest.strip_prefix(root).unwrap_or(dest);
+            names.decrypt_path(relative).map(|relative| root.join(relative))
+        }
+        None => Ok(dest.to_path_buf()),
+    }
+}
@@ -0,0 +1,1211 @@
+//! The `serve` subcommand, and the client side of its protocol.
+//!
+//! `backr -d ssh://host/path` starts `backr serve -d /path` on the other end
+//! through ssh and talks to it over stdin and stdout, so only the files that
+//! changed cross the network:
+//!
+//!  1. both sides send MAGIC, then the server sends a string that is empty
+//!     if it can write to its destination, or says why it can not
+//!  2. the client sends the manifest: the --update flag, the delta threshold
+//!     and every file and directory with a type byte, its path relative to
+//!     the destination, size, mtime and mode. The server creates the
+//!     directories right away
+//!  3. the server answers with the indexes of the files it needs, those it
+//!     does not have with the same size and mtime, or newer for --update,
+//!     each with the block size of a delta or 0
+//!  4. the client sends each of them as chunks, ended by an empty chunk, or
+//!     by ABORTED if it failed to read the file. For a delta the server first
+//!     sends the signature of its copy, and the chunks are mixed with COPY
+//!     and its blocks, and followed by the sha256 of the file, see delta.rs
+//!  5. the server gives the directories their mode and mtime, answers with
+//!     the index and message of every error it ran into, and the client
+//!     hangs up
+//!
+//! `backr server` speaks the same protocol over TLS, after the client logged
+//! in with LOGIN, see server.rs.
+//!
+//! Numbers are big endian, strings a u32 length followed by utf-8.
+
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use native_tls::{Certificate, TlsConnector};
+use progress::Bar;
+use sha2::{Digest, Sha256};
+
+use copy;
+use delta::{self, Block, Op, Signature};
+use output;
+use remote::{self, Auth, Url};
+use storage::{self, local, Local, Meta, Storage};
+use throttle::{Throttle, Throttled};
+
+/// Sent by both sides before anything else, the last byte is the version
+const MAGIC: &[u8; 8] = b"backr\0\0\x03";
+
+/// Answers to LOGIN, followed by a message
+pub const LOGIN_OK: u8 = 0;
+pub const LOGIN_DENIED: u8 = 1;
+pub const LOGIN_FAILED: u8 = 2;
+
+/// The type byte of a manifest entry
+const ENTRY_FILE: u8 = 0;
+const ENTRY_DIR: u8 = 1;
+
+/// Ends the chunks of a file the client failed to read
+const ABORTED: u32 = u32::MAX;
+
+/// Stands for blocks of the old copy among the chunks of a delta, followed
+/// by the index of the first block and their count
+const COPY: u32 = u32::MAX - 1;
+
+/// Sent as the delta threshold to always get whole files
+const NO_DELTA: u64 = u64::MAX;
+
+/// More blocks than this in a signature is a broken connection
+const MAX_BLOCKS: u64 = 1 << 24;
+
+/// Size of the chunks files are sent in
+const CHUNK_SIZE: usize = 128 * 1024;
+
+/// Strings longer than this are a broken connection rather than a path
+const MAX_STRING: u32 = 1024 * 1024;
+
+/// A file in the manifest
+struct Entry {
+    dir: bool,
+    /// Relative to the destination
+    path: String,
+    size: u64,
+    meta: Meta,
+}
+
+/// The old copy of a file a delta is applied to
+struct Base {
+    /// None if the server failed to read it, the client then sends no COPY
+    file: Option<File>,
+    block_size: u64,
+    len: u64,
+}
+
+/// The file `receive` writes, with what it has taken so far
+struct Receiving {
+    /// None once writing failed
+    file: Option<BufWriter<File>>,
+    failed: Option<String>,
+    received: u64,
+    limit: Option<u64>,
+    /// Some for a delta, whose end is checked against the hash of the file
+    hasher: Option<Sha256>,
+}
+
+impl Receiving {
+    /// Writes the next piece of the file
+    fn take(&mut self, piece: &[u8]) {
+        self.received += piece.len() as u64;
+        if let Some(ref mut hasher) = self.hasher {
+            hasher.update(piece);
+        }
+        if self.limit.is_some_and(|limit| self.received > limit) && self.file.is_some() {
+            self.fail("The file grew during the backup and no longer fits the quota".to_string());
+        }
+        let written = match self.file {
+            Some(ref mut file) => file.write_all(piece),
+            None => Ok(()),
+        };
+        if let Err(error) = written {
+            self.fail(error.to_string());
+        }
+    }
+
+    /// Stops writing, the rest of the file is still read but dropped
+    fn fail(&mut self, error: String) {
+        self.failed = Some(error);
+        self.file = None;
+    }
+}
+
+/// Builds the clap subcommand
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("serve")
+        .about("Receives a backup over stdin and stdout, started by backr -d ssh://host/path.")
+        .arg(
+            Arg::with_name("destination")
+                .short("d")
+                .long("destination")
+                .value_name("DESTINATION_PATH")
+                .help("The directory the backup is written to")
+                .takes_value(true)
+                .required(true),
+        )
+}
+
+/// Runs the serve subcommand and returns the errors it ran into
+pub fn run(cli: &ArgMatches) -> Vec<String> {
+    // stdout carries the protocol
+    output::use_stderr();
+
+    let root = PathBuf::from(cli.value_of("destination").unwrap());
+    let (stdin, stdout) = (io::stdin(), io::stdout());
+    match serve(&mut stdin.lock(), &mut BufWriter::new(stdout.lock()), &root, None) {
+        Ok(_) => vec![],
+        Err(error) => {
+            let error = format!("Error: backr serve failed \n {}", error);
+            say!("{}", error);
+            vec![error]
+        }
+    }
+}
+
+/// Receives one backup into `root`. Files that would grow it by more than
+/// `quota` bytes are refused.
+pub fn serve<R: Read, W: Write>(reader: &mut R, writer: &mut W, root: &Path, mut quota: Option<u64>) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.flush()?;
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "The client does not speak the backr protocol"));
+    }
+
+    // the same check the client runs for local destinations
+    let probe = root.join(".backr.serve");
+    let status = Local
+        .mkdir_all(root)
+        .and_then(|_| Local.write(&probe, None, &mut |_| Ok(())))
+        .and_then(|_| Local.remove(&probe));
+    match status {
+        Ok(_) => write_string(writer, "")?,
+        Err(error) => {
+            write_string(writer, &format!("Failed to write to {:?} \n {}", root, error))?;
+            return writer.flush();
+        }
+    }
+    writer.flush()?;
+
+    let update = read_u8(reader)? != 0;
+    let threshold = read_u64(reader)?;
+    let count = read_u64(reader)?;
+    let mut entries = vec![];
+    for _ in 0..count {
+        entries.push(Entry {
+            dir: match read_u8(reader)? {
+                ENTRY_FILE => false,
+                ENTRY_DIR => true,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "The client sent an unknown entry")),
+            },
+            path: read_string(reader)?,
+            size: read_u64(reader)?,
+            meta: Meta {
+                mtime: read_u64(reader)?,
+                mode: read_u32(reader)?,
+            },
+        });
+    }
+
+    let mut errors = vec![];
+    let mut needed = vec![];
+    let mut dirs = vec![];
+    for (index, entry) in entries.iter().enumerate() {
+        let path = match target(root, &entry.path) {
+            Some(path) => path,
+            None => {
+                errors.push((index, format!("{:?} is not a relative path", entry.path)));
+                continue;
+            }
+        };
+        if entry.dir {
+            match Local.mkdir_all(&path) {
+                Ok(_) => dirs.push((index, path, entry.meta)),
+                Err(error) => errors.push((index, error.to_string())),
+            }
+            continue;
+        }
+        let existing = fs::metadata(&path).ok().filter(|meta| meta.is_file());
+        // --update keeps a newer copy whatever its size
+        let unchanged = match existing {
+            Some(ref meta) => {
+                let mtime = storage::mtime(meta);
+                (meta.len() == entry.size && mtime == entry.meta.mtime) || (update && mtime > entry.meta.mtime)
+            }
+            _ => false,
+        };
+        if unchanged {
+            continue;
+        }
+
+        // a replaced file gives its space back
+        let old_len = existing.as_ref().map_or(0, |meta| meta.len());
+        let grows = entry.size.saturating_sub(old_len);
+        if let Some(ref mut quota) = quota {
+            if grows > *quota {
+                errors.push((index, "The quota of this client is used up".to_string()));
+                continue;
+            }
+            *quota -= grows;
+        }
+
+        let block_size = match existing {
+            Some(ref meta) if meta.len() > 0 && entry.size >= threshold => delta::block_size(meta.len()),
+            _ => 0,
+        };
+        needed.push((index, path, block_size, old_len, grows));
+    }
+
+    write_u64(writer, needed.len() as u64)?;
+    for (index, _, block_size, ..) in &needed {
+        write_u64(writer, *index as u64)?;
+        write_u32(writer, *block_size)?;
+    }
+    writer.flush()?;
+
+    for (index, path, block_size, old_len, grows) in &needed {
+        let base = match *block_size {
+            0 => None,
+            block_size => Some(send_signature(writer, path, block_size)?),
+        };
+        // a file that grew since the manifest may only take what is left
+        let limit = quota.map(|quota| entries[*index].size.saturating_add(quota));
+        if let Err(error) = receive(reader, path, entries[*index].meta, limit, base)? {
+            errors.push((*index, error));
+        }
+
+        // the reserved space is given back and what the file took counted,
+        // a failed or aborted file left the old copy
+        if let Some(ref mut quota) = quota {
+            let len = fs::metadata(path).map_or(*old_len, |meta| meta.len());
+            *quota = (*quota + grows).saturating_sub(len.saturating_sub(*old_len));
+        }
+    }
+
+    // writing the files changed the mtimes of their directories, the
+    // deepest go first so a parent that can not be entered locks nobody out
+    dirs.sort_by_key(|(_, path, _)| Reverse(path.components().count()));
+    for (index, path, meta) in dirs {
+        if let Err(error) = local::set_meta(&path, meta) {
+            errors.push((index, error.to_string()));
+        }
+    }
+
+    write_u64(writer, errors.len() as u64)?;
+    for (index, error) in &errors {
+        write_u64(writer, *index as u64)?;
+        write_string(writer, error)?;
+    }
+    writer.flush()
+}
+
+/// Joins a path from the client to the root, unless it tries to get out
+pub fn target(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    let relative = path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    match relative {
+        true => Some(root.join(path)),
+        false => None,
+    }
+}
+
+/// Sends the signature of the copy at `path`, or an empty one if it can not
+/// be read, and returns the copy to rebuild the file from
+fn send_signature<W: Write>(writer: &mut W, path: &Path, block_size: u32) -> io::Result<Base> {
+    let signature = File::open(path).and_then(|file| {
+        let len = file.metadata()?.len();
+        let signature = Signature::of(&mut BufReader::new(&file), len, block_size)?;
+        Ok((file, signature))
+    });
+    let (file, signature) = match signature {
+        Ok((file, signature)) => (Some(file), signature),
+        Err(_) => (
+            None,
+            Signature {
+                block_size,
+                len: 0,
+                blocks: vec![],
+            },
+        ),
+    };
+
+    write_u64(writer, signature.len)?;
+    for block in &signature.blocks {
+        write_u32(writer, block.weak)?;
+        writer.write_all(&block.strong)?;
+    }
+    writer.flush()?;
+
+    Ok(Base {
+        file,
+        block_size: block_size as u64,
+        len: signature.len,
+    })
+}
+
+/// Reads the chunks of one file into `path`, taking the blocks of a delta
+/// from `base`. The outer error is a broken connection, the inner one a file
+/// that could not be written. The chunks are read to the end either way, so
+/// the protocol stays in step. Writing more than `limit` bytes fails.
+fn receive<R: Read>(
+    reader: &mut R,
+    path: &Path,
+    meta: Meta,
+    limit: Option<u64>,
+    base: Option<Base>,
+) -> io::Result<Result<(), String>> {
+    let part = copy::part_path(path);
+    let mut into = Receiving {
+        file: None,
+        failed: None,
+        received: 0,
+        limit,
+        hasher: base.as_ref().map(|_| Sha256::new()),
+    };
+    match Local.mkdir_all(path.parent().unwrap()).and_then(|_| File::create(&part)) {
+        Ok(file) => into.file = Some(BufWriter::new(file)),
+        Err(error) => into.fail(error.to_string()),
+    }
+
+    // a broken connection leaves no part behind either
+    let read = read_chunks(reader, &mut into, base);
+    let Receiving { file, failed, .. } = into;
+    let aborted = match read {
+        Ok(aborted) => aborted,
+        Err(error) => {
+            drop(file);
+            let _ = Local.remove(&part);
+            return Err(error);
+        }
+    };
+
+    let result = match (aborted, failed, file) {
+        // the client reports this one itself
+        (true, ..) => Ok(()),
+        (false, Some(error), _) => Err(error),
+        (false, None, Some(file)) => file
+            .into_inner()
+            .map_err(|error| error.into_error())
+            .and_then(|file| file.sync_all())
+            .and_then(|_| local::set_meta(&part, meta))
+            .and_then(|_| Local.rename(&part, path))
+            .map_err(|error| error.to_string()),
+        (false, None, None) => Ok(()),
+    };
+    if aborted || result.is_err() {
+        let _ = Local.remove(&part);
+    }
+    Ok(result)
+}
+
+/// Reads the chunks of a file into `into`, and returns true if the client
+/// aborted it
+fn read_chunks<R: Read>(reader: &mut R, into: &mut Receiving, mut base: Option<Base>) -> io::Result<bool> {
+    let mut aborted = false;
+    let mut buf = vec![];
+    loop {
+        let len = read_u32(reader)?;
+        if len == 0 {
+            break;
+        }
+        if len == ABORTED {
+            aborted = true;
+            break;
+        }
+
+        if len == COPY {
+            let (index, count) = (read_u64(reader)?, read_u32(reader)? as u64);
+            let (old, block_size, old_len) = match base {
+                Some(Base {
+                    file: Some(ref mut old),
+                    block_size,
+                    len,
+                }) => (old, block_size, len),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "The client sent a block the server does not have")),
+            };
+            let start = index.checked_mul(block_size).filter(|&start| start < old_len);
+            let start = match start {
+                Some(start) if count > 0 && index + count <= old_len.div_ceil(block_size) => start,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "The client sent a block the server does not have")),
+            };
+            // a run can cover most of the old file, so it is copied in chunks
+            let end = ((index + count) * block_size).min(old_len);
+            if let Err(error) = old.seek(SeekFrom::Start(start)) {
+                into.fail(error.to_string());
+                continue;
+            }
+            let mut pos = start;
+            while pos < end {
+                buf.resize((end - pos).min(CHUNK_SIZE as u64) as usize, 0);
+                if let Err(error) = old.read_exact(&mut buf) {
+                    into.fail(error.to_string());
+                    break;
+                }
+                into.take(&buf);
+                pos += buf.len() as u64;
+            }
+        } else {
+            if len as usize > CHUNK_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "The client sent a chunk that is too large"));
+            }
+            buf.resize(len as usize, 0);
+            reader.read_exact(&mut buf)?;
+            into.take(&buf);
+        }
+    }
+
+    // a delta ends with the hash of the file, which the rebuilt one must have
+    if base.is_some() && !aborted {
+        let mut expected = [0; 32];
+        reader.read_exact(&mut expected)?;
+        let hasher = into.hasher.take();
+        if into.failed.is_none() && hasher.is_some_and(|hasher| hasher.finalize()[..] != expected[..]) {
+            into.fail("The file changed while it was sent, the delta does not match".to_string());
+        }
+    }
+    Ok(aborted)
+}
+
+/// One end of a stream that is read and written in turns, so it can be
+/// buffered in both directions
+pub struct Shared<S>(Rc<RefCell<S>>);
+
+impl<S> Shared<S> {
+    pub fn split(stream: S) -> (Shared<S>, Shared<S>) {
+        let stream = Rc::new(RefCell::new(stream));
+        (Shared(stream.clone()), Shared(stream))
+    }
+}
+
+impl<S: Read> Read for Shared<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+impl<S: Write> Write for Shared<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// A connection to `backr serve` or `backr server`
+pub struct Session {
+    reader: BufReader<Box<dyn Read>>,
+    writer: BufWriter<Box<dyn Write>>,
+    child: Option<Child>,
+}
+
+impl Session {
+    /// Starts `backr serve` for an ssh:// url, through ssh or `command` if
+    /// given, and checks that it can write to its destination
+    pub fn open(url: &Url, command: Option<&str>, key: Option<&Path>) -> io::Result<Session> {
+        let path = quote(&url.path.to_string_lossy());
+        let mut command = match command {
+            Some(command) => {
+                let mut sh = Command::new("sh");
+                sh.arg("-c").arg(format!("{} -d {}", command, path));
+                sh
+            }
+            None => {
+                let mut ssh = Command::new("ssh");
+                if url.port != 22 {
+                    ssh.arg("-p").arg(url.port.to_string());
+                }
+                if let Some(key) = key {
+                    ssh.arg("-i").arg(key);
+                }
+                match url.user.is_empty() {
+                    true => ssh.arg(&url.host),
+                    false => ssh.arg(format!("{}@{}", url.user, url.host)),
+                };
+                ssh.args(["backr", "serve", "-d"]).arg(path);
+                ssh
+            }
+        };
+
+        let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+        let (stdin, stdout) = (child.stdin.take().unwrap(), child.stdout.take().unwrap());
+        Session {
+            reader: BufReader::new(Box::new(stdout)),
+            writer: BufWriter::new(Box::new(stdin)),
+            child: Some(child),
+        }
+        .start()
+    }
+
+    /// Connects to `backr server` for a backr://client@host/name url and logs
+    /// in. The certificate of the server is checked against the system
+    /// roots, and `ca` if given.
+    pub fn connect(url: &Url, auth: &Auth, ca: Option<&Path>) -> io::Result<Session> {
+        let password = match auth.password.as_ref().or(url.password.as_ref()) {
+            Some(password) => password,
+            None => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "No password")),
+        };
+
+        let mut builder = TlsConnector::builder();
+        if let Some(ca) = ca {
+            let pem = fs::read(ca)?;
+            builder.add_root_certificate(Certificate::from_pem(&pem).map_err(io::Error::other)?);
+        }
+        let connector = builder.build().map_err(io::Error::other)?;
+
+        let stream = TcpStream::connect((url.host.as_str(), url.port))?;
+        stream.set_read_timeout(Some(Duration::from_secs(600)))?;
+        let stream = connector
+            .connect(&url.host, stream)
+            .map_err(|error| io::Error::other(error.to_string()))?;
+
+        let (reader, writer) = Shared::split(stream);
+        let mut session = Session {
+            reader: BufReader::new(Box::new(reader)),
+            writer: BufWriter::new(Box::new(writer)),
+            child: None,
+        };
+
+        write_string(&mut session.writer, &remote::user(url))?;
+        write_string(&mut session.writer, password)?;
+        write_string(&mut session.writer, url.path.to_string_lossy().trim_start_matches('/'))?;
+        session.writer.flush()?;
+        let status = read_u8(&mut session.reader)?;
+        let message = read_string(&mut session.reader)?;
+        match status {
+            LOGIN_OK => session.start(),
+            LOGIN_DENIED => Err(io::Error::new(io::ErrorKind::PermissionDenied, message)),
+            _ => Err(io::Error::other(message)),
+        }
+    }
+
+    /// Checks that the other end speaks the protocol and can write to its
+    /// destination
+    fn start(mut self) -> io::Result<Session> {
+        let session = &mut self;
+        session.writer.write_all(MAGIC)?;
+        session.writer.flush()?;
+        let mut magic = [0; 8];
+        match session.reader.read_exact(&mut magic) {
+            Ok(_) if &magic == MAGIC => {}
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The other end does not speak the backr protocol",
+                ))
+            }
+            Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(io::Error::other(
+                    "backr serve did not start, check that ssh works and backr is installed on the other end",
+                ))
+            }
+            Err(error) => return Err(error),
+        }
+
+        let status = read_string(&mut session.reader)?;
+        match status.is_empty() {
+            true => Ok(self),
+            false => Err(io::Error::other(status)),
+        }
+    }
+
+    /// Sends the files of the queue the server does not have yet. `root` is
+    /// the destination path the queue is relative to.
+    #[allow(clippy::too_many_arguments)]
+    pub fn push(
+        mut self,
+        queue: Vec<(PathBuf, PathBuf)>,
+        root: &Path,
+        update: bool,
+        delta: Option<u64>,
+        progress: bool,
+        quite: bool,
+        throttle: Option<Arc<Throttle>>,
+    ) -> Vec<String> {
+        if quite {
+            say!("** Starting backup ");
+        }
+
+        let mut errors = vec![];
+        let mut files = vec![];
+        for (src, dest) in queue {
+            match fs::metadata(&src) {
+                Ok(meta) => files.push((src, dest, meta)),
+                Err(error) => errors.push(format!("Error: Failed to copy {:?} -> {:?} \n {}", src, dest, error)),
+            }
+        }
+
+        let exchanged = self.exchange(&files, root, update, delta, progress, quite, throttle.as_deref(), &mut errors);
+        if let Err(error) = exchanged {
+            errors.push(format!("Error: Lost the connection to backr serve \n {}", error));
+        }
+
+        // hanging up ends the server
+        drop(self.writer);
+        if let Some(mut child) = self.child {
+            match child.wait() {
+                Ok(status) if !status.success() && errors.is_empty() => {
+                    errors.push(format!("Error: backr serve failed with {}", status))
+                }
+                Err(error) => errors.push(format!("Error: Failed to wait for backr serve \n {}", error)),
+                _ => {}
+            }
+        }
+        errors
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn exchange(
+        &mut self,
+        files: &[(PathBuf, PathBuf, fs::Metadata)],
+        root: &Path,
+        update: bool,
+        delta: Option<u64>,
+        progress: bool,
+        quite: bool,
+        throttle: Option<&Throttle>,
+        errors: &mut Vec<String>,
+    ) -> io::Result<()> {
+        write_u8(&mut self.writer, update as u8)?;
+        write_u64(&mut self.writer, delta.unwrap_or(NO_DELTA))?;
+        write_u64(&mut self.writer, files.len() as u64)?;
+        for (_, dest, meta) in files {
+            let path = dest.strip_prefix(root).unwrap_or(dest);
+            match meta.is_dir() {
+                true => write_u8(&mut self.writer, ENTRY_DIR)?,
+                false => write_u8(&mut self.writer, ENTRY_FILE)?,
+            }
+            write_string(&mut self.writer, &path.to_string_lossy())?;
+            write_u64(&mut self.writer, meta.len())?;
+            write_u64(&mut self.writer, storage::mtime(meta))?;
+            write_u32(&mut self.writer, storage::mode(meta))?;
+        }
+        self.writer.flush()?;
+
+        let count = read_u64(&mut self.reader)?;
+        let mut needed = vec![];
+        for _ in 0..count {
+            let file = files.get(read_u64(&mut self.reader)? as usize);
+            let block_size = read_u32(&mut self.reader)?;
+            match file {
+                Some(file) if !file.2.is_dir() => needed.push((file, block_size)),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "The server asked for an unknown file")),
+            }
+        }
+        if quite {
+            let count = files.iter().filter(|(_, _, meta)| !meta.is_dir()).count();
+            say!("** {} of {} files changed", needed.len(), count);
+        }
+
+        let mut bar = match progress {
+            true => {
+                let mut bar = Bar::new();
+                bar.set_job_title("Backup");
+                Some(bar)
+            }
+            false => None,
+        };
+
+        let mut buf = vec![0; CHUNK_SIZE];
+        for (sent, ((src, dest, _), block_size)) in needed.iter().enumerate() {
+            let result = match *block_size {
+                0 => self.send_file(src, throttle, &mut buf)?,
+                block_size => self.send_delta(src, block_size, throttle)?,
+            };
+            if let Err(error) = result {
+                if quite {
+                    say!("{}", &error);
+                }
+                errors.push(format!("Error: Failed to copy {:?} -> {:?} \n {}", src, dest, error));
+            }
+            if let Some(ref mut bar) = bar {
+                bar.reach_percent(((sent + 1) * 100 / needed.len()) as i32);
+            }
+        }
+        self.writer.flush()?;
+
+        let count = read_u64(&mut self.reader)?;
+        for _ in 0..count {
+            let index = read_u64(&mut self.reader)? as usize;
+            let error = read_string(&mut self.reader)?;
+            match files.get(index) {
+                Some((src, dest, _)) => {
+                    errors.push(format!("Error: Failed to copy {:?} -> {:?} \n {}", src, dest, error))
+                }
+                None => errors.push(format!("Error: backr serve failed \n {}", error)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends one file as chunks. The outer error is a broken connection, the
+    /// inner one a file that could not be read.
+    fn send_file(&mut self, src: &Path, throttle: Option<&Throttle>, buf: &mut [u8]) -> io::Result<io::Result<()>> {
+        let mut reader = match File::open(src) {
+            Ok(file) => Throttled::new(file, throttle),
+            Err(error) => {
+                write_u32(&mut self.writer, ABORTED)?;
+                return Ok(Err(error));
+            }
+        };
+
+        loop {
+            match reader.read(buf) {
+                Ok(0) => {
+                    write_u32(&mut self.writer, 0)?;
+                    return Ok(Ok(()));
+                }
+                Ok(len) => {
+                    write_u32(&mut self.writer, len as u32)?;
+                    self.writer.write_all(&buf[..len])?;
+                }
+                Err(ref error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => {
+                    write_u32(&mut self.writer, ABORTED)?;
+                    return Ok(Err(error));
+                }
+            }
+        }
+    }
+
+    /// Sends one file as a delta against the signature the server sends
+    /// first. The outer error is a broken connection, the inner one a file
+    /// that could not be read.
+    fn send_delta(&mut self, src: &Path, block_size: u32, throttle: Option<&Throttle>) -> io::Result<io::Result<()>> {
+        // the server waits for everything sent so far before it answers
+        self.writer.flush()?;
+        let len = read_u64(&mut self.reader)?;
+        let count = len.div_ceil(block_size as u64);
+        if block_size == 0 || count > MAX_BLOCKS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "The server sent a signature that is too large"));
+        }
+        let mut blocks = vec![];
+        for _ in 0..count {
+            let weak = read_u32(&mut self.reader)?;
+            let mut strong = [0; 16];
+            self.reader.read_exact(&mut strong)?;
+            blocks.push(Block { weak, strong });
+        }
+        let signature = Signature { block_size, len, blocks };
+
+        let mut reader = match File::open(src) {
+            Ok(file) => Hashed(Throttled::new(file, throttle), Sha256::new()),
+            Err(error) => {
+                write_u32(&mut self.writer, ABORTED)?;
+                return Ok(Err(error));
+            }
+        };
+
+        // failed writes are told apart from failed reads
+        let mut lost = None;
+        let writer = &mut self.writer;
+        let result = delta::delta(&mut reader, &signature, &mut |op| {
+            let written = match op {
+                Op::Copy { index, count } => write_u32(writer, COPY)
+                    .and_then(|_| write_u64(writer, index))
+                    .and_then(|_| write_u32(writer, count)),
+                Op::Data(data) => data.chunks(CHUNK_SIZE).try_for_each(|chunk| {
+                    write_u32(writer, chunk.len() as u32).and_then(|_| writer.write_all(chunk))
+                }),
+            };
+            written.map_err(|error| {
+                let kind = error.kind();
+                lost = Some(error);
+                io::Error::new(kind, "The connection was lost")
+            })
+        });
+
+        if let Some(error) = lost {
+            return Err(error);
+        }
+        match result {
+            Ok(_) => {
+                write_u32(&mut self.writer, 0)?;
+                self.writer.write_all(&reader.1.finalize())?;
+                Ok(Ok(()))
+            }
+            Err(error) => {
+                write_u32(&mut self.writer, ABORTED)?;
+                Ok(Err(error))
+            }
+        }
+    }
+}
+
+/// Hashes everything read through it
+struct Hashed<R>(R, Sha256);
+
+impl<R: Read> Read for Hashed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.0.read(buf)?;
+        self.1.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Quotes an argument for sh
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+pub fn write_u8<W: Write>(writer: &mut W, value: u8) -> io::Result<()> {
+    writer.write_all(&[value])
+}
+
+pub fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_be_bytes())
+}
+
+pub fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_be_bytes())
+}
+
+pub fn write_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    write_u32(writer, value.len() as u32)?;
+    writer.write_all(value.as_bytes())
+}
+
+pub fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+pub fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+pub fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let len = read_u32(reader)?;
+    if len > MAX_STRING {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Received a string that is too long"));
+    }
+    let mut buf = vec![0; len as usize];
+    reader.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Received a string that is not utf-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixStream;
+    use std::thread::{self, JoinHandle};
+
+    use filetime::{self, FileTime};
+    use tempfile::TempDir;
+
+    use delta::tests::noise;
+
+    /// Starts `serve` for `root` on one end of a socket and a session on
+    /// the other, like `backr serve` through ssh
+    fn loopback(root: &Path, quota: Option<u64>) -> (Session, JoinHandle<io::Result<()>>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let root = root.to_path_buf();
+        let served = thread::spawn(move || serve(&mut BufReader::new(&server), &mut BufWriter::new(&server), &root, quota));
+        let session = Session {
+            reader: BufReader::new(Box::new(client.try_clone().unwrap())),
+            writer: BufWriter::new(Box::new(client)),
+            child: None,
+        };
+        (session.start().unwrap(), served)
+    }
+
+    /// Pushes `files` from `src` to the same names under `dest`
+    fn push(src: &Path, dest: &Path, files: &[&str], delta: Option<u64>, quota: Option<u64>) -> Vec<String> {
+        let queue = files.iter().map(|file| (src.join(file), dest.join(file))).collect();
+        let (session, served) = loopback(dest, quota);
+        let errors = session.push(queue, dest, false, delta, false, false, None);
+        served.join().unwrap().unwrap();
+        errors
+    }
+
+    fn put(path: &Path, data: &[u8], mtime: i64) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+        filetime::set_file_mtime(path, FileTime::from_unix_time(mtime, 0)).unwrap();
+    }
+
+    fn mtime(path: &Path) -> u64 {
+        storage::mtime(&fs::metadata(path).unwrap())
+    }
+
+    #[test]
+    fn push_over_a_loopback_session() {
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let big = noise(3 * CHUNK_SIZE + 7, 1);
+        put(&src.path().join("empty"), b"", 1_600_000_000);
+        put(&src.path().join("dir/small"), b"small", 1_600_000_001);
+        put(&src.path().join("dir/big"), &big, 1_600_000_002);
+        let files = ["empty", "dir/small", "dir/big"];
+
+        assert!(push(src.path(), dest.path(), &files, None, None).is_empty());
+        let expected: [(&str, &[u8], u64); 3] = [
+            ("empty", b"", 1_600_000_000),
+            ("dir/small", b"small", 1_600_000_001),
+            ("dir/big", &big, 1_600_000_002),
+        ];
+        for (file, data, time) in expected {
+            assert_eq!(fs::read(dest.path().join(file)).unwrap(), data);
+            assert_eq!(mtime(&dest.path().join(file)), time);
+        }
+        assert!(!copy::part_path(&dest.path().join("dir/big")).exists());
+
+        // files with the same size and mtime are not sent again
+        put(&dest.path().join("dir/small"), b"SMALL", 1_600_000_001);
+        put(&src.path().join("empty"), b"grown", 1_600_000_003);
+        assert!(push(src.path(), dest.path(), &files, None, None).is_empty());
+        assert_eq!(fs::read(dest.path().join("dir/small")).unwrap(), b"SMALL");
+        assert_eq!(fs::read(dest.path().join("empty")).unwrap(), b"grown");
+    }
+
+    #[test]
+    fn delta_over_a_loopback_session() {
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let old = noise(2 * CHUNK_SIZE + 100, 2);
+        let mut new = [&b"prefix"[..], &old[..], &noise(1000, 3)[..]].concat();
+        new[CHUNK_SIZE] ^= 1;
+        put(&dest.path().join("file"), &old, 1_600_000_000);
+        put(&src.path().join("file"), &new, 1_600_000_001);
+        put(&src.path().join("new"), b"new", 1_600_000_001);
+
+        assert!(push(src.path(), dest.path(), &["file", "new"], Some(1), None).is_empty());
+        assert!(fs::read(dest.path().join("file")).unwrap() == new);
+        assert_eq!(mtime(&dest.path().join("file")), 1_600_000_001);
+        assert_eq!(fs::read(dest.path().join("new")).unwrap(), b"new");
+    }
+
+    /// Receives `chunks` as a delta against `old`, in blocks of 2048
+    fn receive_delta(old: &[u8], chunks: &[u8]) -> io::Result<Result<(), String>> {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("old"), old).unwrap();
+        let base = Base {
+            file: Some(File::open(dir.path().join("old")).unwrap()),
+            block_size: 2048,
+            len: old.len() as u64,
+        };
+        let meta = Meta {
+            mtime: 1_600_000_000,
+            mode: 0o644,
+        };
+        let path = dir.path().join("new");
+        let received = receive(&mut &chunks[..], &path, meta, None, Some(base));
+        assert!(!copy::part_path(&path).exists());
+        if let Ok(Ok(_)) = received {
+            assert!(fs::read(&path).unwrap() == old[2048..]);
+        }
+        received
+    }
+
+    /// A delta of one COPY, and the hash of the blocks after the first
+    fn copy(old: &[u8], index: u64, count: u32) -> Vec<u8> {
+        let mut chunks = vec![];
+        write_u32(&mut chunks, COPY).unwrap();
+        write_u64(&mut chunks, index).unwrap();
+        write_u32(&mut chunks, count).unwrap();
+        write_u32(&mut chunks, 0).unwrap();
+        chunks.extend_from_slice(&Sha256::digest(&old[2048..]));
+        chunks
+    }
+
+    #[test]
+    fn invalid_copy_ranges_are_rejected() {
+        // three blocks, the last one short
+        let old = noise(5000, 4);
+        assert_eq!(receive_delta(&old, &copy(&old, 1, 2)).unwrap(), Ok(()));
+        assert!(receive_delta(&old, &copy(&old, 1, 1)).unwrap().unwrap_err().contains("does not match"));
+
+        for (index, count) in [(1, 0), (3, 1), (2, 2), (0, 4), (u64::MAX / 1024, 1), (u64::MAX, u32::MAX)] {
+            let error = receive_delta(&old, &copy(&old, index, count)).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{} {}", index, count);
+        }
+    }
+
+    #[test]
+    fn update_keeps_newer_copies() {
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        for name in ["older", "newer", "newer_resized"] {
+            put(&src.path().join(name), b"source", 1_600_000_000);
+        }
+        put(&dest.path().join("older"), b"before", 1_500_000_000);
+        put(&dest.path().join("newer"), b"edited", 1_700_000_000);
+        put(&dest.path().join("newer_resized"), b"edited more", 1_700_000_000);
+
+        let queue = ["older", "newer", "newer_resized"]
+            .iter()
+            .map(|name| (src.path().join(name), dest.path().join(name)))
+            .collect();
+        let (session, served) = loopback(dest.path(), None);
+        assert!(session.push(queue, dest.path(), true, None, false, false, None).is_empty());
+        served.join().unwrap().unwrap();
+        assert_eq!(fs::read(dest.path().join("older")).unwrap(), b"source");
+        assert_eq!(fs::read(dest.path().join("newer")).unwrap(), b"edited");
+        assert_eq!(fs::read(dest.path().join("newer_resized")).unwrap(), b"edited more");
+    }
+
+    #[test]
+    fn directories_are_created_and_finished() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        fs::create_dir_all(src.path().join("full/empty")).unwrap();
+        put(&src.path().join("full/file"), b"file", 1_600_000_000);
+        for (dir, mode, mtime) in [("full", 0o750, 1_500_000_000), ("full/empty", 0o700, 1_400_000_000)] {
+            let path = src.path().join(dir);
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+            filetime::set_file_mtime(&path, FileTime::from_unix_time(mtime, 0)).unwrap();
+        }
+
+        assert!(push(src.path(), dest.path(), &["full", "full/empty", "full/file"], None, None).is_empty());
+        for (dir, mode, mtime) in [("full", 0o750, 1_500_000_000), ("full/empty", 0o700, 1_400_000_000)] {
+            let meta = fs::metadata(dest.path().join(dir)).unwrap();
+            assert!(meta.is_dir());
+            assert_eq!((meta.permissions().mode() & 0o777, storage::mtime(&meta)), (mode, mtime));
+        }
+        assert_eq!(fs::read(dest.path().join("full/file")).unwrap(), b"file");
+    }
+
+    #[test]
+    fn quota_and_paths_outside_are_refused() {
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        put(&src.path().join("fits"), &[1; 10], 1_600_000_000);
+        put(&src.path().join("large"), &[2; 100], 1_600_000_000);
+
+        let errors = push(src.path(), dest.path(), &["fits", "large"], None, Some(50));
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("quota"));
+        assert!(dest.path().join("fits").exists());
+        assert!(!dest.path().join("large").exists());
+
+        let outside = dest.path().join("..").join("outside");
+        let (session, served) = loopback(dest.path(), None);
+        let queue = vec![(src.path().join("fits"), outside.clone())];
+        let errors = session.push(queue, &dest.path().join("sub"), false, None, false, false, None);
+        served.join().unwrap().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("not a relative path"));
+        assert!(!outside.exists());
+    }
+
+    #[test]
+    fn failed_files_give_their_quota_back() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let dest = TempDir::new().unwrap();
+        let root = dest.path().to_path_buf();
+        let served =
+            thread::spawn(move || serve(&mut BufReader::new(&server), &mut BufWriter::new(&server), &root, Some(25)));
+        let (mut reader, mut writer) = (BufReader::new(&client), &client);
+
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic).unwrap();
+        writer.write_all(MAGIC).unwrap();
+        assert_eq!(read_string(&mut reader).unwrap(), "");
+        write_u8(&mut writer, 0).unwrap();
+        write_u64(&mut writer, NO_DELTA).unwrap();
+        write_u64(&mut writer, 2).unwrap();
+        for name in ["aborted", "grown"] {
+            write_u8(&mut writer, ENTRY_FILE).unwrap();
+            write_string(&mut writer, name).unwrap();
+            write_u64(&mut writer, 10).unwrap();
+            write_u64(&mut writer, 1_600_000_000).unwrap();
+            write_u32(&mut writer, 0o644).unwrap();
+        }
+        assert_eq!(read_u64(&mut reader).unwrap(), 2);
+        for _ in 0..2 {
+            read_u64(&mut reader).unwrap();
+            read_u32(&mut reader).unwrap();
+        }
+
+        // the first file is aborted, so the second one may grow into its space
+        write_u32(&mut writer, ABORTED).unwrap();
+        write_u32(&mut writer, 20).unwrap();
+        writer.write_all(&[1; 20]).unwrap();
+        write_u32(&mut writer, 0).unwrap();
+        assert_eq!(read_u64(&mut reader).unwrap(), 0);
+        served.join().unwrap().unwrap();
+        assert!(!dest.path().join("aborted").exists());
+        assert_eq!(fs::read(dest.path().join("grown")).unwrap(), [1; 20]);
+    }
+
+    #[test]
+    fn unreadable_files_are_aborted() {
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        put(&src.path().join("there"), b"there", 1_600_000_000);
+        let queue = vec![
+            (src.path().join("missing"), dest.path().join("missing")),
+            (src.path().join("there"), dest.path().join("there")),
+        ];
+        let (session, served) = loopback(dest.path(), None);
+        let errors = session.push(queue, dest.path(), false, None, false, false, None);
+        served.join().unwrap().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(fs::read_dir(dest.path()).unwrap().count(), 1);
+        assert_eq!(fs::read(dest.path().join("there")).unwrap(), b"there");
+    }
+
+    #[test]
+    fn sessions_through_a_command() {
+        let dir = TempDir::new().unwrap();
+        let url = Url {
+            scheme: "ssh".to_string(),
+            user: String::new(),
+            password: None,
+            host: "localhost".to_string(),
+            port: 22,
+            path: PathBuf::from("/backups/it's here"),
+        };
+        let started = |command: &str| Session::open(&url, Some(command), None);
+
+        // a stand-in for backr serve that writes down its destination,
+        // answers an empty backup and fails on its way out
+        let out = dir.path().join("destination");
+        let command = format!(
+            "f() {{ printf '%s' \"$2\" > {}; printf 'backr\\000\\000\\003'; head -c 8 > /dev/null; \
+             printf '\\000%.0s' $(seq 20); cat > /dev/null; exit 3; }}; f",
+            quote(&out.to_string_lossy())
+        );
+        let errors = started(&command).unwrap().push(vec![], Path::new("/"), false, None, false, false, None);
+        assert_eq!(fs::read_to_string(&out).unwrap(), "/backups/it's here");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("backr serve failed with exit status: 3"), "{}", errors[0]);
+
+        let error = started("true").err().unwrap();
+        assert!(error.to_string().contains("backr serve did not start"));
+    }
+
+    #[test]
+    fn other_protocols_are_refused() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let dest = TempDir::new().unwrap();
+        let root = dest.path().to_path_buf();
+        let served = thread::spawn(move || serve(&mut BufReader::new(&server), &mut BufWriter::new(&server), &root, None));
+        (&client).write_all(b"SSH-2.0-").unwrap();
+        assert_eq!(served.join().unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn quoted_arguments() {
+        assert_eq!(quote("/backups/it's here"), "'/backups/it'\\''s here'");
+        assert_eq!(target(Path::new("/root"), "a/b"), Some(PathBuf::from("/root/a/b")));
+        for path in ["", "/etc/passwd", "../up", "a/../../up", "./a"] {
+            assert_eq!(target(Path::new("/root"), path), None, "{:?}", path);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! The `snapshots` subcommand. Lists the snapshots in a repository.
+
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use repo::snapshot;
+use repo::Repository;
+
+/// Builds the clap subcommand
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("snapshots")
+        .about("Lists the snapshots in a repository.")
+        .arg(
+            Arg::with_name("source")
+                .short("s")
+                .long("source")
+                .value_name("REPOSITORY_PATH")
+                .help("The repository, ie. the destination of backr -f repo")
+                .takes_value(true)
+                .required(true),
+        )
+}
+
+/// Runs the snapshots subcommand and returns the errors it ran into
+pub fn run(cli: &ArgMatches) -> Vec<String> {
+    let source = PathBuf::from(cli.value_of("source").unwrap());
+
+    let snapshots = match Repository::open(&source).and_then(|repo| repo.snapshots()) {
+        Ok(snapshots) => snapshots,
+        Err(error) => {
+            let error = format!("Error: Failed to read the repository {:?} \n {}", source, error);
+            say!("{}", error);
+            return vec![error];
+        }
+    };
+
+    say!("{:<16}  {:<19}  {:>7}  {:>12}  SOURCE", "ID", "TIME", "FILES", "SIZE");
+    for snapshot in &snapshots {
+        say!(
+            "{:<16}  {:<19}  {:>7}  {:>12}  {}",
+            snapshot.id,
+            snapshot::format_time(snapshot.time),
+            snapshot.files.len(),
+            snapshot.size(),
+            snapshot.source
+        );
+    }
+
+    vec![]
+}
@@ -0,0 +1,146 @@
+//! Sparse files, whose holes take no space on disk, ie. VM images and
+//! database files.
+//!
+//! The holes of a file are found with SEEK_DATA and SEEK_HOLE and skipped
+//! when it is copied, so the copy gets the same holes. With --sparse blocks
+//! of zeros are skipped as well, which turns long zero runs into holes.
+
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// With --sparse aligned blocks of this many zeros become holes, a multiple
+/// of the block size of common filesystems
+pub const ZERO_RUN: usize = 32 * 1024;
+
+/// Returns the start and end of every range of `file` that holds data, or
+/// None if it has no holes or the platform can not tell. Moves the file
+/// position.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos"))]
+pub fn data_ranges(file: &File) -> io::Result<Option<Vec<(u64, u64)>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let len = file.metadata()?.len();
+    if len == 0 {
+        return Ok(None);
+    }
+
+    let fd = file.as_raw_fd();
+    let mut ranges = vec![];
+    let mut offset = 0;
+    while offset < len {
+        let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            let error = io::Error::last_os_error();
+            match error.raw_os_error() {
+                // only a hole is left
+                Some(libc::ENXIO) => break,
+                // the filesystem does not know about holes
+                Some(libc::EINVAL) | Some(libc::ENOTSUP) => return Ok(None),
+                _ => return Err(error),
+            }
+        }
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        ranges.push((start as u64, (end as u64).min(len)));
+        offset = end as u64;
+    }
+
+    match ranges.as_slice() {
+        [(0, end)] if *end == len => Ok(None),
+        _ => Ok(Some(ranges)),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos")))]
+pub fn data_ranges(_file: &File) -> io::Result<Option<Vec<(u64, u64)>>> {
+    Ok(None)
+}
+
+/// Writes `buf`, which belongs at `offset` of the file, but seeks over the
+/// aligned blocks of ZERO_RUN zeros in it instead of writing them
+pub fn write_skipping_zeros(writer: &mut File, buf: &[u8], offset: u64) -> io::Result<()> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        // pieces end on ZERO_RUN boundaries of the file
+        let boundary = ZERO_RUN - ((offset + pos as u64) % ZERO_RUN as u64) as usize;
+        let piece = &buf[pos..buf.len().min(pos + boundary)];
+        match piece.len() == ZERO_RUN && piece.iter().all(|&byte| byte == 0) {
+            true => writer.seek(SeekFrom::Current(piece.len() as i64)).map(|_| ())?,
+            false => writer.write_all(piece)?,
+        }
+        pos += piece.len();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    use tempfile::TempDir;
+
+    /// Writes 64 KiB of data, a 1 MiB hole, 64 KiB of data and a 1 MiB hole
+    /// at the end, and returns what the file reads as
+    pub fn holey(path: &Path) -> Vec<u8> {
+        let mut file = File::create(path).unwrap();
+        file.write_all(&[1; 64 * 1024]).unwrap();
+        file.seek(SeekFrom::Current(1 << 20)).unwrap();
+        file.write_all(&[2; 64 * 1024]).unwrap();
+        file.set_len(2 * (64 * 1024 + (1 << 20))).unwrap();
+        fs::read(path).unwrap()
+    }
+
+    /// The data ranges `holey` writes
+    pub const HOLEY_RANGES: [(u64, u64); 2] = [(0, 64 * 1024), (64 * 1024 + (1 << 20), 2 * 64 * 1024 + (1 << 20))];
+
+    /// The bytes a file takes on disk
+    pub fn on_disk(path: &Path) -> u64 {
+        fs::metadata(path).unwrap().blocks() * 512
+    }
+
+    #[test]
+    fn ranges_of_data() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+
+        let data = holey(&path);
+        assert_eq!(data_ranges(&File::open(&path).unwrap()).unwrap().unwrap(), HOLEY_RANGES);
+        assert!(on_disk(&path) < data.len() as u64 / 4);
+
+        // a file that is all data or empty has no ranges, one that is all
+        // hole has no data
+        fs::write(&path, [1; 4096]).unwrap();
+        assert_eq!(data_ranges(&File::open(&path).unwrap()).unwrap(), None);
+        fs::write(&path, b"").unwrap();
+        assert_eq!(data_ranges(&File::open(&path).unwrap()).unwrap(), None);
+        File::create(&path).unwrap().set_len(1 << 20).unwrap();
+        assert_eq!(data_ranges(&File::open(&path).unwrap()).unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn aligned_zero_runs_are_skipped() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        let buf = [&[1; ZERO_RUN / 2][..], &[0; 5 * ZERO_RUN / 2], &[2; 10]].concat();
+
+        let mut file = File::create(&path).unwrap();
+        write_skipping_zeros(&mut file, &buf, 0).unwrap();
+        file.set_len(buf.len() as u64).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), buf);
+        let ranges = data_ranges(&File::open(&path).unwrap()).unwrap().unwrap();
+        assert_eq!(ranges, [(0, ZERO_RUN as u64), (3 * ZERO_RUN as u64, buf.len() as u64)]);
+
+        // zeros that do not cover a whole aligned block are written
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&[1; 1000]).unwrap();
+        write_skipping_zeros(&mut file, &[0; ZERO_RUN], 1000).unwrap();
+        assert_eq!(fs::read(&path).unwrap().len(), 1000 + ZERO_RUN);
+        assert_eq!(data_ranges(&File::open(&path).unwrap()).unwrap(), None);
+    }
+}
@@ -0,0 +1,203 @@
+//! FIFOs, sockets and device nodes, which are neither files nor
+//! directories.
+//!
+//! `walk` sets them aside instead of queueing them, and --specials decides
+//! what happens next. With copy the FIFOs and device nodes are made again on
+//! a local destination once the backup threads are done, device nodes only
+//! if backr runs as root. Sockets belong to the program listening on them
+//! and are never copied. Every special file that is not made is reported,
+//! with warn and copy in the log as well.
+
+use std::fs;
+use std::path::PathBuf;
+
+use storage::Open;
+
+/// What --specials does with special files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Recreate them on the destination where possible
+    Copy,
+    /// Leave them out and list them
+    Skip,
+    /// Leave them out and list them in the log too
+    Warn,
+}
+
+impl Policy {
+    pub fn parse(value: &str) -> Result<Policy, String> {
+        match value {
+            "copy" => Ok(Policy::Copy),
+            "skip" => Ok(Policy::Skip),
+            "warn" => Ok(Policy::Warn),
+            _ => Err(format!("{:?} is not one of copy, skip or warn", value)),
+        }
+    }
+}
+
+/// A special file found by `walk`
+#[derive(Debug, Clone)]
+pub struct Special {
+    pub src: PathBuf,
+    pub dest: PathBuf,
+    pub meta: fs::Metadata,
+}
+
+/// Returns true if `meta` belongs to a FIFO, socket or device node
+#[cfg(unix)]
+pub fn is_special(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    let kind = meta.file_type();
+    kind.is_fifo() || kind.is_socket() || kind.is_char_device() || kind.is_block_device()
+}
+
+#[cfg(not(unix))]
+pub fn is_special(_meta: &fs::Metadata) -> bool {
+    false
+}
+
+/// Names the kind of a special file for the messages
+#[cfg(unix)]
+fn kind(meta: &fs::Metadata) -> &'static str {
+    use std::os::unix::fs::FileTypeExt;
+
+    let kind = meta.file_type();
+    match () {
+        _ if kind.is_fifo() => "FIFO",
+        _ if kind.is_socket() => "socket",
+        _ if kind.is_char_device() => "character device",
+        _ => "block device",
+    }
+}
+
+#[cfg(not(unix))]
+fn kind(_meta: &fs::Metadata) -> &'static str {
+    "special file"
+}
+
+/// Makes the special files on the destination. Returns the errors it ran
+/// into, and the files that were skipped since the storage or the user
+/// running backr can not make them.
+pub fn create(open: &Open, specials: Vec<Special>, quite: bool) -> (Vec<String>, Vec<Special>) {
+    if specials.is_empty() {
+        return (vec![], vec![]);
+    }
+    if quite {
+        say!("** Creating {} special files", specials.len());
+    }
+
+    let mut storage = match open() {
+        Ok(storage) => storage,
+        Err(error) => return (vec![format!("Error: Failed to open the destination \n {}", error)], vec![]),
+    };
+
+    let (mut errors, mut skipped) = (vec![], vec![]);
+    for special in specials {
+        let made = match special.dest.parent() {
+            Some(parent) => storage
+                .mkdir_all(parent)
+                .and_then(|_| storage.make_special(&special.meta, &special.dest)),
+            None => Ok(false),
+        };
+        match made {
+            Ok(true) => (),
+            Ok(false) => skipped.push(special),
+            Err(error) => {
+                let error = format!("Error: Failed to create {:?} -> {:?} \n {}", special.src, special.dest, error);
+                if quite {
+                    say!("{}", &error);
+                }
+                errors.push(error);
+            }
+        }
+    }
+    (errors, skipped)
+}
+
+/// Lists the special files that were left out. Returns them as warnings for
+/// the log unless the policy is skip.
+pub fn report(skipped: &[Special], policy: Policy, quite: bool) -> Vec<String> {
+    if skipped.is_empty() {
+        return vec![];
+    }
+    if quite {
+        say!("** Skipped {} special files", skipped.len());
+    }
+
+    let mut warnings = vec![];
+    for special in skipped {
+        let message = format!("Skipped the {} {:?}", kind(&special.meta), special.src);
+        if quite {
+            say!("   {}", &message);
+        }
+        if policy != Policy::Skip {
+            warnings.push(format!("Warning: {}", message));
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use tempfile::TempDir;
+
+    use storage::{Local, Storage};
+
+    fn special(src: &Path, dest: &Path) -> Special {
+        Special {
+            src: src.to_path_buf(),
+            dest: dest.to_path_buf(),
+            meta: fs::symlink_metadata(src).unwrap(),
+        }
+    }
+
+    #[test]
+    fn policies() {
+        assert_eq!(Policy::parse("copy"), Ok(Policy::Copy));
+        assert_eq!(Policy::parse("skip"), Ok(Policy::Skip));
+        assert_eq!(Policy::parse("warn"), Ok(Policy::Warn));
+        assert!(Policy::parse("Copy").is_err());
+    }
+
+    #[test]
+    fn fifos_are_made_and_sockets_skipped() {
+        let (src, dest) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let fifo = src.path().join("fifo");
+        let name = CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(name.as_ptr(), 0o640) }, 0);
+        let _listener = UnixListener::bind(src.path().join("socket")).unwrap();
+
+        let specials = vec![
+            special(&fifo, &dest.path().join("dir/fifo")),
+            special(&src.path().join("socket"), &dest.path().join("socket")),
+        ];
+        assert!(specials.iter().all(|special| is_special(&special.meta)));
+        assert!(!is_special(&fs::metadata(src.path()).unwrap()));
+
+        let open: Open = Arc::new(|| Ok(Box::new(Local) as Box<dyn Storage>));
+        let (errors, skipped) = create(&open, specials, false);
+        assert!(errors.is_empty());
+        assert!(fs::symlink_metadata(dest.path().join("dir/fifo")).unwrap().file_type().is_fifo());
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(kind(&skipped[0].meta), "socket");
+        assert!(!dest.path().join("socket").exists());
+
+        // skip only lists them, warn and copy log them too
+        assert!(report(&skipped, Policy::Skip, false).is_empty());
+        for policy in [Policy::Warn, Policy::Copy] {
+            let warnings = report(&skipped, policy, false);
+            assert_eq!(warnings.len(), 1);
+            assert!(warnings[0].starts_with("Warning: Skipped the socket"));
+        }
+    }
+}
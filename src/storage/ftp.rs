@@ -0,0 +1,571 @@
+//! The `ftp://user@host/path` and `ftps://user@host/path` destinations.
+//!
+//! ftps upgrades the control connection with AUTH TLS and protects the data
+//! connections as well. Transfers are binary and passive, EPSV is tried
+//! before PASV. --update compares SIZE and MDTM, and the mtime of uploaded
+//! files is set with MFMT on servers that support it.
+
+use std::collections::HashSet;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use native_tls::{TlsConnector, TlsStream};
+
+use remote::{self, Auth, Url};
+use repo::snapshot;
+
+use super::{Meta, Stat, Storage};
+
+/// How long to wait for the server before giving up
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A control or data connection, with or without TLS
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Stream {
+    /// Closes a data connection, which tells the server the upload is
+    /// complete
+    fn close(self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.shutdown(Shutdown::Write),
+            Stream::Tls(mut stream) => stream.shutdown(),
+        }
+    }
+}
+
+fn tcp(addr: (&str, u16)) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+fn handshake(tls: &TlsConnector, host: &str, stream: TcpStream) -> io::Result<Stream> {
+    tls.connect(host, stream)
+        .map(|stream| Stream::Tls(Box::new(stream)))
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))
+}
+
+/// Reads a reply, which spans several lines if the code is followed by a -
+fn read_reply(control: &mut BufReader<Stream>) -> io::Result<(u32, String)> {
+    let mut reply = String::new();
+    loop {
+        let mut line = String::new();
+        if control.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The server closed the connection",
+            ));
+        }
+        reply.push_str(&line);
+
+        if line.get(..3) == reply.get(..3) && line.get(3..4) == Some(" ") {
+            break;
+        }
+    }
+
+    let code = reply
+        .get(..3)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid reply {:?}", reply)))?;
+    Ok((code, reply[3..].trim().to_string()))
+}
+
+/// Returns `path` as it is sent in a command. CR, LF and NUL would end the
+/// command early and let a file name send commands of its own.
+fn name(path: &Path) -> io::Result<String> {
+    let name = path.display().to_string();
+    match name.contains(['\r', '\n', '\0']) {
+        true => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} can not be sent to an ftp server", path),
+        )),
+        false => Ok(name),
+    }
+}
+
+/// Sends `command`, if any, and fails unless the reply has one of `codes`
+fn expect(control: &mut BufReader<Stream>, command: Option<&str>, codes: &[u32]) -> io::Result<(u32, String)> {
+    if let Some(command) = command {
+        // the user and password come from the url, the paths from name()
+        if command.contains(['\r', '\n', '\0']) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "An ftp command can not span lines"));
+        }
+        let stream = control.get_mut();
+        stream.write_all(format!("{}\r\n", command).as_bytes())?;
+        stream.flush()?;
+    }
+
+    let (code, text) = read_reply(control)?;
+    if codes.contains(&code) {
+        return Ok((code, text));
+    }
+
+    // only the verb, so a password never ends up in the log
+    let verb = command.and_then(|command| command.split(' ').next()).unwrap_or("connecting");
+    Err(io::Error::new(
+        match code {
+            530 => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        },
+        format!("The server answered {} {} to {}", code, text, verb),
+    ))
+}
+
+/// Returns the port of an EPSV reply, ie. `Entering Extended Passive Mode (|||6446|)`
+fn epsv_port(text: &str) -> Option<u16> {
+    let start = text.find('(')?;
+    text[start + 1..].split('|').nth(3)?.parse().ok()
+}
+
+/// Returns the port of a PASV reply, ie. `Entering Passive Mode (10,0,0,2,25,46)`
+fn pasv_port(text: &str) -> Option<u16> {
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let numbers: Vec<u16> = text[start..]
+        .split(|c: char| !c.is_ascii_digit() && c != ',')
+        .next()?
+        .split(',')
+        .map(|number| number.parse().ok())
+        .collect::<Option<_>>()?;
+    match numbers.len() {
+        6 => Some(numbers[4] * 256 + numbers[5]),
+        _ => None,
+    }
+}
+
+/// Parses the `YYYYMMDDHHMMSS[.sss]` time of a MDTM reply, which is in UTC
+fn parse_time(text: &str) -> Option<u64> {
+    let digits = text.get(..14)?;
+    if !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let number = |start: usize, end: usize| digits[start..end].parse::<i64>().unwrap();
+    let days = snapshot::days_from_civil(number(0, 4), number(4, 6), number(6, 8));
+    let time = days * 86_400 + number(8, 10) * 3_600 + number(10, 12) * 60 + number(12, 14);
+    match time >= 0 {
+        true => Some(time as u64),
+        false => None,
+    }
+}
+
+/// Formats seconds since the unix epoch for MFMT
+fn format_time(time: u64) -> String {
+    let (year, month, day) = snapshot::civil_date(time);
+    let secs = time % 86_400;
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
+}
+
+/// A logged in ftp session
+pub struct Client {
+    control: BufReader<Stream>,
+    /// Set for ftps, the data connections use it too
+    tls: Option<TlsConnector>,
+    host: String,
+    /// Data connections go to the address of the control connection
+    peer: IpAddr,
+    /// The directories that are known to exist
+    created: HashSet<PathBuf>,
+}
+
+impl Client {
+    /// Connects and logs in without asking for anything
+    pub fn connect(url: &Url, auth: &Auth) -> io::Result<Client> {
+        let stream = tcp((url.host.as_str(), url.port))?;
+        let peer = stream.peer_addr()?.ip();
+
+        let mut control = BufReader::new(Stream::Plain(stream));
+        expect(&mut control, None, &[220])?;
+
+        let tls = match url.scheme.as_str() {
+            "ftps" => {
+                expect(&mut control, Some("AUTH TLS"), &[234])?;
+                let tls = TlsConnector::new().map_err(io::Error::other)?;
+                let stream = match control.into_inner() {
+                    Stream::Plain(stream) => stream,
+                    Stream::Tls(_) => unreachable!(),
+                };
+                control = BufReader::new(handshake(&tls, &url.host, stream)?);
+                Some(tls)
+            }
+            _ => None,
+        };
+
+        let user = remote::user(url);
+        let (code, _) = expect(&mut control, Some(&format!("USER {}", user)), &[230, 331])?;
+        if code == 331 {
+            let password = auth.password.as_ref().or(url.password.as_ref()).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} needs a password to log in to {}", user, url.host),
+                )
+            })?;
+            expect(&mut control, Some(&format!("PASS {}", password)), &[202, 230])?;
+        }
+
+        if tls.is_some() {
+            expect(&mut control, Some("PBSZ 0"), &[200])?;
+            expect(&mut control, Some("PROT P"), &[200])?;
+        }
+        expect(&mut control, Some("TYPE I"), &[200])?;
+
+        Ok(Client {
+            control,
+            tls,
+            host: url.host.clone(),
+            peer,
+            created: HashSet::new(),
+        })
+    }
+
+    fn command(&mut self, command: &str, codes: &[u32]) -> io::Result<(u32, String)> {
+        expect(&mut self.control, Some(command), codes)
+    }
+
+    /// Opens a passive data connection. The address in a PASV reply is
+    /// often the private one of a server behind NAT, so only the port is
+    /// used.
+    fn passive(&mut self) -> io::Result<TcpStream> {
+        let port = match self.command("EPSV", &[229]) {
+            Ok((_, text)) => epsv_port(&text),
+            Err(_) => pasv_port(&self.command("PASV", &[227])?.1),
+        };
+        let port = port.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Failed to parse the passive mode reply")
+        })?;
+
+        tcp((self.peer.to_string().as_str(), port))
+    }
+
+    /// Opens a data connection for `command`. The TLS handshake of the data
+    /// connection starts after the server accepted the command.
+    fn transfer(&mut self, command: &str) -> io::Result<Stream> {
+        let stream = self.passive()?;
+        self.command(command, &[125, 150])?;
+        match self.tls {
+            Some(ref tls) => handshake(tls, &self.host, stream),
+            None => Ok(Stream::Plain(stream)),
+        }
+    }
+}
+
+impl Storage for Client {
+    fn mkdir_all(&mut self, dir: &Path) -> io::Result<()> {
+        if self.created.contains(dir) || dir.parent().is_none() {
+            return Ok(());
+        }
+
+        let cwd = format!("CWD {}", name(dir)?);
+        if self.command(&cwd, &[250]).is_err() {
+            if let Some(parent) = dir.parent() {
+                self.mkdir_all(parent)?;
+            }
+            // another thread may have created it in the meantime
+            if let Err(error) = self.command(&format!("MKD {}", name(dir)?), &[257]) {
+                if self.command(&cwd, &[250]).is_err() {
+                    return Err(error);
+                }
+            }
+        }
+
+        self.created.insert(dir.to_path_buf());
+        Ok(())
+    }
+
+    fn stat(&mut self, path: &Path) -> io::Result<Option<Stat>> {
+        let path = name(path)?;
+        let size = match self.command(&format!("SIZE {}", path), &[213]) {
+            Ok((_, size)) => size.parse().ok(),
+            Err(_) => None,
+        };
+        let size = match size {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+
+        // without MDTM the file is never up to date
+        let mtime = match self.command(&format!("MDTM {}", path), &[213]) {
+            Ok((_, time)) => parse_time(&time).unwrap_or_default(),
+            Err(_) => 0,
+        };
+        Ok(Some(Stat {
+            size,
+            mtime,
+            // SIZE only works on files
+            is_dir: false,
+        }))
+    }
+
+    fn write(
+        &mut self,
+        path: &Path,
+        meta: Option<Meta>,
+        fill: &mut dyn FnMut(&mut dyn Write) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let path = name(path)?;
+        let command = format!("STOR {}", path);
+        let mut stream = self.transfer(&command)?;
+
+        let filled = fill(&mut stream);
+        let closed = stream.close();
+        // the server answers once the data connection is closed, even after a
+        // failed upload
+        let reply = expect(&mut self.control, None, &[226, 250]);
+
+        filled?;
+        closed?;
+        reply?;
+
+        // both are extensions, a server without them keeps the upload time
+        // and its default permissions
+        if let Some(meta) = meta {
+            let _ = self.command(&format!("MFMT {} {}", format_time(meta.mtime), path), &[213]);
+            let _ = self.command(&format!("SITE CHMOD {:o} {}", meta.mode, path), &[200]);
+        }
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (name(from)?, name(to)?);
+        // some servers only rename onto a file that does not exist
+        let _ = self.command(&format!("DELE {}", to), &[250]);
+        self.command(&format!("RNFR {}", from), &[350])?;
+        self.command(&format!("RNTO {}", to), &[250])?;
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &Path) -> io::Result<()> {
+        match self.command(&format!("DELE {}", name(path)?), &[250]) {
+            Ok(_) => Ok(()),
+            Err(error) => match self.stat(path)? {
+                Some(_) => Err(error),
+                None => Ok(()),
+            },
+        }
+    }
+
+    fn list(&mut self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut stream = self.transfer(&format!("NLST {}", name(dir)?))?;
+        let mut names = String::new();
+        let read = stream.read_to_string(&mut names);
+        let reply = expect(&mut self.control, None, &[226, 250]);
+        read?;
+        reply?;
+
+        // servers answer with names, or with paths relative to the cwd
+        Ok(names
+            .lines()
+            .filter_map(|line| Path::new(line).file_name())
+            .map(|name| dir.join(name))
+            .collect())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _ = self.control.get_mut().write_all(b"QUIT\r\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use tempfile::TempDir;
+
+    use storage::{self, tests::round_trip};
+
+    /// Answers one command of the stand-in server
+    fn answer(control: &mut TcpStream, data: &mut Option<TcpListener>, from: &mut Option<String>, line: &str) -> String {
+        let (verb, arg) = match line.find(' ') {
+            Some(space) => (&line[..space], &line[space + 1..]),
+            None => (line, ""),
+        };
+        let done = |result: io::Result<()>, code: &str| match result {
+            Ok(()) => format!("{} Done", code),
+            Err(error) => format!("550 {}", error),
+        };
+        let mut accept = |control: &mut TcpStream| {
+            control.write_all(b"150 Opening the data connection\r\n").unwrap();
+            data.take().unwrap().accept().unwrap().0
+        };
+
+        match verb {
+            "USER" => "331 Send the password".to_string(),
+            "PASS" if arg == "secret" => "230 Logged in".to_string(),
+            "PASS" => "530 Wrong password".to_string(),
+            "TYPE" => "200 Binary".to_string(),
+            "EPSV" => {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let port = listener.local_addr().unwrap().port();
+                *data = Some(listener);
+                format!("229 Entering Extended Passive Mode (|||{}|)", port)
+            }
+            "CWD" if Path::new(arg).is_dir() => "250 Changed".to_string(),
+            "MKD" => done(fs::create_dir(arg), "257"),
+            "SIZE" => match fs::metadata(arg) {
+                Ok(ref meta) if meta.is_file() => format!("213 {}", meta.len()),
+                _ => "550 Not a file".to_string(),
+            },
+            "MDTM" => match fs::metadata(arg) {
+                Ok(meta) => format!("213 {}", format_time(storage::mtime(&meta))),
+                Err(error) => format!("550 {}", error),
+            },
+            "MFMT" => {
+                let (time, path) = arg.split_at(arg.find(' ').unwrap());
+                let time = ::filetime::FileTime::from_unix_time(parse_time(time).unwrap() as i64, 0);
+                done(::filetime::set_file_mtime(&path[1..], time), "213")
+            }
+            "SITE" => {
+                use std::os::unix::fs::PermissionsExt;
+
+                let fields: Vec<&str> = arg.splitn(3, ' ').collect();
+                let mode = u32::from_str_radix(fields[1], 8).unwrap();
+                done(fs::set_permissions(fields[2], fs::Permissions::from_mode(mode)), "200")
+            }
+            "DELE" => done(fs::remove_file(arg), "250"),
+            "RNFR" => {
+                *from = Some(arg.to_string());
+                "350 Send the new name".to_string()
+            }
+            "RNTO" => done(fs::rename(from.take().unwrap(), arg), "250"),
+            "STOR" => {
+                let mut contents = vec![];
+                let read = accept(control).read_to_end(&mut contents);
+                done(read.and_then(|_| fs::write(arg, contents)), "226")
+            }
+            "NLST" => {
+                let mut stream = accept(control);
+                for entry in fs::read_dir(arg).unwrap() {
+                    writeln!(stream, "{}", entry.unwrap().path().display()).unwrap();
+                }
+                "226 Sent".to_string()
+            }
+            _ => "502 Not implemented".to_string(),
+        }
+    }
+
+    /// Starts a stand-in ftp server on the paths of this machine, which
+    /// speaks just enough of the protocol for the client, and returns its
+    /// port and the commands it received
+    fn serve() -> (u16, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let log = Arc::new(Mutex::new(vec![]));
+
+        let received = log.clone();
+        thread::spawn(move || {
+            for control in listener.incoming() {
+                let mut control = control.unwrap();
+                let received = received.clone();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(control.try_clone().unwrap());
+                    control.write_all(b"220 Ready\r\n").unwrap();
+                    let (mut data, mut from) = (None, None);
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                        let command = line.trim_end_matches("\r\n").to_string();
+                        line.clear();
+                        received.lock().unwrap().push(command.clone());
+                        if command == "QUIT" {
+                            break;
+                        }
+                        let reply = answer(&mut control, &mut data, &mut from, &command);
+                        control.write_all(format!("{}\r\n", reply).as_bytes()).unwrap();
+                    }
+                });
+            }
+        });
+        (port, log)
+    }
+
+    #[test]
+    fn round_trip_against_a_stand_in() {
+        let (port, received) = serve();
+        let root = TempDir::new().unwrap();
+        let url = Url::parse(&format!("ftp://backr@127.0.0.1:{}{}", port, root.path().display())).unwrap();
+        let auth = |password: &str| Auth {
+            key: None,
+            password: Some(password.to_string()),
+        };
+
+        let refused = Client::connect(&url, &auth("wrong")).err().unwrap();
+        assert_eq!(refused.kind(), io::ErrorKind::PermissionDenied);
+        // only the verb of a failed command is shown
+        assert!(!refused.to_string().contains("wrong"));
+
+        let mut client = Client::connect(&url, &auth("secret")).unwrap();
+        round_trip(&mut client, root.path(), &|path| fs::read(path).ok());
+
+        // a name can not smuggle in a command of its own
+        let sneaky = root.path().join("file\r\nDELE important");
+        let error = client.write(&sneaky, None, &mut |_| Ok(())).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(client.stat(&sneaky).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(client.stat(&root.path().join("missing")).unwrap(), None);
+        assert!(!received.lock().unwrap().iter().any(|command| command.starts_with("DELE important")));
+    }
+
+    #[test]
+    fn replies() {
+        assert_eq!(epsv_port("Entering Extended Passive Mode (|||6446|)"), Some(6446));
+        assert_eq!(epsv_port("Entering Extended Passive Mode"), None);
+        assert_eq!(pasv_port("Entering Passive Mode (10,0,0,2,25,46)."), Some(25 * 256 + 46));
+        assert_eq!(pasv_port("Entering Passive Mode 10,0,0,2,25,46"), Some(25 * 256 + 46));
+        assert_eq!(pasv_port("Entering Passive Mode (10,0,0,2,25)"), None);
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("20240229235959"), Some(1_709_251_199));
+        assert_eq!(parse_time("19700101000000.123"), Some(0));
+        assert_eq!(parse_time("2024022923595"), None);
+        assert_eq!(parse_time("2024-02-29 23:59"), None);
+        for &time in &[0, 951_782_400, 1_709_251_199, 4_107_542_400] {
+            assert_eq!(parse_time(&format_time(time)), Some(time));
+        }
+    }
+}
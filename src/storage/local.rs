@@ -0,0 +1,251 @@
+//! The filesystem of this machine.
+
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use filetime::{self, FileTime};
+
+use copy;
+use delta;
+use throttle::Throttle;
+
+use super::{mtime, Meta, Stat, Storage};
+
+/// Paths are used as they are
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Local;
+
+impl Storage for Local {
+    fn stat(&mut self, path: &Path) -> io::Result<Option<Stat>> {
+        match fs::metadata(path) {
+            Ok(meta) => Ok(Some(Stat {
+                size: meta.len(),
+                mtime: mtime(&meta),
+                is_dir: meta.is_dir(),
+            })),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn mkdir_all(&mut self, dir: &Path) -> io::Result<()> {
+        match dir.is_dir() {
+            true => Ok(()),
+            false => DirBuilder::new().recursive(true).create(dir),
+        }
+    }
+
+    fn write(
+        &mut self,
+        path: &Path,
+        meta: Option<Meta>,
+        fill: &mut dyn FnMut(&mut dyn Write) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        fill(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        meta.map_or(Ok(()), |meta| set_meta(path, meta))
+    }
+
+    // fs::copy uses the fastest way the platform has
+    fn copy_file(
+        &mut self,
+        src: &Path,
+        path: &Path,
+        meta: Option<Meta>,
+        throttle: Option<&Throttle>,
+        sparse: bool,
+    ) -> io::Result<()> {
+        copy::copy(src, path, throttle, sparse)?;
+        meta.map_or(Ok(()), |meta| set_meta(path, meta))
+    }
+
+    fn patch_file(&mut self, src: &Path, path: &Path, meta: Meta, throttle: Option<&Throttle>) -> io::Result<bool> {
+        // the copy is patched under the part name, writing bumps its mtime
+        // and a half patched file must not pass for an up to date one. The
+        // next backup patches the part of an interrupted patch again.
+        let part = copy::part_path(path);
+        if delta::open_existing(path).is_some() {
+            fs::rename(path, &part)?;
+        }
+        let file = match delta::open_existing(&part) {
+            Some(file) => file,
+            None => return Ok(false),
+        };
+        delta::patch(src, &file, throttle)?;
+        drop(file);
+        set_meta(&part, meta)?;
+        fs::rename(&part, path).map(|_| true)
+    }
+
+    fn hard_link(&mut self, target: &Path, path: &Path) -> io::Result<bool> {
+        // renaming over another name of the same file does nothing
+        if same_file(target, path) {
+            return Ok(true);
+        }
+        let part = copy::part_path(path);
+        self.remove(&part)?;
+        fs::hard_link(target, &part)?;
+        fs::rename(&part, path).map(|_| true)
+    }
+
+    fn set_meta(&mut self, path: &Path, meta: Meta) -> io::Result<()> {
+        set_meta(path, meta)
+    }
+
+    #[cfg(unix)]
+    fn make_special(&mut self, meta: &fs::Metadata, path: &Path) -> io::Result<bool> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        // a socket is only made by the program that listens on it
+        if meta.file_type().is_socket() {
+            return Ok(false);
+        }
+        let name = CString::new(path.as_os_str().as_bytes())
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+
+        self.remove(path)?;
+        if unsafe { libc::mknod(name.as_ptr(), meta.mode() as libc::mode_t, meta.rdev() as libc::dev_t) } != 0 {
+            let error = io::Error::last_os_error();
+            return match error.raw_os_error() {
+                // device nodes need root
+                Some(libc::EPERM) => Ok(false),
+                _ => Err(error),
+            };
+        }
+        // set_file_mtime opens the file, which blocks on a FIFO
+        fs::set_permissions(path, meta.permissions())?;
+        let mtime = FileTime::from_unix_time(mtime(meta) as i64, 0);
+        filetime::set_symlink_file_times(path, FileTime::from_last_access_time(meta), mtime).map(|_| true)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove(&mut self, path: &Path) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn list(&mut self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?.map(|entry| entry.map(|entry| entry.path())).collect()
+    }
+}
+
+/// Returns true if both paths are names of the same file
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Sets the permissions and mtime of a file or directory. The mtime goes
+/// first, setting it opens the file, which the permissions may not allow.
+pub fn set_meta(path: &Path, meta: Meta) -> io::Result<()> {
+    filetime::set_file_mtime(path, FileTime::from_unix_time(meta.mtime as i64, 0))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(meta.mode))?;
+    }
+    #[cfg(not(unix))]
+    {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(meta.mode & 0o200 == 0);
+        fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use tempfile::TempDir;
+
+    use delta;
+    use storage::tests::round_trip;
+
+    #[test]
+    fn round_trip_on_this_machine() {
+        let root = TempDir::new().unwrap();
+        round_trip(&mut Local, root.path(), &|path| fs::read(path).ok());
+    }
+
+    #[test]
+    fn meta_of_locked_files_and_dirs() {
+        let dir = TempDir::new().unwrap();
+        let (file, locked) = (dir.path().join("file"), dir.path().join("locked"));
+        fs::write(&file, b"file").unwrap();
+        fs::create_dir(&locked).unwrap();
+
+        // the permissions come last, so neither keeps the mtime from being set
+        for (path, mode) in [(&file, 0o000), (&locked, 0o500)] {
+            set_meta(path, Meta { mtime: 1_600_000_000, mode }).unwrap();
+            let meta = fs::metadata(path).unwrap();
+            assert_eq!((mtime(&meta), meta.permissions().mode() & 0o7777), (1_600_000_000, mode));
+        }
+    }
+
+    #[test]
+    fn links_over_the_same_file() {
+        let dir = TempDir::new().unwrap();
+        let (target, path) = (dir.path().join("target"), dir.path().join("path"));
+        fs::write(&target, b"target").unwrap();
+        fs::write(&path, b"an older copy").unwrap();
+
+        let inode = |path: &Path| fs::metadata(path).unwrap().ino();
+        for _ in 0..2 {
+            assert!(Local.hard_link(&target, &path).unwrap());
+            assert_eq!(inode(&path), inode(&target));
+            assert_eq!(fs::metadata(&target).unwrap().nlink(), 2);
+            assert!(!copy::part_path(&path).exists());
+        }
+        assert!(Local.hard_link(&target, &target).unwrap());
+        assert_eq!(fs::read(&target).unwrap(), b"target");
+    }
+
+    #[test]
+    fn interrupted_patches_are_patched_again() {
+        let dir = TempDir::new().unwrap();
+        let (src, dest) = (dir.path().join("src"), dir.path().join("dest"));
+        let meta = Meta {
+            mtime: 1_600_000_000,
+            mode: 0o640,
+        };
+        fs::write(&src, [1; 100_000]).unwrap();
+        fs::write(&dest, [2; 100_000]).unwrap();
+
+        // reading a directory fails after the copy was moved aside
+        assert!(Local.patch_file(dir.path(), &dest, meta, None).is_err());
+        assert!(Local.stat(&dest).unwrap().is_none());
+        assert!(copy::part_path(&dest).exists());
+
+        assert!(Local.patch_file(&src, &dest, meta, None).unwrap());
+        assert_eq!(fs::read(&dest).unwrap(), [1; 100_000]);
+        assert_eq!(Local.stat(&dest).unwrap().unwrap().mtime, 1_600_000_000);
+        assert!(!copy::part_path(&dest).exists());
+
+        assert!(!Local.patch_file(&src, &dir.path().join("missing"), meta, None).unwrap());
+        assert!(delta::open_existing(&dir.path().join("missing")).is_none());
+    }
+}
@@ -0,0 +1,389 @@
+//! Where a plain backup is written to. The backup threads, `walk` and
+//! `check_permissions` only talk to a `Storage`, so a new kind of
+//! destination only has to implement it. `Local` is the filesystem of this
+//! machine, `sftp::Client`, `ftp::Client`, `s3::Client` and
+//! `webdav::Client` are the remote destinations of `remote`.
+
+pub mod ftp;
+pub mod local;
+pub mod s3;
+pub mod sftp;
+pub mod webdav;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use repo::snapshot;
+use throttle::{Throttle, Throttled};
+
+pub use self::local::Local;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// What a storage knows about a path
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stat {
+    pub size: u64,
+    /// Seconds since the unix epoch
+    pub mtime: u64,
+    pub is_dir: bool,
+}
+
+/// The permissions and mtime a file is written with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Meta {
+    pub mode: u32,
+    /// Seconds since the unix epoch
+    pub mtime: u64,
+}
+
+impl Meta {
+    pub fn of(meta: &fs::Metadata) -> Meta {
+        Meta {
+            mode: mode(meta),
+            mtime: mtime(meta),
+        }
+    }
+}
+
+/// Opens a storage, once for every backup thread
+pub type Open = Arc<dyn Fn() -> io::Result<Box<dyn Storage>> + Send + Sync>;
+
+pub trait Storage {
+    /// Returns what is known about `path`, or None if it does not exist
+    fn stat(&mut self, path: &Path) -> io::Result<Option<Stat>>;
+
+    /// Creates `dir` and every missing parent. Called for every file, so
+    /// directories known to exist should be remembered.
+    fn mkdir_all(&mut self, dir: &Path) -> io::Result<()>;
+
+    /// Creates or truncates `path` and hands `fill` a writer for it. The file
+    /// is complete once this returns, and has the permissions and mtime of
+    /// `meta` as far as the storage allows.
+    fn write(
+        &mut self,
+        path: &Path,
+        meta: Option<Meta>,
+        fill: &mut dyn FnMut(&mut dyn Write) -> io::Result<()>,
+    ) -> io::Result<()>;
+
+    /// Copies a local file to `path`. A storage that can do better than
+    /// `write` overrides it. A storage that can make holes keeps those of
+    /// `src`, and with `sparse` turns long runs of zeros into holes too.
+    fn copy_file(
+        &mut self,
+        src: &Path,
+        path: &Path,
+        meta: Option<Meta>,
+        throttle: Option<&Throttle>,
+        _sparse: bool,
+    ) -> io::Result<()> {
+        let mut reader = File::open(src)?;
+        self.write(path, meta, &mut |writer| {
+            io::copy(&mut reader, &mut Throttled::new(writer, throttle)).map(|_| ())
+        })
+    }
+
+    /// Rewrites only the parts of `path` that differ from the local file
+    /// `src`, see delta.rs. Returns false if the storage can not or there is
+    /// no `path` yet, and the file has to be written whole.
+    fn patch_file(&mut self, _src: &Path, _path: &Path, _meta: Meta, _throttle: Option<&Throttle>) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Makes `path` another name of the file `target`, replacing `path`.
+    /// Returns false if the storage can not.
+    fn hard_link(&mut self, _target: &Path, _path: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Gives an existing file or directory the permissions and mtime of
+    /// `meta`, as far as the storage allows
+    fn set_meta(&mut self, _path: &Path, _meta: Meta) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Makes a FIFO or device node like the one `meta` belongs to at
+    /// `path`, replacing `path`. Returns false if the storage, the platform
+    /// or the user running backr can not.
+    fn make_special(&mut self, _meta: &fs::Metadata, _path: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Renames `from` to `to`, replacing `to`
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Removes a file. A file that does not exist is not an error.
+    fn remove(&mut self, path: &Path) -> io::Result<()>;
+
+    /// Returns the paths in `dir`
+    fn list(&mut self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// True if a file only shows up once `write` is complete, so it does not
+    /// have to be written under a temporary name first
+    fn atomic(&self) -> bool {
+        false
+    }
+}
+
+/// Returns the mtime of a file in seconds since the unix epoch
+pub fn mtime(meta: &fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map(|mtime| mtime.as_secs())
+        .unwrap_or_default()
+}
+
+/// Returns the device a file is on, or None if the platform can not tell
+#[cfg(unix)]
+pub fn device(meta: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.dev())
+}
+
+#[cfg(not(unix))]
+pub fn device(_meta: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Returns the permission bits of a file
+#[cfg(unix)]
+pub fn mode(meta: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+pub fn mode(meta: &fs::Metadata) -> u32 {
+    match meta.permissions().readonly() {
+        true => 0o444,
+        false => 0o644,
+    }
+}
+
+/// Parses a http date, ie. `Wed, 21 Oct 2015 07:28:00 GMT`
+fn parse_date(text: &str) -> Option<u64> {
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    if fields.len() != 6 {
+        return None;
+    }
+
+    let day = fields[1].parse().ok()?;
+    let month = MONTHS.iter().position(|month| *month == fields[2])? as i64 + 1;
+    let year = fields[3].parse().ok()?;
+    let clock = fields[4]
+        .split(':')
+        .map(|field| field.parse().ok())
+        .collect::<Option<Vec<i64>>>()?;
+    if clock.len() != 3 {
+        return None;
+    }
+
+    let time = snapshot::days_from_civil(year, month, day) * 86_400 + clock[0] * 3_600 + clock[1] * 60 + clock[2];
+    match time >= 0 {
+        true => Some(time as u64),
+        false => None,
+    }
+}
+
+/// Undoes the escapes of xml text
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use remote;
+
+    /// A request to a stand-in http server
+    #[derive(Debug)]
+    pub struct Request {
+        pub method: String,
+        /// The path as it was sent, percent encoded
+        pub path: String,
+        /// The decoded query parameters
+        pub query: Vec<(String, String)>,
+        /// The headers with lowercase names
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl Request {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(header, _)| header == name)
+                .map(|(_, value)| value.as_str())
+        }
+
+        pub fn param(&self, name: &str) -> Option<&str> {
+            self.query
+                .iter()
+                .find(|(param, _)| param == name)
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    /// The status, headers and body of an answer. A Content-Length header
+    /// replaces the length of the body, for answers to HEAD.
+    pub type Response = (u16, Vec<(&'static str, String)>, Vec<u8>);
+
+    /// Reads a request, with a body of a known length or in chunks. Fails if
+    /// the connection ends before the request does.
+    fn read_request(reader: &mut BufReader<TcpStream>) -> io::Result<Request> {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut fields = line.split_whitespace();
+        let (method, target) = match (fields.next(), fields.next()) {
+            (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+            _ => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "No request")),
+        };
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), query),
+            None => (target.clone(), ""),
+        };
+        let query = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                (remote::decode(name).unwrap(), remote::decode(value).unwrap())
+            }).collect();
+
+        let mut headers = vec![];
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            match line.trim_end().split_once(':') {
+                Some((name, value)) => headers.push((name.to_lowercase(), value.trim().to_string())),
+                None => break,
+            }
+        }
+
+        let mut request = Request {
+            method,
+            path,
+            query,
+            headers,
+            body: vec![],
+        };
+        if request.header("transfer-encoding") == Some("chunked") {
+            loop {
+                line.clear();
+                reader.read_line(&mut line)?;
+                let len = usize::from_str_radix(line.trim(), 16)
+                    .map_err(|_| io::Error::new(io::ErrorKind::UnexpectedEof, "The chunks ended early"))?;
+                let mut chunk = vec![0; len + 2];
+                reader.read_exact(&mut chunk)?;
+                if len == 0 {
+                    break;
+                }
+                request.body.extend_from_slice(&chunk[..len]);
+            }
+        } else if let Some(len) = request.header("content-length").and_then(|len| len.parse().ok()) {
+            request.body = vec![0; len];
+            reader.read_exact(&mut request.body)?;
+        }
+        Ok(request)
+    }
+
+    /// Starts a stand-in http server on a port of localhost and returns the
+    /// port. Every request is answered by `handler` on a connection of its
+    /// own, a request that is cut short is never handed to it.
+    pub fn http<F>(handler: F) -> u16
+    where
+        F: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handler = Arc::new(handler);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (stream, handler) = (stream.unwrap(), handler.clone());
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let request = match read_request(&mut reader) {
+                        Ok(request) => request,
+                        Err(_) => return,
+                    };
+                    let (status, headers, body) = handler(request);
+
+                    let mut answer = format!("HTTP/1.1 {} Stand-in\r\nConnection: close\r\n", status);
+                    if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("content-length")) {
+                        answer.push_str(&format!("Content-Length: {}\r\n", body.len()));
+                    }
+                    for (name, value) in headers {
+                        answer.push_str(&format!("{}: {}\r\n", name, value));
+                    }
+                    answer.push_str("\r\n");
+                    let mut stream = stream;
+                    let _ = stream.write_all(answer.as_bytes()).and_then(|_| stream.write_all(&body));
+                });
+            }
+        });
+        port
+    }
+
+    /// Runs `storage` through the calls a backup makes below `root`.
+    /// `read` returns what the server behind the storage holds at a path.
+    pub fn round_trip(storage: &mut dyn Storage, root: &Path, read: &dyn Fn(&Path) -> Option<Vec<u8>>) {
+        let (dir, file, renamed) = (root.join("a/b"), root.join("a/b/file"), root.join("a/b/renamed"));
+        let meta = Meta {
+            mode: 0o640,
+            mtime: 1_600_000_000,
+        };
+
+        assert_eq!(storage.stat(&file).unwrap(), None);
+        storage.mkdir_all(&dir).unwrap();
+        storage.mkdir_all(&dir).unwrap();
+
+        storage
+            .write(&file, Some(meta), &mut |writer| writer.write_all(b"first version"))
+            .unwrap();
+        storage.write(&file, Some(meta), &mut |writer| writer.write_all(b"second")).unwrap();
+        assert_eq!(read(&file).unwrap(), b"second");
+        assert_eq!(
+            storage.stat(&file).unwrap(),
+            Some(Stat {
+                size: 6,
+                mtime: meta.mtime,
+                is_dir: false,
+            })
+        );
+
+        // a large file takes more than one write
+        let large: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let src = tempfile::NamedTempFile::new().unwrap();
+        fs::write(src.path(), &large).unwrap();
+        storage.copy_file(src.path(), &renamed, None, None, false).unwrap();
+        assert!(read(&renamed).unwrap() == large);
+
+        storage.rename(&file, &renamed).unwrap();
+        assert_eq!(storage.stat(&file).unwrap(), None);
+        assert_eq!(read(&renamed).unwrap(), b"second");
+        assert_eq!(storage.list(&dir).unwrap(), vec![renamed.clone()]);
+
+        storage.remove(&renamed).unwrap();
+        storage.remove(&renamed).unwrap();
+        assert_eq!(storage.stat(&renamed).unwrap(), None);
+        assert_eq!(read(&renamed), None);
+        assert!(storage.list(&dir).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,542 @@
+//! The `webdav://user@host/path` destination, `webdavs://` for https, ie.
+//! Nextcloud at `webdavs://me@cloud.example.com/remote.php/dav/files/me`.
+//!
+//! Collections are created with MKCOL, files uploaded with PUT and looked at
+//! with PROPFIND. Servers store an upload under a temporary name until it is
+//! complete, so files are written under their final name. Nextcloud and
+//! ownCloud keep the mtime sent in X-OC-Mtime, other servers the upload time,
+//! which is newer than the file and good enough for --update. There are no
+//! permissions to keep.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use native_tls::TlsConnector;
+use ureq::{Agent, AgentBuilder, Request, Response};
+
+use remote::{self, decode, encode, Auth, Url};
+use throttle::{Throttle, Throttled};
+
+use super::{parse_date, unescape, Meta, Stat, Storage};
+
+/// What PROPFIND asks for
+const PROPERTIES: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+    <d:propfind xmlns:d=\"DAV:\"><d:prop>\
+    <d:resourcetype/><d:getcontentlength/><d:getlastmodified/>\
+    </d:prop></d:propfind>";
+
+/// A WebDAV server and the credentials to use it
+pub struct Client {
+    agent: Agent,
+    /// ie. `https://host:8443`, paths are appended to it
+    base: String,
+    /// The Authorization header, once there is a password
+    authorization: Option<String>,
+    /// The collections that are known to exist
+    created: HashSet<PathBuf>,
+}
+
+impl Client {
+    /// Logs in by looking at the destination, which must be a collection if
+    /// it exists already
+    pub fn connect(url: &Url, auth: &Auth) -> io::Result<Client> {
+        let tls = TlsConnector::new().map_err(io::Error::other)?;
+        let agent = AgentBuilder::new()
+            .tls_connector(Arc::new(tls))
+            .timeout_connect(Duration::from_secs(30))
+            .build();
+
+        let (scheme, port) = match url.scheme.as_str() {
+            "webdavs" => ("https", 443),
+            _ => ("http", 80),
+        };
+        let mut base = match url.host.contains(':') {
+            true => format!("{}://[{}]", scheme, url.host),
+            false => format!("{}://{}", scheme, url.host),
+        };
+        if url.port != port {
+            base.push_str(&format!(":{}", url.port));
+        }
+
+        let authorization = auth.password.as_ref().or(url.password.as_ref()).map(|password| {
+            let credentials = format!("{}:{}", remote::user(url), password);
+            format!("Basic {}", STANDARD.encode(credentials))
+        });
+
+        let mut client = Client {
+            agent,
+            base,
+            authorization,
+            created: HashSet::new(),
+        };
+        match client.stat(&url.path)? {
+            Some(ref stat) if !stat.is_dir => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is a file, not a collection", url.path.display()),
+            )),
+            _ => Ok(client),
+        }
+    }
+
+    fn request(&self, method: &str, path: &Path) -> Request {
+        let url = format!("{}{}", self.base, encode(&path.to_string_lossy(), false));
+        let request = self.agent.request(method, &url);
+        match self.authorization {
+            Some(ref authorization) => request.set("Authorization", authorization),
+            None => request,
+        }
+    }
+
+    /// Returns the properties of `path` and, with a depth of 1, of everything
+    /// in it, as the path and the xml of each `response` element
+    fn propfind(&self, path: &Path, depth: &str) -> io::Result<Vec<(PathBuf, String)>> {
+        let response = check(
+            self.request("PROPFIND", path)
+                .set("Depth", depth)
+                .set("Content-Type", "application/xml; charset=utf-8")
+                .send_string(PROPERTIES),
+        )?;
+        let body = response.into_string()?;
+
+        let mut found = vec![];
+        for block in elements(&body, "response") {
+            let href = match elements(block, "href").first() {
+                Some(href) => unescape(href),
+                None => continue,
+            };
+            // hrefs are either absolute paths or whole urls
+            let href = match href.find("://") {
+                Some(start) => match href[start + 3..].find('/') {
+                    Some(end) => href[start + 3 + end..].to_string(),
+                    None => "/".to_string(),
+                },
+                None => href,
+            };
+            let href = decode(&href).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+            found.push((PathBuf::from(href.trim_end_matches('/')), block.to_string()));
+        }
+        Ok(found)
+    }
+}
+
+impl Storage for Client {
+    fn stat(&mut self, path: &Path) -> io::Result<Option<Stat>> {
+        let found = match self.propfind(path, "0") {
+            Ok(found) => found,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let block = match found.first() {
+            Some((_, block)) => block,
+            None => return Ok(None),
+        };
+
+        let text = |name: &str| elements(block, name).first().map(|text| unescape(text));
+        Ok(Some(Stat {
+            size: text("getcontentlength").and_then(|size| size.trim().parse().ok()).unwrap_or_default(),
+            mtime: text("getlastmodified").and_then(|mtime| parse_date(&mtime)).unwrap_or_default(),
+            is_dir: !elements(block, "collection").is_empty(),
+        }))
+    }
+
+    fn mkdir_all(&mut self, dir: &Path) -> io::Result<()> {
+        if dir.parent().is_none() || self.created.contains(dir) {
+            return Ok(());
+        }
+
+        match self.request("MKCOL", dir).call() {
+            // 405 means the collection exists already
+            Ok(_) | Err(ureq::Error::Status(405, _)) => {}
+            // 409 means the parent is missing
+            Err(ureq::Error::Status(409, _)) => {
+                if let Some(parent) = dir.parent() {
+                    self.mkdir_all(parent)?;
+                }
+                check(self.request("MKCOL", dir).call())?;
+            }
+            result => {
+                check(result)?;
+            }
+        }
+        self.created.insert(dir.to_path_buf());
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        path: &Path,
+        meta: Option<Meta>,
+        fill: &mut dyn FnMut(&mut dyn Write) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let request = with_meta(self.request("PUT", path), meta);
+        let (sender, receiver) = mpsc::sync_channel(8);
+        let end = sender.clone();
+
+        // the request reads what fill writes, as a chunked upload
+        thread::scope(|scope| {
+            let upload = scope.spawn(move || {
+                check(request.send(Pipe {
+                    receiver,
+                    buf: vec![],
+                    pos: 0,
+                    done: false,
+                }))
+            });
+
+            let mut feed = BufWriter::with_capacity(64 * 1024, Feed(sender));
+            let filled = fill(&mut feed).and_then(|_| feed.flush());
+            drop(feed);
+
+            // the upload is only complete with the end marker, an error
+            // aborts it, so the server never stores a truncated file
+            let _ = end.send(match filled {
+                Ok(_) => Ok(vec![]),
+                Err(ref error) => Err(io::Error::new(error.kind(), error.to_string())),
+            });
+            drop(end);
+
+            // a failed upload makes fill fail too, its error says more
+            let sent = upload.join().unwrap_or_else(|_| Err(io::Error::other("The upload thread panicked")));
+            filled.and(sent.map(|_| ()))
+        })
+    }
+
+    fn copy_file(
+        &mut self,
+        src: &Path,
+        path: &Path,
+        meta: Option<Meta>,
+        throttle: Option<&Throttle>,
+        _sparse: bool,
+    ) -> io::Result<()> {
+        let file = File::open(src)?;
+        let len = file.metadata()?.len();
+        let request = with_meta(self.request("PUT", path), meta).set("Content-Length", &len.to_string());
+        check(request.send(Throttled::new(file, throttle))).map(|_| ())
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        let destination = format!("{}{}", self.base, encode(&to.to_string_lossy(), false));
+        check(
+            self.request("MOVE", from)
+                .set("Destination", &destination)
+                .set("Overwrite", "T")
+                .call(),
+        )
+        .map(|_| ())
+    }
+
+    fn remove(&mut self, path: &Path) -> io::Result<()> {
+        match check(self.request("DELETE", path).call()) {
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    fn list(&mut self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let found = self.propfind(dir, "1")?;
+        // the first response is the collection itself
+        Ok(found.into_iter().map(|(path, _)| path).filter(|path| path != dir).collect())
+    }
+
+    fn atomic(&self) -> bool {
+        true
+    }
+}
+
+/// Adds the mtime header Nextcloud and ownCloud understand
+fn with_meta(request: Request, meta: Option<Meta>) -> Request {
+    match meta {
+        Some(meta) => request.set("X-OC-Mtime", &meta.mtime.to_string()),
+        None => request,
+    }
+}
+
+/// Turns anything but a 2xx answer into an error
+fn check(result: Result<Response, ureq::Error>) -> io::Result<Response> {
+    match result {
+        Ok(response) => Ok(response),
+        Err(ureq::Error::Status(status, response)) => {
+            let kind = match status {
+                401 | 403 => io::ErrorKind::PermissionDenied,
+                404 => io::ErrorKind::NotFound,
+                _ => io::ErrorKind::Other,
+            };
+            Err(io::Error::new(
+                kind,
+                format!("The server answered {} {}", status, response.status_text()),
+            ))
+        }
+        Err(ureq::Error::Transport(transport)) => Err(io::Error::other(transport.to_string())),
+    }
+}
+
+/// The writing end of a chunked upload. An empty buffer marks the end of the
+/// upload, an error aborts it.
+struct Feed(SyncSender<io::Result<Vec<u8>>>);
+
+impl Write for Feed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.0
+            .send(Ok(buf.to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The upload was cancelled"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The reading end of a chunked upload. It ends at the end marker, and
+/// fails if the Feed is dropped without one.
+struct Pipe {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl Read for Pipe {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            match self.receiver.recv() {
+                Ok(Ok(buf)) => {
+                    self.done = buf.is_empty();
+                    self.buf = buf;
+                    self.pos = 0;
+                }
+                Ok(Err(error)) => return Err(error),
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "The upload ended without its end marker",
+                    ))
+                }
+            }
+        }
+
+        let len = out.len().min(self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Returns the contents of every element called `name`, whatever namespace
+/// prefix the server gave it. Empty elements like `<d:collection/>` are
+/// returned as "".
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let mut found = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = &rest[..end];
+        let qualified = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or("");
+        let local = qualified.rsplit(':').next().unwrap_or("");
+        if tag.starts_with('/') || local != name {
+            continue;
+        }
+
+        rest = &rest[end + 1..];
+        if tag.ends_with('/') {
+            found.push("");
+            continue;
+        }
+        let close = format!("</{}>", qualified);
+        match rest.find(&close) {
+            Some(end) => {
+                found.push(&rest[..end]);
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use repo::snapshot;
+    use storage::tests::{http, round_trip, Request as Received, Response as Answer};
+    use storage::{self, MONTHS};
+
+    /// Formats a time like `getlastmodified`
+    fn http_date(time: u64) -> String {
+        let (year, month, day) = snapshot::civil_date(time);
+        let weekday = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"][(time / 86_400 % 7) as usize];
+        let secs = time % 86_400;
+        format!(
+            "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            weekday,
+            day,
+            MONTHS[month as usize - 1],
+            year,
+            secs / 3_600,
+            secs % 3_600 / 60,
+            secs % 60
+        )
+    }
+
+    /// The `response` element of a PROPFIND answer for `path`, under `href`
+    fn property(path: &Path, href: String) -> String {
+        let meta = fs::metadata(path).unwrap();
+        let kind = match meta.is_dir() {
+            true => "<d:collection/>",
+            false => "",
+        };
+        format!(
+            "<d:response><d:href>{}</d:href><d:propstat><d:prop>\
+             <d:resourcetype>{}</d:resourcetype>\
+             <d:getcontentlength>{}</d:getcontentlength>\
+             <d:getlastmodified>{}</d:getlastmodified>\
+             </d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+            href,
+            kind,
+            meta.len(),
+            http_date(storage::mtime(&meta))
+        )
+    }
+
+    /// A stand-in WebDAV server on the paths of this machine
+    fn handle(request: Received, base: &str) -> Answer {
+        if request.header("authorization") != Some(&format!("Basic {}", STANDARD.encode("backr:secret"))) {
+            return (401, vec![("WWW-Authenticate", "Basic".to_string())], vec![]);
+        }
+        let path = PathBuf::from(decode(&request.path).unwrap());
+        let done = |result: io::Result<()>, status: u16| match result {
+            Ok(()) => (status, vec![], vec![]),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => (409, vec![], vec![]),
+            Err(_) => (500, vec![], vec![]),
+        };
+
+        match request.method.as_str() {
+            "PROPFIND" if !path.exists() => (404, vec![], vec![]),
+            "PROPFIND" => {
+                let mut body = String::from("<?xml version=\"1.0\"?><d:multistatus xmlns:d=\"DAV:\">");
+                body.push_str(&property(&path, request.path.clone()));
+                // children get whole urls, as some servers send them
+                if request.header("depth") == Some("1") && path.is_dir() {
+                    for entry in fs::read_dir(&path).unwrap() {
+                        let entry = entry.unwrap().path();
+                        let href = format!("{}{}", base, encode(&entry.to_string_lossy(), false));
+                        body.push_str(&property(&entry, href));
+                    }
+                }
+                body.push_str("</d:multistatus>");
+                (207, vec![], body.into_bytes())
+            }
+            "MKCOL" if path.exists() => (405, vec![], vec![]),
+            "MKCOL" => done(fs::create_dir(&path), 201),
+            "PUT" => {
+                let written = fs::write(&path, &request.body).and_then(|_| match request.header("x-oc-mtime") {
+                    Some(mtime) => {
+                        let mtime = ::filetime::FileTime::from_unix_time(mtime.parse().unwrap(), 0);
+                        ::filetime::set_file_mtime(&path, mtime)
+                    }
+                    None => Ok(()),
+                });
+                done(written, 201)
+            }
+            "MOVE" => {
+                let destination = request.header("destination").unwrap().trim_start_matches(base);
+                done(fs::rename(&path, decode(destination).unwrap()), 201)
+            }
+            "DELETE" if !path.exists() => (404, vec![], vec![]),
+            "DELETE" => done(fs::remove_file(&path), 204),
+            _ => (405, vec![], vec![]),
+        }
+    }
+
+    /// Starts a stand-in and returns the url of `root` on it
+    fn serve(root: &Path) -> Url {
+        let port = ::std::sync::Arc::new(::std::sync::OnceLock::new());
+        let known = port.clone();
+        let bound = http(move |request| handle(request, &format!("http://127.0.0.1:{}", known.get().unwrap())));
+        port.set(bound).unwrap();
+        let url = format!("webdav://backr@127.0.0.1:{}{}", bound, encode(&root.to_string_lossy(), false));
+        Url::parse(&url).unwrap()
+    }
+
+    fn auth(password: &str) -> Auth {
+        Auth {
+            key: None,
+            password: Some(password.to_string()),
+        }
+    }
+
+    #[test]
+    fn round_trip_against_a_stand_in() {
+        let root = TempDir::new().unwrap();
+        let url = serve(root.path());
+        assert_eq!(Client::connect(&url, &auth("wrong")).err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+
+        let mut client = Client::connect(&url, &auth("secret")).unwrap();
+        round_trip(&mut client, root.path(), &|path| fs::read(path).ok());
+
+        // names are percent encoded on the way
+        let odd = root.path().join("with space & ümlaut");
+        client.write(&odd, None, &mut |writer| writer.write_all(b"odd")).unwrap();
+        assert_eq!(fs::read(&odd).unwrap(), b"odd");
+        assert_eq!(client.list(root.path()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn files_are_not_collections() {
+        let root = TempDir::new().unwrap();
+        fs::write(root.path().join("file"), b"").unwrap();
+        let mut url = serve(root.path());
+        url.path = root.path().join("file");
+        assert_eq!(Client::connect(&url, &auth("secret")).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn failed_uploads_are_aborted() {
+        let root = TempDir::new().unwrap();
+        let url = serve(root.path());
+        let mut client = Client::connect(&url, &auth("secret")).unwrap();
+
+        let path = root.path().join("truncated");
+        let error = client
+            .write(&path, None, &mut |writer| {
+                writer.write_all(&[7; 200 * 1024])?;
+                Err(io::Error::new(io::ErrorKind::InvalidData, "The file could not be read to the end"))
+            }).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn elements_in_any_namespace() {
+        let xml = "<D:multistatus><D:response><D:href>/a</D:href><D:resourcetype><D:collection/></D:resourcetype></D:response>\
+                   <response xmlns=\"DAV:\"><href>/b</href><resourcetype/></response></D:multistatus>";
+        assert_eq!(elements(xml, "href"), vec!["/a", "/b"]);
+        assert_eq!(elements(xml, "collection"), vec![""]);
+        assert_eq!(elements(xml, "resourcetype"), vec!["<D:collection/>", ""]);
+        assert_eq!(parse_date(&http_date(1_600_000_000)), Some(1_600_000_000));
+    }
+}
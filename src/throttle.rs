@@ -0,0 +1,257 @@
+//! Bandwidth and IOPS throttling shared by every backup thread.
+//!
+//! A single `Throttle` is wrapped in an `Arc` and handed to each worker. Each
+//! worker asks it for tokens before reading or writing, so the limits apply to
+//! the backup as a whole and not per thread.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A token bucket that refills at `rate` tokens per second. A rate of 0 means
+/// the bucket is unlimited.
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        Bucket {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Removes `amount` tokens from the bucket and returns how long the caller
+    /// has to wait before the bucket is out of debt
+    fn take(&mut self, amount: u64) -> Duration {
+        if self.rate == 0 {
+            return Duration::from_secs(0);
+        }
+
+        // refill, allowing at most one second worth of burst
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        self.last = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate as f64)
+            .min(self.rate as f64);
+
+        self.tokens -= amount as f64;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+
+    fn set_rate(&mut self, rate: u64) {
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+        self.last = Instant::now();
+    }
+}
+
+/// Limits the bytes per second and the I/O operations per second of a backup
+#[derive(Debug)]
+pub struct Throttle {
+    bytes: Mutex<Bucket>,
+    ops: Mutex<Bucket>,
+}
+
+impl Throttle {
+    /// Creates a new throttle. A limit of 0 disables that limit.
+    pub fn new(bwlimit: u64, max_iops: u64) -> Throttle {
+        Throttle {
+            bytes: Mutex::new(Bucket::new(bwlimit)),
+            ops: Mutex::new(Bucket::new(max_iops)),
+        }
+    }
+
+    /// Returns the current (bwlimit, max_iops) pair
+    pub fn limits(&self) -> (u64, u64) {
+        (self.bytes.lock().unwrap().rate, self.ops.lock().unwrap().rate)
+    }
+
+    /// Replaces both limits. Threads that are currently waiting keep their
+    /// old wait time, every following request uses the new limits.
+    pub fn set_limits(&self, bwlimit: u64, max_iops: u64) {
+        self.bytes.lock().unwrap().set_rate(bwlimit);
+        self.ops.lock().unwrap().set_rate(max_iops);
+    }
+
+    /// Blocks until `amount` bytes may be transferred
+    pub fn take_bytes(&self, amount: u64) {
+        // release the mutex before sleeping so other threads can queue up
+        let wait = self.bytes.lock().unwrap().take(amount);
+        thread::sleep(wait);
+    }
+
+    /// Blocks until a single I/O operation may be issued
+    pub fn take_op(&self) {
+        let wait = self.ops.lock().unwrap().take(1);
+        thread::sleep(wait);
+    }
+}
+
+/// Wraps a writer or a reader so that everything passing through it is
+/// throttled
+pub struct Throttled<'a, W> {
+    inner: W,
+    throttle: Option<&'a Throttle>,
+}
+
+impl<'a, W> Throttled<'a, W> {
+    /// Wraps `inner`. Without a throttle everything is passed straight through.
+    pub fn new(inner: W, throttle: Option<&'a Throttle>) -> Throttled<'a, W> {
+        Throttled { inner, throttle }
+    }
+}
+
+impl<'a, W: Write> Write for Throttled<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(throttle) = self.throttle {
+            throttle.take_op();
+            throttle.take_bytes(buf.len() as u64);
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<'a, R: Read> Read for Throttled<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if let Some(throttle) = self.throttle {
+            throttle.take_op();
+            throttle.take_bytes(len as u64);
+        }
+        Ok(len)
+    }
+}
+
+/// Parses a rate such as `512K`, `20M` or `1G` into a number. Suffixes are
+/// powers of 1024 and a plain number is taken as is.
+pub fn parse_rate(rate: &str) -> Result<u64, String> {
+    let rate = rate.trim();
+    let (num, multiplier) = match rate.chars().last() {
+        Some('k') | Some('K') => (&rate[..rate.len() - 1], 1024),
+        Some('m') | Some('M') => (&rate[..rate.len() - 1], 1024 * 1024),
+        Some('g') | Some('G') => (&rate[..rate.len() - 1], 1024 * 1024 * 1024),
+        _ => (rate, 1),
+    };
+
+    match num.trim().parse::<u64>().ok().and_then(|num| num.checked_mul(multiplier)) {
+        Some(rate) => Ok(rate),
+        None => Err(format!("{:?} is not a valid rate, try 512K, 20M or 1G", rate)),
+    }
+}
+
+/// Parses the contents of a throttle control file. Each line is a
+/// `key = value` pair with `bwlimit` and `max-iops` as the known keys. Keys
+/// that are missing keep the value from `current`.
+fn parse_control(contents: &str, current: (u64, u64)) -> Result<(u64, u64), String> {
+    let (mut bwlimit, mut max_iops) = current;
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut split = line.splitn(2, '=');
+        let key = split.next().unwrap_or_default().trim();
+        let value = match split.next() {
+            Some(value) => value.trim(),
+            None => return Err(format!("Expected `key = value`, found {:?}", line)),
+        };
+
+        match key {
+            "bwlimit" => bwlimit = parse_rate(value)?,
+            "max-iops" => max_iops = parse_rate(value)?,
+            _ => return Err(format!("Unknown throttle setting {:?}", key)),
+        }
+    }
+
+    Ok((bwlimit, max_iops))
+}
+
+/// Spawns a thread that polls the control file once a second and applies any
+/// new limits it finds. The thread lives until the program exits.
+pub fn watch(throttle: Arc<Throttle>, control: PathBuf, quite: bool) {
+    thread::spawn(move || {
+        let mut last_modified: Option<SystemTime> = None;
+
+        loop {
+            let modified = fs::metadata(&control).and_then(|meta| meta.modified()).ok();
+
+            if modified.is_some() && modified != last_modified {
+                last_modified = modified;
+
+                match fs::read_to_string(&control)
+                    .map_err(|error| error.to_string())
+                    .and_then(|contents| parse_control(&contents, throttle.limits()))
+                {
+                    Ok((bwlimit, max_iops)) => {
+                        throttle.set_limits(bwlimit, max_iops);
+                        if quite {
+                            say!(
+                                "** Throttle set to {} bytes/s and {} IOPS (0 is unlimited)",
+                                bwlimit, max_iops
+                            );
+                        }
+                    }
+                    Err(error) => {
+                        if quite {
+                            say!("Error: Failed to read {:?}. \n{}", control, error);
+                        }
+                    }
+                }
+            }
+
+            thread::sleep(Duration::from_secs(1));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rate_suffixes() {
+        assert_eq!(parse_rate("512"), Ok(512));
+        assert_eq!(parse_rate("512K"), Ok(512 * 1024));
+        assert_eq!(parse_rate("20m"), Ok(20 * 1024 * 1024));
+        assert_eq!(parse_rate(" 1G "), Ok(1024 * 1024 * 1024));
+        assert_eq!(parse_rate("0"), Ok(0));
+    }
+
+    #[test]
+    fn parse_rate_rejects() {
+        assert!(parse_rate("").is_err());
+        assert!(parse_rate("K").is_err());
+        assert!(parse_rate("-1M").is_err());
+        assert!(parse_rate("1.5M").is_err());
+        assert!(parse_rate("20T").is_err());
+        assert!(parse_rate("18446744073709551615K").is_err());
+    }
+
+    #[test]
+    fn parse_control_keeps_missing_keys() {
+        let contents = "# limits\n\nbwlimit = 1M\n";
+        assert_eq!(parse_control(contents, (5, 7)), Ok((1024 * 1024, 7)));
+        assert_eq!(parse_control("max-iops=100", (5, 7)), Ok((5, 100)));
+        assert!(parse_control("bwlimit", (5, 7)).is_err());
+        assert!(parse_control("speed = 1M", (5, 7)).is_err());
+    }
+}